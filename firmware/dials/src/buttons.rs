@@ -0,0 +1,85 @@
+use arduino_hal::port::mode::{Input, Output, PullUp};
+use arduino_hal::port::Pin;
+use shared::millis::millis;
+
+use crate::pedal::DEFAULT_DEBOUNCE_MS;
+
+// Each button has an LED next to it, the host decides what they show
+
+pub const BUTTONS: usize = 3;
+
+// Buttons and LEDs are in the same order, AF then plus then minus
+#[macro_export]
+macro_rules! buttons_init {
+    ( $p:expr ) => {
+        crate::buttons::Buttons::new(
+            [
+                $p.d3.into_pull_up_input().downgrade(),
+                $p.d4.into_pull_up_input().downgrade(),
+                $p.d5.into_pull_up_input().downgrade(),
+            ],
+            [
+                $p.d8.into_output().downgrade(),
+                $p.d7.into_output().downgrade(),
+                $p.d6.into_output().downgrade(),
+            ],
+        )
+    };
+}
+
+pub struct Buttons {
+    // Buttons pull the pin to ground when pressed
+    buttons: [Pin<Input<PullUp>>; BUTTONS],
+    leds: [Pin<Output>; BUTTONS],
+
+    pressed: [bool; BUTTONS],
+    changed_at: [Option<u32>; BUTTONS],
+    // Same as the pedal, a change has to hold this long before it counts
+    pub debounce_ms: u32,
+}
+
+impl Buttons {
+    pub fn new(buttons: [Pin<Input<PullUp>>; BUTTONS], leds: [Pin<Output>; BUTTONS]) -> Self {
+        Self {
+            buttons,
+            leds,
+            pressed: [false; BUTTONS],
+            changed_at: [None; BUTTONS],
+            debounce_ms: DEFAULT_DEBOUNCE_MS,
+        }
+    }
+
+    // Calls back with the button index and its new state for every button that changed
+    pub fn scan(&mut self, mut on_change: impl FnMut(u8, bool)) {
+        let now = millis();
+
+        for i in 0..BUTTONS {
+            let pressed = self.buttons[i].is_low();
+            if pressed == self.pressed[i] {
+                self.changed_at[i] = None;
+                continue;
+            }
+
+            let changed_at = *self.changed_at[i].get_or_insert(now);
+            if now.wrapping_sub(changed_at) < self.debounce_ms {
+                continue;
+            }
+
+            self.pressed[i] = pressed;
+            self.changed_at[i] = None;
+            on_change(i as u8, pressed);
+        }
+    }
+
+    pub fn set_led(&mut self, led: u8, on: bool) {
+        let Some(led) = self.leds.get_mut(led as usize) else {
+            return;
+        };
+
+        if on {
+            led.set_high();
+        } else {
+            led.set_low();
+        }
+    }
+}
@@ -2,6 +2,19 @@ use arduino_hal::adc;
 use arduino_hal::hal::port::{PC0, PC1};
 use arduino_hal::port::mode::Analog;
 use arduino_hal::port::Pin;
+use shared::millis::millis;
+use shared::protocol::command::MAX_OVERSAMPLE;
+
+// The ADC jitters by a step or two even when nothing's moving. Each reading is an average of a few,
+//   and a fader is only sent once it's moved far enough from the value last sent, and no more often
+//   than every `interval_ms`. The daemon can change all three
+
+pub const DEFAULT_OVERSAMPLE: u16 = 4;
+pub const DEFAULT_HYSTERESIS: u16 = 2;
+pub const DEFAULT_INTERVAL_MS: u32 = 10;
+
+// The ADC is 10 bits
+const ADC_MAX: u16 = 1023;
 
 #[macro_export]
 macro_rules! faders_init {
@@ -11,9 +24,31 @@ macro_rules! faders_init {
     )};
 }
 
+#[derive(Copy, Clone)]
+pub enum Fader {
+    Volume,
+    Pitch,
+    Modulation,
+}
+
+const FADERS: [Fader; 3] = [Fader::Volume, Fader::Pitch, Fader::Modulation];
+
+// The last value sent for a fader, and when. None until the first one
+#[derive(Copy, Clone)]
+struct Sent {
+    value: Option<u16>,
+    at: u32,
+}
+
 pub struct Faders {
     pitch: Pin<Analog, PC0>,
     modulation: Pin<Analog, PC1>,
+
+    sent: [Sent; 3],
+
+    oversample: u16,
+    pub hysteresis: u16,
+    pub interval_ms: u32,
 }
 
 impl Faders {
@@ -21,17 +56,68 @@ impl Faders {
         pitch: Pin<Analog, PC0>,
         modulation: Pin<Analog, PC1>,
     ) -> Self {
-        Self { pitch, modulation }
+        Self {
+            pitch,
+            modulation,
+            sent: [Sent { value: None, at: 0 }; 3],
+            oversample: DEFAULT_OVERSAMPLE,
+            hysteresis: DEFAULT_HYSTERESIS,
+            interval_ms: DEFAULT_INTERVAL_MS,
+        }
     }
-    pub fn read_volume(&mut self, adc: &mut adc::Adc) -> u16 {
-        adc.read_blocking(&adc::channel::ADC7)
+
+    // False if it's out of range
+    pub fn set_oversample(&mut self, oversample: u16) -> bool {
+        if oversample == 0 || oversample > MAX_OVERSAMPLE {
+            return false;
+        }
+
+        self.oversample = oversample;
+        true
+    }
+
+    // Reads every fader, calls `send` for each one that's moved enough to send
+    pub fn scan(&mut self, adc: &mut adc::Adc, mut send: impl FnMut(Fader, u16)) {
+        for (i, fader) in FADERS.into_iter().enumerate() {
+            let value = self.read(adc, fader);
+            let now = millis();
+
+            if self.should_send(self.sent[i], value, now) {
+                self.sent[i] = Sent { value: Some(value), at: now };
+                send(fader, value);
+            }
+        }
     }
 
-    pub fn read_pitch(&mut self, adc: &mut adc::Adc) -> u16 {
-        self.pitch.analog_read(adc)
+    fn should_send(&self, sent: Sent, value: u16, now: u32) -> bool {
+        let Some(last) = sent.value else {
+            return true;
+        };
+        if now.wrapping_sub(sent.at) < self.interval_ms {
+            return false;
+        }
+
+        // The ends are always sent, or the hysteresis could keep a fader from ever reaching them
+        let moved = last.abs_diff(value);
+        moved >= self.hysteresis.max(1) || (moved > 0 && (value == 0 || value == ADC_MAX))
     }
 
-    pub fn read_modulation(&mut self, adc: &mut adc::Adc) -> u16 {
-        self.modulation.analog_read(adc)
+    // The average of `oversample` readings, rounded
+    fn read(&mut self, adc: &mut adc::Adc, fader: Fader) -> u16 {
+        let mut sum: u32 = 0;
+        for _ in 0..self.oversample {
+            sum += self.read_once(adc, fader) as u32;
+        }
+
+        let oversample = self.oversample as u32;
+        ((sum + oversample / 2) / oversample) as u16
     }
-}
\ No newline at end of file
+
+    fn read_once(&mut self, adc: &mut adc::Adc, fader: Fader) -> u16 {
+        match fader {
+            Fader::Volume => adc.read_blocking(&adc::channel::ADC7),
+            Fader::Pitch => self.pitch.analog_read(adc),
+            Fader::Modulation => self.modulation.analog_read(adc),
+        }
+    }
+}
@@ -2,14 +2,29 @@
 #![no_main]
 #![feature(abi_avr_interrupt)]
 
+mod buttons;
 mod faders;
+mod pedal;
 
+use crate::buttons::Buttons;
+use crate::faders::{Fader, Faders};
+use crate::pedal::Pedal;
 use arduino_hal::hal::wdt;
 use arduino_hal::pins;
+use arduino_hal::prelude::*;
+use arduino_hal::Eeprom;
 use avr_device::atmega328p::Peripherals;
+use shared::eeprom::stored_params;
 use shared::millis::millis_init;
-use shared::serial::{write_msg_str, write_msg_u16};
+use shared::protocol::command::{
+    PARAM_DEBOUNCE, PARAM_FADER_INTERVAL, PARAM_HYSTERESIS, PARAM_OVERSAMPLE, PARAM_PEDAL_POLARITY,
+};
+use shared::protocol::{Command, Message, Request, Status};
+use shared::serial::{
+    handle_stored, respond, send_last_panic, write_message, CommandReader, Serial,
+};
 use shared::serial_init;
+use shared::telemetry::{LoopTimer, WATCHDOG_TIMEOUT};
 
 // // Buttons
 // int pinInButtonAF = 3;
@@ -27,22 +42,8 @@ use shared::serial_init;
 // int pinInPitch = A0;
 // int pinInModulation = A1;
 
-// Output Messages:
-// P<panic message>\x04 - Firmware panic (from shared panic handler)
-// V<u8 length><string> - Firmware version
-const MSG_VERSION: u8 = b'V';
-
-// F<u16 value> - Volume fader value
-const MSG_FADER_VOLUME: u8 = b'F';
-
-// G<u16 value> - Pitch fader value
-const MSG_FADER_PITCH: u8 = b'G';
-
-// H<u16 value> - Modulation fader value
-const MSG_FADER_MODULATION: u8 = b'H';
-
-// B<nibble id><nibble value> - Button control
-
+// Messages and commands are in shared::protocol, the dials send the faders, pedal and buttons and
+//   answer LED commands
 
 const FIRMWARE_VERSION: &str = concat!(
     "I am dials! :3 ",
@@ -52,12 +53,6 @@ const FIRMWARE_VERSION: &str = concat!(
     ))
 );
 
-struct Values {
-    volume: u16,
-    pitch: u16,
-    modulation: u16,
-}
-
 #[arduino_hal::entry]
 fn main() -> ! {
     let dp = Peripherals::take().unwrap();
@@ -69,37 +64,110 @@ fn main() -> ! {
     unsafe { avr_device::interrupt::enable() };
     millis_init(dp.TC0);
 
-    let mut values = Values {
-        volume: 0,
-        pitch: 0,
-        modulation: 0,
-    };
     let mut faders = faders_init!(pins, adc);
+    let mut pedal = pedal_init!(pins);
+    let mut buttons = buttons_init!(pins);
+    let mut commands = CommandReader::new();
 
     let mut watchdog = wdt::Wdt::new(dp.WDT, &dp.CPU.mcusr);
-    watchdog.start(wdt::Timeout::Ms500).unwrap();
-
-    write_msg_str(serial, MSG_VERSION, FIRMWARE_VERSION);
+    watchdog.start(WATCHDOG_TIMEOUT).unwrap();
+
+    write_message(serial, &Message::Version(FIRMWARE_VERSION));
+    let mut eeprom = arduino_hal::Eeprom::new(dp.EEPROM);
+    send_last_panic(serial, &eeprom);
+    stored_params(&mut eeprom, |param, value| {
+        set_param(param, value, &mut faders, &mut buttons, &mut pedal);
+    });
+    let mut timer = LoopTimer::new();
     loop {
-        let volume_val = faders.read_volume(&mut adc);
-        if values.volume != volume_val {
-            values.volume = volume_val;
-            write_msg_u16(serial, MSG_FADER_VOLUME, volume_val);
+        faders.scan(&mut adc, |fader, value| {
+            let message = match fader {
+                Fader::Volume => Message::Volume(value),
+                Fader::Pitch => Message::Pitch(value),
+                Fader::Modulation => Message::Modulation(value),
+            };
+            write_message(serial, &message)
+        });
+
+        if let Some(pressed) = pedal.read() {
+            write_message(serial, &Message::Pedal(pressed));
         }
 
-        let pitch_val = faders.read_pitch(&mut adc);
-        if values.pitch != pitch_val {
-            values.pitch = pitch_val;
-            write_msg_u16(serial, MSG_FADER_PITCH, pitch_val);
-        }
+        buttons.scan(|button, pressed| {
+            write_message(serial, &Message::Button { button, pressed })
+        });
 
-        let modulation_val = faders.read_modulation(&mut adc);
-        if values.modulation != modulation_val {
-            values.modulation = modulation_val;
-            write_msg_u16(serial, MSG_FADER_MODULATION, modulation_val);
-            panic!("TESTTESTTEST");
+        while let Some(request) = commands.poll(serial) {
+            handle_command(serial, &request, &mut eeprom, &mut faders, &mut buttons, &mut pedal);
         }
 
         watchdog.feed();
+        timer.lap();
+        // Everything's read once a loop
+        timer.scanned();
+        timer.report(serial);
     }
-}
\ No newline at end of file
+}
+
+fn handle_command(
+    serial: &mut Serial,
+    request: &Request,
+    eeprom: &mut Eeprom,
+    faders: &mut Faders,
+    buttons: &mut Buttons,
+    pedal: &mut Pedal,
+) {
+    if handle_stored(serial, request, eeprom) {
+        return;
+    }
+
+    let status = match request.command {
+        Command::Ping => Status::Ok,
+        Command::Version => {
+            respond(serial, request, Status::Ok, FIRMWARE_VERSION.as_bytes());
+            return;
+        }
+        Command::Set { param, value } => set_param(param, value, faders, buttons, pedal),
+        Command::Led { led, on } => {
+            buttons.set_led(led, on);
+            Status::Ok
+        }
+        _ => Status::UnknownCommand,
+    };
+
+    respond(serial, request, status, &[]);
+}
+
+// From the daemon, or stored in the EEPROM
+fn set_param(
+    param: u8,
+    value: u16,
+    faders: &mut Faders,
+    buttons: &mut Buttons,
+    pedal: &mut Pedal,
+) -> Status {
+    let ok = match param {
+        PARAM_DEBOUNCE => {
+            buttons.debounce_ms = value as u32;
+            pedal.debounce_ms = value as u32;
+            true
+        }
+        PARAM_OVERSAMPLE => faders.set_oversample(value),
+        PARAM_HYSTERESIS => {
+            faders.hysteresis = value;
+            true
+        }
+        PARAM_FADER_INTERVAL => {
+            faders.interval_ms = value as u32;
+            true
+        }
+        PARAM_PEDAL_POLARITY => pedal.set_polarity(value),
+        _ => false,
+    };
+
+    if ok {
+        Status::Ok
+    } else {
+        Status::BadArgument
+    }
+}
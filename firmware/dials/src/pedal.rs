@@ -0,0 +1,78 @@
+use arduino_hal::hal::port::PB2;
+use arduino_hal::port::mode::{Input, PullUp};
+use arduino_hal::port::Pin;
+use shared::millis::millis;
+use shared::protocol::command::{POLARITY_AUTO, POLARITY_NORMALLY_CLOSED, POLARITY_NORMALLY_OPEN};
+
+// The pedal jack switches the pin to ground. Some pedals close when pressed and some open, so
+//   unless the daemon's said which (see PARAM_PEDAL_POLARITY), whatever the pin reads at boot is
+//   taken as "released". Don't hold the pedal down while booting!
+
+// The contacts bounce, a change has to hold this long before it counts. The daemon can change it
+pub const DEFAULT_DEBOUNCE_MS: u32 = 5;
+
+#[macro_export]
+macro_rules! pedal_init {
+    ( $p:expr ) => {
+        crate::pedal::Pedal::new($p.d10.into_pull_up_input())
+    };
+}
+
+pub struct Pedal {
+    pin: Pin<Input<PullUp>, PB2>,
+    released_level: bool,
+    // What the pin read at boot, for POLARITY_AUTO
+    boot_level: bool,
+
+    pressed: bool,
+    // When the pin started disagreeing with `pressed`
+    changed_at: Option<u32>,
+    pub debounce_ms: u32,
+}
+
+impl Pedal {
+    pub fn new(pin: Pin<Input<PullUp>, PB2>) -> Self {
+        let boot_level = pin.is_high();
+
+        Self {
+            pin,
+            released_level: boot_level,
+            boot_level,
+            pressed: false,
+            changed_at: None,
+            debounce_ms: DEFAULT_DEBOUNCE_MS,
+        }
+    }
+
+    // False if it isn't one of POLARITY_*
+    pub fn set_polarity(&mut self, polarity: u16) -> bool {
+        self.released_level = match polarity {
+            POLARITY_AUTO => self.boot_level,
+            // Pulled up until the switch closes
+            POLARITY_NORMALLY_OPEN => true,
+            POLARITY_NORMALLY_CLOSED => false,
+            _ => return false,
+        };
+
+        true
+    }
+
+    // Returns the new state when the pedal is pressed or released
+    pub fn read(&mut self) -> Option<bool> {
+        let pressed = self.pin.is_high() != self.released_level;
+        if pressed == self.pressed {
+            self.changed_at = None;
+            return None;
+        }
+
+        let now = millis();
+        let changed_at = *self.changed_at.get_or_insert(now);
+        if now.wrapping_sub(changed_at) < self.debounce_ms {
+            return None;
+        }
+
+        self.pressed = pressed;
+        self.changed_at = None;
+        Some(pressed)
+    }
+}
@@ -2,12 +2,14 @@ use arduino_hal::port::mode::{Floating, Input};
 use arduino_hal::port::Pin;
 
 use crate::shift::ShiftRegister;
-use shared::millis::millis;
+use keybed_logic::{key_index, slot, ContactHealth, KeyState, KEYS_PER_SECTION, SECTIONS, SLOTS};
+use shared::millis::micros;
+use shared::protocol::message::{CONTACT_FAULT_LEN, KEY_CHANGE_LEN};
+use shared::protocol::{ContactFault, ContactFaults, KeyChange, KeyChanges};
 
 // The key matrix has 8 outputs and 14 inputs to read from 49 total keys.
 // Each key has two contacts, to calculate velocity.
-
-const KEYS: usize = 49;
+// Which key is where and how keys change state is in keybed-logic, so it can be tested on the host.
 
 // Borrow checker won't let constructor borrow Pins and it would be cumbersome to do this elsewhere
 #[macro_export]
@@ -43,17 +45,6 @@ macro_rules! keybed_init {
     }};
 }
 
-#[derive(Copy, Clone)]
-pub enum KeyState {
-    // Key goes through these states in order
-    // B up, A up
-    Up,
-    // B down, A up (Key may go to "up" after "partial")
-    DownPartial(u32), // millis when B triggered
-    // B down, A down (Key may only go to "down" after "partial")
-    Down(u32), // millis travel time
-}
-
 pub struct Keybed {
     // Shift register selects which 7 keys we're reading
     shift: ShiftRegister,
@@ -63,7 +54,18 @@ pub struct Keybed {
     keys_a: [Pin<Input<Floating>>; 7],
     keys_b: [Pin<Input<Floating>>; 7],
 
-    pub key_states: [KeyState; KEYS],
+    // Every slot in the matrix, wired or not
+    pub key_states: [KeyState; SLOTS],
+
+    // The last scan's changes or the last contact faults, already encoded. Each is sent before
+    //   the other's written and a fault's no longer than a change, so one buffer does for both
+    encoded: [u8; SLOTS * KEY_CHANGE_LEN],
+
+    // Bad readings from each slot's contacts, until they're sent (see contact_faults)
+    health: [ContactHealth; SLOTS],
+
+    // Report slots instead of keys, the daemon has its own map of which key is where
+    pub raw_keys: bool,
 }
 
 impl Keybed {
@@ -78,83 +80,77 @@ impl Keybed {
             shift,
             keys_a,
             keys_b,
-            key_states: [KeyState::Up; KEYS],
+            key_states: [KeyState::Up; SLOTS],
+            encoded: [0; SLOTS * KEY_CHANGE_LEN],
+            health: [ContactHealth::new(); SLOTS],
+            raw_keys: false,
         }
     }
 
-    fn next_state(a_down: bool, b_down: bool, state: KeyState) -> Option<KeyState> {
-        // There are two contacts under each key.
-        // As a key is pressed down it will first touch contact B, then it will contact A
-
-        // | B | A | STATE | NEW_STATE | DESC                                        |
-        // |---|---|-------|-----------|---------------------------------------------|
-        // | 0 | 0 | Up    | Up        | Key is in the neutral position              |
-        // | 1 | 0 | Up    | DownP     | A key press has started                     |
-        // | 0 | 1 | Up    | Up        | Physically impossible                       |
-        // | 1 | 1 | Up    | Down      | Key was pressed faster than we could detect |
-        // | 0 | 0 | DownP | Up        | Key was pressed halfway, then released      |
-        // | 1 | 0 | DownP | DownP     | Key is travelling                           |
-        // | 0 | 1 | DownP | Up        | Physically impossible                       |
-        // | 1 | 1 | DownP | Down      | Key was pressed all the way                 |
-        // | 0 | 0 | Down  | Up        | Key press has finished                      |
-        // | 1 | 0 | Down  | Down      | Key has been released                       |
-        // | 0 | 1 | Down  | Up        | Physically impossible.                      |
-        // | 1 | 1 | Down  | Down      | Key is being held down                      |
-
-        match (b_down, a_down, state) {
-            // Key touched first contact
-            (true, false, KeyState::Up) => Some(KeyState::DownPartial(millis())),
-            // Key touched both contacts, calculate travel time
-            (true, true, KeyState::DownPartial(at)) => Some(KeyState::Down(millis().saturating_sub(at))),
-            // Key touched both contacts before we could register the first, report as the smallest resolution
-            (true, true, KeyState::Up) => Some(KeyState::Down(2)),
-            // Key is always up if the first contact is up
-            (false, _, _) => Some(KeyState::Up),
-            // Anything else is either impossible or shouldn't change the current state
-            _ => None,
-        }
-    }
-
-    // Scan key matrix
-    pub fn scan(&mut self, mut key_update: impl FnMut(usize, KeyState)) {
+    // Scan key matrix, returns every key that changed so they can be sent together
+    pub fn scan(&mut self) -> KeyChanges<'_> {
+        let mut len = 0;
 
         // The keybed has 8 sections
-        for i in 0..8 {
+        for section in 0..SECTIONS {
             // The shift register is used to select each section of the keybed in order
-            if i == 0 {
+            if section == 0 {
                 self.shift.push_high()
             } else {
                 self.shift.push_low()
             }
 
             // Each section of the keybed has 7 keys
-            for j in 0..7 {
-                // Calculate index of key_state
-                // The leftmost key on the keyboard is 0 and the rightmost key is KEYS-1
-                // This calculation is funky because I wired things bad, it could be made simple with some work
-                let (key_index, _) =
-                    ((j * 8) + (if i == 0 { 8usize } else { i })).overflowing_sub(1);
-                if key_index >= KEYS {
-                    // This matrix supports more keys than we actually have
-                    // We can't break because key_index does not increase sequentially with every iteration
+            for input in 0..KEYS_PER_SECTION {
+                let slot = slot(section, input);
+                // key_index does not increase sequentially with every iteration, so no breaking early
+                let Some(key) = self.key(section, input) else {
                     continue;
+                };
+
+                let a_down = self.keys_a[input].is_high();
+                let b_down = self.keys_b[input].is_high();
+                let now = micros();
+
+                self.health[slot].check(self.key_states[slot], a_down, b_down, now);
+                if let Some(event) = self.key_states[slot].update(a_down, b_down, now) {
+                    // Each slot is visited once per scan, so this can't overflow
+                    let change = KeyChange { key: key as u8, event }.encode();
+                    self.encoded[len..len + KEY_CHANGE_LEN].copy_from_slice(&change);
+                    len += KEY_CHANGE_LEN;
                 }
+            }
+        }
+
+        KeyChanges::Encoded(&self.encoded[..len])
+    }
 
-                let a_down = self.keys_a[j].is_high();
-                let b_down = self.keys_b[j].is_high();
-
-                let state = self.key_states[key_index];
-                if let Some(new_state) = Self::next_state(a_down, b_down, state) {
-                    match (state, new_state) {
-                        // Down state is always reported
-                        (_, KeyState::Down(_)) => key_update(key_index, new_state),
-                        // Up state is only reported when key had been fully depressed
-                        (KeyState::Down(_), KeyState::Up) => key_update(key_index, new_state),
-                        _ => {}
-                    }
-                    self.key_states[key_index] = new_state
+    // Every key that's had bad readings since the last call, numbered like scan's changes
+    pub fn contact_faults(&mut self) -> ContactFaults<'_> {
+        let mut len = 0;
+
+        for section in 0..SECTIONS {
+            for input in 0..KEYS_PER_SECTION {
+                let Some(key) = self.key(section, input) else {
+                    continue;
+                };
+                if let Some((impossible, chatter)) = self.health[slot(section, input)].take() {
+                    let fault = ContactFault { key: key as u8, impossible, chatter }.encode();
+                    self.encoded[len..len + CONTACT_FAULT_LEN].copy_from_slice(&fault);
+                    len += CONTACT_FAULT_LEN;
                 }
             }
         }
+
+        ContactFaults::Encoded(&self.encoded[..len])
+    }
+
+    // None for slots with nothing wired to them, unless the daemon wants slots
+    fn key(&self, section: usize, input: usize) -> Option<usize> {
+        if self.raw_keys {
+            Some(slot(section, input))
+        } else {
+            key_index(section, input)
+        }
     }
 }
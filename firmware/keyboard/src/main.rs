@@ -2,16 +2,23 @@
 #![no_main]
 #![feature(abi_avr_interrupt)]
 
-use crate::keybed::{KeyState, Keybed};
+use crate::keybed::Keybed;
 use crate::shift::ShiftRegister;
 use arduino_hal::hal::wdt;
 use arduino_hal::pins;
 use arduino_hal::port::mode::{Floating, Input};
 use arduino_hal::port::Pin;
 use avr_device::atmega328p::Peripherals;
-use shared::millis::millis_init;
-use shared::serial::{write_msg_2u8, write_msg_str, write_msg_u8};
+use arduino_hal::Eeprom;
+use shared::eeprom::stored_params;
+use shared::millis::{micros, millis_init};
+use shared::protocol::command::{PARAM_RAW_KEYS, PARAM_SCAN_INTERVAL};
+use shared::protocol::{Command, Message, Request, Status};
+use shared::serial::{
+    handle_stored, respond, send_last_panic, write_message, CommandReader, Serial,
+};
 use shared::serial_init;
+use shared::telemetry::{LoopTimer, WATCHDOG_TIMEOUT};
 
 mod keybed;
 mod shift;
@@ -24,13 +31,7 @@ const FIRMWARE_VERSION: &str = concat!(
     ))
 );
 
-
-// A serial message may start with either a 'D', 'U', 'V' or 'P'
-// 'P' messages are written by the shared panic handler
-const MSG_VERSION: u8 = b'V';
-const MSG_NOTE_UP: u8 = b'U';
-const MSG_NOTE_DOWN: u8 = b'D';
-
+const CONTACTS_INTERVAL_US: u32 = 1_000_000;
 
 #[arduino_hal::entry]
 fn main() -> ! {
@@ -42,21 +43,89 @@ fn main() -> ! {
     millis_init(dp.TC0);
 
     let mut watchdog = wdt::Wdt::new(dp.WDT, &dp.CPU.mcusr);
-    watchdog.start(wdt::Timeout::Ms500).unwrap();
+    watchdog.start(WATCHDOG_TIMEOUT).unwrap();
 
-    write_msg_str(serial, MSG_VERSION, FIRMWARE_VERSION);
+    write_message(serial, &Message::Version(FIRMWARE_VERSION));
+    let mut eeprom = arduino_hal::Eeprom::new(dp.EEPROM);
+    send_last_panic(serial, &eeprom);
 
     let mut keybed = keybed_init!(pins);
+    let mut sequence: u8 = 0;
+    let mut commands = CommandReader::new();
+    let mut scan_interval_us: u32 = 0;
+    stored_params(&mut eeprom, |param, value| {
+        set_param(param, value, &mut keybed, &mut scan_interval_us);
+    });
+    let mut last_scan = micros();
+    let mut last_contacts = micros();
+    let mut timer = LoopTimer::new();
     loop {
-        // TODO: Scan should instead collect a list of which keys changed so we can send the messages in bulk
-        keybed.scan(|key, state| match state {
-            KeyState::Up => write_msg_u8(serial, MSG_NOTE_UP, key as u8),
-            KeyState::Down(travel_time) => write_msg_2u8(serial, MSG_NOTE_DOWN, key as u8, travel_time as u8),
-            _ => {}
-        });
+        watchdog.feed();
+        timer.lap();
+        timer.report(serial);
+
+        while let Some(request) = commands.poll(serial) {
+            handle_command(serial, &request, &mut eeprom, &mut keybed, &mut scan_interval_us);
+        }
+
+        if micros().wrapping_sub(last_scan) < scan_interval_us {
+            continue;
+        }
+        last_scan = micros();
+
+        // Scans where nothing changed aren't sent, the sequence only counts messages
+        let changes = keybed.scan();
+        timer.scanned();
+        if !changes.is_empty() {
+            write_message(serial, &Message::Keys { sequence, changes });
+            sequence = sequence.wrapping_add(1);
+        }
+
+        // Bad contacts don't need reporting the moment they happen, and a broken one would
+        //   otherwise flood the serial port
+        if last_scan.wrapping_sub(last_contacts) >= CONTACTS_INTERVAL_US {
+            last_contacts = last_scan;
+            let faults = keybed.contact_faults();
+            if !faults.is_empty() {
+                write_message(serial, &Message::Contacts(faults));
+            }
+        }
 
         serial.flush();
-        // TODO: How could we detect if scanning goes beyond the watchdog timeout
-        watchdog.feed();
     }
 }
+
+fn handle_command(
+    serial: &mut Serial,
+    request: &Request,
+    eeprom: &mut Eeprom,
+    keybed: &mut Keybed,
+    scan_interval_us: &mut u32,
+) {
+    if handle_stored(serial, request, eeprom) {
+        return;
+    }
+
+    let status = match request.command {
+        Command::Ping => Status::Ok,
+        Command::Version => {
+            respond(serial, request, Status::Ok, FIRMWARE_VERSION.as_bytes());
+            return;
+        }
+        Command::Set { param, value } => set_param(param, value, keybed, scan_interval_us),
+        _ => Status::UnknownCommand,
+    };
+
+    respond(serial, request, status, &[]);
+}
+
+// From the daemon, or stored in the EEPROM
+fn set_param(param: u8, value: u16, keybed: &mut Keybed, scan_interval_us: &mut u32) -> Status {
+    match param {
+        PARAM_SCAN_INTERVAL => *scan_interval_us = value as u32,
+        PARAM_RAW_KEYS => keybed.raw_keys = value != 0,
+        _ => return Status::BadArgument,
+    }
+
+    Status::Ok
+}
@@ -0,0 +1,73 @@
+// What's kept in the EEPROM, so it survives the watchdog resetting the firmware:
+//
+//   0   PANIC_MAGIC when there's a panic report
+//   1   The report's length
+//   2.. The last panic report, encoded the way it's sent (see protocol::panic_report)
+//   PANIC_END.. Settings stored by the daemon, laid out by protocol::stored
+
+use crate::protocol::panic_report::{PanicReport, MAX_REPORT_LEN};
+use crate::protocol::stored::{Storage, StoredSettings, STORED_PARAMS, STORED_SIZE};
+use arduino_hal::Eeprom;
+
+const PANIC_MAGIC: u8 = 0x5A;
+const PANIC_MAGIC_ADDR: u16 = 0;
+const PANIC_LEN_ADDR: u16 = 1;
+const PANIC_REPORT_ADDR: u16 = 2;
+const PANIC_END: u16 = PANIC_REPORT_ADDR + MAX_REPORT_LEN as u16;
+const SETTINGS_ADDR: u16 = PANIC_END;
+// Where the next thing kept in the EEPROM can start
+pub const SETTINGS_END: u16 = SETTINGS_ADDR + STORED_SIZE;
+
+pub fn last_panic<'a>(eeprom: &Eeprom, buf: &'a mut [u8; MAX_REPORT_LEN]) -> Option<PanicReport<'a>> {
+    if eeprom.read_byte(PANIC_MAGIC_ADDR) != PANIC_MAGIC {
+        return None;
+    }
+
+    let len = (eeprom.read_byte(PANIC_LEN_ADDR) as usize).min(MAX_REPORT_LEN);
+    eeprom.read(PANIC_REPORT_ADDR, &mut buf[..len]).ok()?;
+    PanicReport::decode(&buf[..len])
+}
+
+// Each byte takes a few ms to write, so this keeps the watchdog fed while it works. The magic goes
+//   last, a reset part way through leaves no report rather than half of one
+pub fn store_panic(eeprom: &mut Eeprom, report: &PanicReport) {
+    eeprom.write_byte(PANIC_MAGIC_ADDR, 0xFF);
+    eeprom.write_byte(PANIC_LEN_ADDR, report.encoded_len() as u8);
+
+    let mut addr = PANIC_REPORT_ADDR;
+    report.encode(&mut |byte| {
+        avr_device::asm::wdr();
+        eeprom.write_byte(addr, byte);
+        addr += 1;
+    });
+
+    eeprom.write_byte(PANIC_MAGIC_ADDR, PANIC_MAGIC);
+}
+
+pub struct EepromStorage<'a>(&'a mut Eeprom);
+
+impl Storage for EepromStorage<'_> {
+    fn read(&self, addr: u16) -> u8 {
+        self.0.read_byte(addr)
+    }
+
+    // Starting the settings again writes the whole block, far longer than the watchdog waits
+    fn write(&mut self, addr: u16, byte: u8) {
+        avr_device::asm::wdr();
+        self.0.write_byte(addr, byte);
+    }
+}
+
+pub fn settings(eeprom: &mut Eeprom) -> StoredSettings<EepromStorage<'_>> {
+    StoredSettings::new(EepromStorage(eeprom), SETTINGS_ADDR)
+}
+
+// Calls `set` with every parameter that's stored, for the firmware to start with
+pub fn stored_params(eeprom: &mut Eeprom, mut set: impl FnMut(u8, u16)) {
+    let settings = settings(eeprom);
+    for param in 0..STORED_PARAMS {
+        if let Some(value) = settings.get(param) {
+            set(param, value);
+        }
+    }
+}
@@ -1,5 +1,11 @@
 #![no_std]
 #![feature(abi_avr_interrupt)]
+#![feature(panic_info_message)]
 
+pub mod eeprom;
 pub mod millis;
-pub mod serial;
\ No newline at end of file
+pub mod serial;
+pub mod telemetry;
+
+// Shared with the daemon, see system-components/arduino-protocol
+pub use arduino_protocol as protocol;
\ No newline at end of file
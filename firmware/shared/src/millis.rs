@@ -1,12 +1,16 @@
 /*!
- * A basic implementation of the `millis()` function from Arduino:
+ * A basic implementation of the `millis()` and `micros()` functions from Arduino:
  *
  *     https://www.arduino.cc/reference/en/language/functions/time/millis/
+ *     https://www.arduino.cc/reference/en/language/functions/time/micros/
  *
  * Uses timer TC0 and one of its interrupts to update a global millisecond
  * counter.  A walkthough of this code is available here:
  *
  *     https://blog.rahix.de/005-avr-hal-millis/
+ *
+ * `micros()` adds the timer's current count on top of the millisecond counter,
+ * so its resolution is one timer tick (4us with the settings below).
  */
 
 use core::cell;
@@ -26,6 +30,8 @@ const PRESCALER: u32 = 64;
 const TIMER_COUNTS: u32 = 250;
 
 const MILLIS_INCREMENT: u32 = PRESCALER * TIMER_COUNTS / 16000;
+// 16MHz clock
+const MICROS_PER_TICK: u32 = PRESCALER / 16;
 
 static MILLIS_COUNTER: avr_device::interrupt::Mutex<cell::Cell<u32>> =
     avr_device::interrupt::Mutex::new(cell::Cell::new(0));
@@ -62,3 +68,22 @@ fn TIMER0_COMPA() {
 pub fn millis() -> u32 {
     avr_device::interrupt::free(|cs| MILLIS_COUNTER.borrow(cs).get())
 }
+
+// Wraps after about 71 minutes, use wrapping_sub to measure intervals
+pub fn micros() -> u32 {
+    avr_device::interrupt::free(|cs| {
+        // Only read here, millis_init has already set the timer up
+        let tc0 = unsafe { &*arduino_hal::pac::TC0::ptr() };
+
+        let mut millis = MILLIS_COUNTER.borrow(cs).get();
+        let ticks = tc0.tcnt0.read().bits() as u32;
+
+        // The timer wrapped but the interrupt can't run until we're done, count the millisecond ourselves.
+        //   A low count means the wrap happened before we read it
+        if tc0.tifr0.read().ocf0a().bit_is_set() && ticks < TIMER_COUNTS / 2 {
+            millis += MILLIS_INCREMENT;
+        }
+
+        millis.wrapping_mul(1000).wrapping_add(ticks * MICROS_PER_TICK)
+    })
+}
@@ -1,36 +1,62 @@
 use arduino_hal::hal::port::{PD0, PD1};
 use arduino_hal::port::mode::{Input, Output};
 use arduino_hal::port::Pin;
-use arduino_hal::prelude::_ufmt_uWrite;
-use arduino_hal::Usart;
+use arduino_hal::prelude::*;
+use arduino_hal::{Eeprom, Peripherals, Usart};
+use arduino_protocol::frame::FrameDecoder;
+use arduino_protocol::panic_report::MAX_REPORT_LEN;
+use arduino_protocol::stored::STORED_LEN;
+use arduino_protocol::{Command, DecodeError, Message, PanicReport, Request, Status};
 use avr_device::atmega328p::USART0;
+use avr_device::interrupt::Mutex;
+use core::cell::Cell;
 use core::panic;
 use core::sync::atomic::{compiler_fence, Ordering};
+use crate::eeprom;
 
 pub const SERIAL_BAUD: u32 = 115_200;
+
+// Every message and command is framed and encoded by arduino-protocol, so both ends agree on them
+
+// Longest command payload, including the id
+const MAX_COMMAND_LEN: usize = 8;
+
 pub static mut SERIAL_PORT: Option<Serial> = None;
 
 pub type Serial = Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>;
 
-pub fn handle_panic(info: &panic::PanicInfo) -> ! {
-    if let Some(serial) = unsafe { SERIAL_PORT.as_mut() } {
-        _ = serial.flush();
+// Messages that had to wait for the port to finish sending the one before, since the last telemetry
+//   report. There's no buffer to overflow into, writing blocks the main loop until there's room.
+// The keyboard flushes at the end of every loop, so its messages never wait on each other and this
+//   stays at 0 there. The time it spends sending shows up in the worst loop instead
+static TX_WAITS: Mutex<Cell<u16>> = Mutex::new(Cell::new(0));
 
-        // Firmware may have panicked mid-message. If we don't send enough bytes to end the
-        //   previous message, then the read might stall or get de-synced.
-        _ = serial.write_str("PPPPPPPPPPPPPPPANIC");
+pub fn handle_panic(info: &panic::PanicInfo) -> ! {
+    // Whatever had the EEPROM isn't coming back to it
+    let dp = unsafe { Peripherals::steal() };
+    let mut eeprom = Eeprom::new(dp.EEPROM);
 
-        if let Some(location) = info.location() {
-            _ = ufmt::uwrite!(serial,"@ {}:{}:{}", location.file(), location.line(), location.column());
-        }
+    let mut last = [0; MAX_REPORT_LEN];
+    let number = eeprom::last_panic(&eeprom, &mut last).map_or(1, |last| last.number.wrapping_add(1));
+    let (file, line, column) = info
+        .location()
+        .map_or(("", 0, 0), |location| (location.file(), location.line(), location.column()));
+    // Formatted messages would need formatting machinery we don't have room for
+    let message = info.message().and_then(|message| message.as_str()).unwrap_or("");
+    let report = PanicReport::new(number, file, line, column, message);
 
-        // Just in case reader is somehow stuck still
-        _ = serial.write_str("PANIC PANIC");
+    if let Some(serial) = unsafe { SERIAL_PORT.as_mut() } {
+        _ = serial.flush();
 
-        // End of transmission
-        _ = serial.write_str("\x04");
+        // Firmware may have panicked mid-message. The daemon will throw that one away when its
+        //   CRC doesn't match, and find this frame after it
+        write_message(serial, &Message::Panic(report));
         _ = serial.flush();
     }
+
+    // Sent again after the reset, in case nobody was listening
+    eeprom::store_panic(&mut eeprom, &report);
+
     loop {
         compiler_fence(Ordering::SeqCst);
     }
@@ -54,31 +80,101 @@ macro_rules! serial_init {
     }
 }
 
-pub fn write_msg_str(serial: &mut Serial, header: u8, value: &str) {
-    let str_len = value.len();
-    assert!(
-        str_len < u8::MAX as usize,
-        "String must be less than 255 characters"
-    );
-    serial.write_byte(header);
-    serial.write_byte(str_len as u8);
-    serial.write_str(value).unwrap()
+pub fn write_message(serial: &mut Serial, message: &Message) {
+    let mut first = true;
+    message.encode(&mut |byte| {
+        // Every byte after the first waits for the one before it, that's just the baud rate. The
+        //   first only has to wait when the last message is still going out
+        if first {
+            first = false;
+            if serial.write(byte).is_ok() {
+                return;
+            }
+            avr_device::interrupt::free(|cs| {
+                let waits = TX_WAITS.borrow(cs);
+                waits.set(waits.get().saturating_add(1));
+            });
+        }
+        serial.write_byte(byte);
+    });
+}
+
+pub fn take_tx_waits() -> u16 {
+    avr_device::interrupt::free(|cs| TX_WAITS.borrow(cs).replace(0))
+}
+
+// Called after the version at boot. The daemon knows which reports it's already seen
+pub fn send_last_panic(serial: &mut Serial, eeprom: &Eeprom) {
+    let mut buf = [0; MAX_REPORT_LEN];
+    if let Some(report) = eeprom::last_panic(eeprom, &mut buf) {
+        write_message(serial, &Message::LastPanic(report));
+    }
+}
+
+// Commands with id 0 don't get a response
+pub fn respond(serial: &mut Serial, request: &Request, status: Status, data: &[u8]) {
+    if request.id == 0 {
+        return;
+    }
+
+    write_message(serial, &Message::Response { id: request.id, status, data });
 }
 
-pub fn write_msg_u8(serial: &mut Serial, header: u8, value: u8) {
-    serial.write_byte(header);
-    serial.write_byte(value);
+// Loading and storing settings is the same on both boards. False for any other command, for the
+//   firmware to handle itself
+pub fn handle_stored(serial: &mut Serial, request: &Request, eeprom: &mut Eeprom) -> bool {
+    match request.command {
+        Command::Load { setting } if setting < STORED_LEN => {
+            // Nothing at all when it isn't stored
+            let value = eeprom::settings(eeprom).get(setting).map(u16::to_be_bytes);
+            respond(serial, request, Status::Ok, value.as_ref().map_or(&[], |value| &value[..]));
+        }
+        Command::Load { .. } => respond(serial, request, Status::BadArgument, &[]),
+        Command::Store { setting, value } => {
+            let status = if eeprom::settings(eeprom).set(setting, value) {
+                Status::Ok
+            } else {
+                Status::BadArgument
+            };
+            respond(serial, request, status, &[]);
+        }
+        _ => return false,
+    }
+
+    true
 }
 
-pub fn write_msg_2u8(serial: &mut Serial, header: u8, value1: u8, value2: u8) {
-    serial.write_byte(header);
-    serial.write_byte(value1);
-    serial.write_byte(value2);
+// Picks commands out of whatever has arrived, a byte at a time so the main loop never blocks.
+//   Bad frames are dropped, the daemon will time out waiting for their response. Commands that
+//   arrive whole but don't make sense are answered here, the firmware only sees good ones
+pub struct CommandReader {
+    decoder: FrameDecoder<MAX_COMMAND_LEN>,
 }
 
-pub fn write_msg_u16(serial: &mut Serial, header: u8, value: u16) {
-    let bytes = value.to_be_bytes();
-    serial.write_byte(header);
-    serial.write_byte(bytes[0]);
-    serial.write_byte(bytes[1]);
+impl CommandReader {
+    pub fn new() -> Self {
+        Self { decoder: FrameDecoder::new() }
+    }
+
+    pub fn poll(&mut self, serial: &mut Serial) -> Option<Request> {
+        while let Ok(byte) = serial.read() {
+            let Some((kind, payload)) = self.decoder.push(byte) else {
+                continue;
+            };
+
+            match Request::decode(kind, payload) {
+                (id, Ok(command)) => return Some(Request { id, command }),
+                (0, Err(_)) => {}
+                (id, Err(e)) => {
+                    let status = match e {
+                        DecodeError::UnknownKind(_) => Status::UnknownCommand,
+                        DecodeError::BadPayload(_) => Status::BadArgument,
+                    };
+                    write_message(serial, &Message::Response { id, status, data: &[] });
+                }
+            }
+        }
+
+        None
+    }
 }
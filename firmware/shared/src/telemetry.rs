@@ -0,0 +1,61 @@
+// Timing of the main loop, reported to the daemon about once a second (see Message::Telemetry) so a
+//   firmware change that slows the loop down can be measured before the watchdog starts catching it
+
+use crate::millis::micros;
+use crate::protocol::{Message, Telemetry};
+use crate::serial::{take_tx_waits, write_message, Serial};
+use arduino_hal::hal::wdt;
+
+// Both boards reset after the same time without the watchdog being fed
+pub const WATCHDOG_TIMEOUT: wdt::Timeout = wdt::Timeout::Ms500;
+const WATCHDOG_US: u32 = 500_000;
+
+const TELEMETRY_INTERVAL_US: u32 = 1_000_000;
+
+pub struct LoopTimer {
+    period_start: u32,
+    last_lap: u32,
+    scans: u32,
+    worst_loop_us: u32,
+}
+
+impl LoopTimer {
+    pub fn new() -> Self {
+        let now = micros();
+        Self { period_start: now, last_lap: now, scans: 0, worst_loop_us: 0 }
+    }
+
+    // Once a loop, wherever the watchdog's fed. Storing settings feeds it too (see eeprom.rs), so a
+    //   loop that stored some can take longer than the watchdog without a reset
+    pub fn lap(&mut self) {
+        let now = micros();
+        self.worst_loop_us = self.worst_loop_us.max(now.wrapping_sub(self.last_lap));
+        self.last_lap = now;
+    }
+
+    pub fn scanned(&mut self) {
+        self.scans = self.scans.saturating_add(1);
+    }
+
+    // Sends the report once the period's up, and starts the next one
+    pub fn report(&mut self, serial: &mut Serial) {
+        let now = micros();
+        let period_us = now.wrapping_sub(self.period_start);
+        if period_us < TELEMETRY_INTERVAL_US {
+            return;
+        }
+
+        let telemetry = Telemetry {
+            period_ms: (period_us / 1000).min(u16::MAX as u32) as u16,
+            scans: self.scans,
+            worst_loop_us: self.worst_loop_us,
+            watchdog_us: WATCHDOG_US,
+            tx_waits: take_tx_waits(),
+        };
+        write_message(serial, &Message::Telemetry(telemetry));
+
+        self.period_start = now;
+        self.scans = 0;
+        self.worst_loop_us = 0;
+    }
+}
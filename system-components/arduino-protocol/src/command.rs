@@ -0,0 +1,235 @@
+// Commands from the daemon to the arduinos, in the same frames as everything else with a payload
+//   of <id><args>. Every command gets a Response message back with the same id, unless its id is 0
+//
+// Both firmwares:
+//   p - Ping, empty response
+//   v - Version, responds with the version string
+//   s<param><u16 value> - Set one of the firmware's parameters, see PARAM_*
+//   r<setting> - Load a setting from the EEPROM, see stored.rs. Responds with the u16 value, or
+//     nothing when it isn't stored
+//   w<setting><u16 value> - Store a setting in the EEPROM. Storing UNSET clears it
+//
+// Dials:
+//   L<id><value> - Turn an LED on (1) or off (0)
+
+use crate::frame::FrameWriter;
+use crate::{DecodeError, Sink};
+
+pub const CMD_PING: u8 = b'p';
+pub const CMD_VERSION: u8 = b'v';
+pub const CMD_SET: u8 = b's';
+pub const CMD_LOAD: u8 = b'r';
+pub const CMD_STORE: u8 = b'w';
+pub const CMD_LED: u8 = b'L';
+
+// Keyboard: shortest time between the start of each scan in us, 0 scans as fast as it can
+pub const PARAM_SCAN_INTERVAL: u8 = 0;
+// Keyboard: 1 sends the matrix slot of each key instead of its number, for the daemon to map itself
+pub const PARAM_RAW_KEYS: u8 = 1;
+// Dials: how long, in ms, the buttons and pedal have to settle before a change counts
+pub const PARAM_DEBOUNCE: u8 = 0;
+// Dials: how many ADC readings are averaged for each fader reading, 1 to MAX_OVERSAMPLE
+pub const PARAM_OVERSAMPLE: u8 = 1;
+// Dials: how far, in ADC steps, a fader has to move from the last value sent before it's sent again
+pub const PARAM_HYSTERESIS: u8 = 2;
+// Dials: shortest time, in ms, between two messages for the same fader
+pub const PARAM_FADER_INTERVAL: u8 = 3;
+// Dials: which way round the pedal's switch is, see POLARITY_*
+pub const PARAM_PEDAL_POLARITY: u8 = 4;
+
+pub const MAX_OVERSAMPLE: u16 = 64;
+
+// Whatever the pedal reads as the firmware starts is released
+pub const POLARITY_AUTO: u16 = 0;
+// The switch closes when the pedal's pressed
+pub const POLARITY_NORMALLY_OPEN: u16 = 1;
+// The switch opens when the pedal's pressed
+pub const POLARITY_NORMALLY_CLOSED: u16 = 2;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Status {
+    Ok = 0,
+    UnknownCommand = 1,
+    BadArgument = 2,
+}
+
+impl Status {
+    pub fn from_u8(status: u8) -> Option<Self> {
+        match status {
+            0 => Some(Status::Ok),
+            1 => Some(Status::UnknownCommand),
+            2 => Some(Status::BadArgument),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Command {
+    Ping,
+    Version,
+    Set { param: u8, value: u16 },
+    Load { setting: u8 },
+    Store { setting: u8, value: u16 },
+    Led { led: u8, on: bool },
+}
+
+impl Command {
+    pub fn kind(&self) -> u8 {
+        match self {
+            Command::Ping => CMD_PING,
+            Command::Version => CMD_VERSION,
+            Command::Set { .. } => CMD_SET,
+            Command::Load { .. } => CMD_LOAD,
+            Command::Store { .. } => CMD_STORE,
+            Command::Led { .. } => CMD_LED,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Request {
+    pub id: u8,
+    pub command: Command,
+}
+
+impl Request {
+    pub fn encode(&self, sink: &mut impl Sink) {
+        let args_len = match self.command {
+            Command::Ping | Command::Version => 0,
+            Command::Set { .. } | Command::Store { .. } => 3,
+            Command::Load { .. } => 1,
+            Command::Led { .. } => 2,
+        };
+
+        let mut frame = FrameWriter::new(sink, self.command.kind(), 1 + args_len);
+        frame.write(self.id);
+        match self.command {
+            Command::Ping | Command::Version => {}
+            Command::Set { param, value } => {
+                frame.write(param);
+                frame.write_all(&value.to_be_bytes());
+            }
+            Command::Load { setting } => frame.write(setting),
+            Command::Store { setting, value } => {
+                frame.write(setting);
+                frame.write_all(&value.to_be_bytes());
+            }
+            Command::Led { led, on } => {
+                frame.write(led);
+                frame.write(on as u8);
+            }
+        }
+        frame.finish();
+    }
+
+    // The id comes back even when the command can't be decoded, so it can still be answered.
+    //   A payload without an id gets 0, which isn't answered
+    pub fn decode(kind: u8, payload: &[u8]) -> (u8, Result<Command, DecodeError>) {
+        let Some((&id, args)) = payload.split_first() else {
+            return (0, Err(DecodeError::BadPayload(kind)));
+        };
+
+        let command = match (kind, args) {
+            (CMD_PING, []) => Ok(Command::Ping),
+            (CMD_VERSION, []) => Ok(Command::Version),
+            (CMD_SET, &[param, high, low]) => Ok(Command::Set {
+                param,
+                value: u16::from_be_bytes([high, low]),
+            }),
+            (CMD_LOAD, &[setting]) => Ok(Command::Load { setting }),
+            (CMD_STORE, &[setting, high, low]) => Ok(Command::Store {
+                setting,
+                value: u16::from_be_bytes([high, low]),
+            }),
+            (CMD_LED, &[led, on]) => Ok(Command::Led { led, on: on != 0 }),
+            (CMD_PING | CMD_VERSION | CMD_SET | CMD_LOAD | CMD_STORE | CMD_LED, _) => {
+                Err(DecodeError::BadPayload(kind))
+            }
+            _ => Err(DecodeError::UnknownKind(kind)),
+        };
+
+        (id, command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::FrameDecoder;
+
+    fn round_trip(request: Request) {
+        let mut bytes = vec![];
+        request.encode(&mut |byte| bytes.push(byte));
+
+        let mut decoder = FrameDecoder::<8>::new();
+        let decoded: Vec<_> = bytes
+            .iter()
+            .filter_map(|&byte| {
+                decoder
+                    .push(byte)
+                    .map(|(kind, payload)| Request::decode(kind, payload))
+            })
+            .collect();
+
+        assert_eq!(decoded, [(request.id, Ok(request.command))]);
+    }
+
+    #[test]
+    fn commands() {
+        round_trip(Request {
+            id: 1,
+            command: Command::Ping,
+        });
+        round_trip(Request {
+            id: 2,
+            command: Command::Version,
+        });
+        round_trip(Request {
+            id: 255,
+            command: Command::Set {
+                param: PARAM_SCAN_INTERVAL,
+                value: 500,
+            },
+        });
+        round_trip(Request {
+            id: 3,
+            command: Command::Load { setting: 40 },
+        });
+        round_trip(Request {
+            id: 4,
+            command: Command::Store {
+                setting: 40,
+                value: 0xFFFF,
+            },
+        });
+        round_trip(Request {
+            id: 0,
+            command: Command::Led { led: 2, on: true },
+        });
+    }
+
+    #[test]
+    fn bad_commands_keep_their_id() {
+        assert_eq!(
+            Request::decode(CMD_SET, &[9, PARAM_DEBOUNCE]),
+            (9, Err(DecodeError::BadPayload(CMD_SET)))
+        );
+        assert_eq!(
+            Request::decode(b'z', &[4]),
+            (4, Err(DecodeError::UnknownKind(b'z')))
+        );
+        assert_eq!(
+            Request::decode(CMD_PING, &[]),
+            (0, Err(DecodeError::BadPayload(CMD_PING)))
+        );
+    }
+
+    #[test]
+    fn statuses() {
+        for status in [Status::Ok, Status::UnknownCommand, Status::BadArgument] {
+            assert_eq!(Status::from_u8(status as u8), Some(status));
+        }
+        assert_eq!(Status::from_u8(3), None);
+    }
+}
@@ -0,0 +1,244 @@
+// Every message is framed, so a corrupted or half written one (the firmware panicking mid-message, say)
+//   can be skipped without losing our place in the stream:
+//
+//   SYNC LEN TYPE PAYLOAD[LEN] CRC
+//
+// CRC is a CRC-8 (polynomial 0x07) of LEN, TYPE and PAYLOAD. The sync byte can turn up inside
+//   a payload too, so a frame only counts once its CRC matches
+
+use crate::Sink;
+
+pub const SYNC: u8 = 0xA5;
+
+// SYNC, LEN, TYPE and CRC
+pub const OVERHEAD: usize = 4;
+pub const MAX_PAYLOAD: usize = u8::MAX as usize;
+
+pub fn crc8_update(crc: u8, byte: u8) -> u8 {
+    let mut crc = crc ^ byte;
+    for _ in 0..8 {
+        crc = if crc & 0x80 != 0 {
+            (crc << 1) ^ 0x07
+        } else {
+            crc << 1
+        };
+    }
+    crc
+}
+
+pub fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, &byte| crc8_update(crc, byte))
+}
+
+// Writes a frame a piece at a time, so big messages don't need building in memory first.
+//   Exactly `len` bytes of payload must be written before `finish`
+pub struct FrameWriter<'a, S: Sink> {
+    sink: &'a mut S,
+    crc: u8,
+    remaining: usize,
+}
+
+impl<'a, S: Sink> FrameWriter<'a, S> {
+    pub fn new(sink: &'a mut S, kind: u8, len: usize) -> Self {
+        debug_assert!(len <= MAX_PAYLOAD, "Payload must be at most 255 bytes");
+
+        sink.write(SYNC);
+        let mut frame = Self {
+            sink,
+            crc: 0,
+            remaining: 2,
+        };
+        frame.write(len as u8);
+        frame.write(kind);
+        frame.remaining = len;
+        frame
+    }
+
+    pub fn write(&mut self, byte: u8) {
+        self.sink.write(byte);
+        self.crc = crc8_update(self.crc, byte);
+        self.remaining = self.remaining.wrapping_sub(1);
+    }
+
+    pub fn write_all(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write(*byte);
+        }
+    }
+
+    pub fn finish(self) {
+        debug_assert!(
+            self.remaining == 0,
+            "Frame payload doesn't match its length"
+        );
+        self.sink.write(self.crc);
+    }
+}
+
+pub fn write_frame(sink: &mut impl Sink, kind: u8, payload: &[u8]) {
+    let mut frame = FrameWriter::new(sink, kind, payload.len());
+    frame.write_all(payload);
+    frame.finish();
+}
+
+#[derive(Copy, Clone)]
+enum State {
+    Sync,
+    Len,
+    Kind,
+    Payload,
+    Crc,
+}
+
+// Picks frames out of a stream a byte at a time, with nowhere to keep more than one frame.
+//   Anything with a payload longer than N or a bad CRC is dropped, and the search for the next
+//   sync byte starts after it
+pub struct FrameDecoder<const N: usize> {
+    state: State,
+    crc: u8,
+    kind: u8,
+    payload: [u8; N],
+    len: usize,
+    pos: usize,
+}
+
+impl<const N: usize> FrameDecoder<N> {
+    pub fn new() -> Self {
+        Self {
+            state: State::Sync,
+            crc: 0,
+            kind: 0,
+            payload: [0; N],
+            len: 0,
+            pos: 0,
+        }
+    }
+
+    // The type and payload once a whole good frame has arrived
+    pub fn push(&mut self, byte: u8) -> Option<(u8, &[u8])> {
+        match self.state {
+            State::Sync => {
+                if byte == SYNC {
+                    self.crc = 0;
+                    self.state = State::Len;
+                }
+            }
+            State::Len => {
+                if byte as usize > N {
+                    self.state = State::Sync;
+                } else {
+                    self.crc = crc8_update(self.crc, byte);
+                    self.len = byte as usize;
+                    self.pos = 0;
+                    self.state = State::Kind;
+                }
+            }
+            State::Kind => {
+                self.crc = crc8_update(self.crc, byte);
+                self.kind = byte;
+                self.state = if self.len == 0 {
+                    State::Crc
+                } else {
+                    State::Payload
+                };
+            }
+            State::Payload => {
+                self.crc = crc8_update(self.crc, byte);
+                self.payload[self.pos] = byte;
+                self.pos += 1;
+                if self.pos == self.len {
+                    self.state = State::Crc;
+                }
+            }
+            State::Crc => {
+                self.state = State::Sync;
+                if byte == self.crc {
+                    return Some((self.kind, &self.payload[..self.len]));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl<const N: usize> Default for FrameDecoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![];
+        write_frame(&mut |byte| bytes.push(byte), kind, payload);
+        bytes
+    }
+
+    fn decode_all<const N: usize>(bytes: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut decoder = FrameDecoder::<N>::new();
+        bytes
+            .iter()
+            .filter_map(|&byte| {
+                decoder
+                    .push(byte)
+                    .map(|(kind, payload)| (kind, payload.to_vec()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn crc_check_value() {
+        // The standard check for CRC-8/SMBUS
+        assert_eq!(crc8(b"123456789"), 0xF4);
+    }
+
+    #[test]
+    fn frame_layout() {
+        assert_eq!(
+            frame(b'F', &[0x01, 0x02]),
+            [SYNC, 2, b'F', 0x01, 0x02, crc8(&[2, b'F', 0x01, 0x02])]
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut bytes = frame(b'V', b"I am a keyboard! :3 1.0");
+        bytes.extend(frame(b'p', &[]));
+
+        assert_eq!(
+            decode_all::<32>(&bytes),
+            [(b'V', b"I am a keyboard! :3 1.0".to_vec()), (b'p', vec![])]
+        );
+    }
+
+    #[test]
+    fn skips_junk_and_bad_frames() {
+        let mut bytes = vec![0x00, 0x42];
+        let mut bad = frame(b'F', &[1, 2]);
+        bad[3] ^= 0xFF;
+        bytes.extend(bad);
+        bytes.extend(frame(b'G', &[3, 4]));
+
+        assert_eq!(decode_all::<8>(&bytes), [(b'G', vec![3, 4])]);
+    }
+
+    #[test]
+    fn sync_in_payload() {
+        assert_eq!(
+            decode_all::<8>(&frame(b'K', &[SYNC, SYNC, 0])),
+            [(b'K', vec![SYNC, SYNC, 0])]
+        );
+    }
+
+    #[test]
+    fn drops_frames_too_long_for_the_decoder() {
+        let mut bytes = frame(b'V', &[0; 20]);
+        bytes.extend(frame(b'p', &[7]));
+
+        assert_eq!(decode_all::<8>(&bytes), [(b'p', vec![7])]);
+    }
+}
@@ -0,0 +1,43 @@
+// Everything the daemon and the arduinos say to each other. Both firmwares and the keyboard-daemon
+//   build against this, so a message can't mean one thing on one side and something else on the other.
+//
+// Every message is sent in a frame (see frame.rs). Messages from the arduinos are in message.rs,
+//   commands to them are in command.rs. What they keep in their EEPROM is in stored.rs
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod command;
+pub mod frame;
+pub mod message;
+pub mod panic_report;
+pub mod stored;
+
+pub use command::{Command, Request, Status};
+pub use message::{
+    ContactFault, ContactFaults, KeyChange, KeyChanges, KeyEvent, Message, Telemetry,
+};
+pub use panic_report::PanicReport;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    UnknownKind(u8),
+    // The payload is the wrong size or has a value that's out of range
+    BadPayload(u8),
+}
+
+// Where encoded bytes go, one at a time. The firmware writes them straight to the serial port
+pub trait Sink {
+    fn write(&mut self, byte: u8);
+
+    fn write_all(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write(*byte);
+        }
+    }
+}
+
+impl<F: FnMut(u8)> Sink for F {
+    fn write(&mut self, byte: u8) {
+        self(byte)
+    }
+}
@@ -0,0 +1,565 @@
+// Messages from the arduinos to the daemon
+//
+//   V<string> - Firmware version, sent once at boot
+//   P<report> - Firmware panic, sent by the panic handler (see panic_report.rs)
+//   Q<report> - The last panic, kept through the reset and sent again after every boot
+//   R<id><status><data> - Response to a command (see command.rs)
+//   T<period ms u16><scans u32><worst loop us u32><watchdog us u32><tx waits u16>
+//     How the main loop kept up over the last period, sent about once a second (see Telemetry).
+//     All big endian
+//
+// Keyboard:
+//   K<sequence><count> then <count> changes of <key><travel time>
+//     Every key that changed in one scan of the matrix, so chords arrive together.
+//     The sequence goes up by one with every message, so the daemon can tell when it missed one.
+//     The top bit of the key is set when the key went down, clear when it came up. The key is its
+//     matrix slot instead once the daemon's asked for them (see PARAM_RAW_KEYS).
+//     Travel times are big endian u16s, in tenths of a millisecond, from the first contact to the
+//     second when pressed and the other way around when released
+//   C<count> then <count> faults of <key><impossible><chatter>
+//     How many readings each key's contacts gave that a healthy key can't (see keybed-logic's
+//     contacts.rs) since the last C. Sent at most once a second, only for keys that had any, and
+//     numbered the same as in K
+//
+// Dials:
+//   F<u16 value> - Volume fader value
+//   G<u16 value> - Pitch fader value
+//   H<u16 value> - Modulation fader value
+//   S<u8 pressed> - Sustain pedal pressed (1) or released (0)
+//   B<nibble id><nibble value> - Button pressed (1) or released (0)
+
+use crate::command::Status;
+use crate::frame::FrameWriter;
+use crate::panic_report::PanicReport;
+use crate::{DecodeError, Sink};
+
+pub const MSG_VERSION: u8 = b'V';
+pub const MSG_PANIC: u8 = b'P';
+pub const MSG_LAST_PANIC: u8 = b'Q';
+pub const MSG_RESPONSE: u8 = b'R';
+pub const MSG_TELEMETRY: u8 = b'T';
+pub const MSG_KEYS: u8 = b'K';
+pub const MSG_CONTACTS: u8 = b'C';
+pub const MSG_FADER_VOLUME: u8 = b'F';
+pub const MSG_FADER_PITCH: u8 = b'G';
+pub const MSG_FADER_MODULATION: u8 = b'H';
+pub const MSG_PEDAL: u8 = b'S';
+pub const MSG_BUTTON: u8 = b'B';
+
+const KEY_DOWN: u8 = 0x80;
+pub const KEY_CHANGE_LEN: usize = 3;
+
+pub const CONTACT_FAULT_LEN: usize = 3;
+const TELEMETRY_LEN: usize = 16;
+
+// Travel times are in tenths of a millisecond
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum KeyEvent {
+    Down(u16), // travel time from B to A
+    Up(u16),   // travel time from A to B
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct KeyChange {
+    pub key: u8,
+    pub event: KeyEvent,
+}
+
+impl KeyChange {
+    pub fn encode(&self) -> [u8; KEY_CHANGE_LEN] {
+        let (key, travel_time) = match self.event {
+            KeyEvent::Down(travel_time) => (self.key | KEY_DOWN, travel_time),
+            KeyEvent::Up(travel_time) => (self.key, travel_time),
+        };
+        let [high, low] = travel_time.to_be_bytes();
+        [key, high, low]
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        let travel_time = u16::from_be_bytes([bytes[1], bytes[2]]);
+        let key = bytes[0] & !KEY_DOWN;
+        let event = if bytes[0] & KEY_DOWN != 0 {
+            KeyEvent::Down(travel_time)
+        } else {
+            KeyEvent::Up(travel_time)
+        };
+
+        Self { key, event }
+    }
+}
+
+// The firmware sends a list it already has, the daemon reads them straight out of the payload
+#[derive(Copy, Clone, Debug)]
+pub enum KeyChanges<'a> {
+    List(&'a [KeyChange]),
+    Encoded(&'a [u8]),
+}
+
+impl<'a> KeyChanges<'a> {
+    pub fn len(&self) -> usize {
+        match self {
+            KeyChanges::List(changes) => changes.len(),
+            KeyChanges::Encoded(bytes) => bytes.len() / KEY_CHANGE_LEN,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = KeyChange> + 'a {
+        let changes = *self;
+        (0..changes.len()).map(move |i| match changes {
+            KeyChanges::List(list) => list[i],
+            KeyChanges::Encoded(bytes) => KeyChange::decode(&bytes[i * KEY_CHANGE_LEN..]),
+        })
+    }
+}
+
+impl PartialEq for KeyChanges<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+// For measuring the firmware, so a change that slows its loop down shows up long before the
+//   watchdog starts resetting the board
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Telemetry {
+    // How long this covers, from the last report
+    pub period_ms: u16,
+    // Keyboard: scans of the keybed. Dials: passes of the main loop, which reads everything once
+    pub scans: u32,
+    // The longest the watchdog went without being fed
+    pub worst_loop_us: u32,
+    // The watchdog resets the board when a loop takes this long
+    pub watchdog_us: u32,
+    // Messages that found the serial port still busy with the one before, the loop waits for it.
+    //   Nothing's lost, this is how often sending held the loop up
+    pub tx_waits: u16,
+}
+
+impl Telemetry {
+    pub fn scans_per_second(&self) -> u32 {
+        (self.scans as u64 * 1000 / self.period_ms.max(1) as u64) as u32
+    }
+
+    // How much slower the worst loop could get before the watchdog resets the board
+    pub fn watchdog_margin_us(&self) -> u32 {
+        self.watchdog_us.saturating_sub(self.worst_loop_us)
+    }
+
+    fn encode(&self) -> [u8; TELEMETRY_LEN] {
+        let mut bytes = [0; TELEMETRY_LEN];
+        bytes[0..2].copy_from_slice(&self.period_ms.to_be_bytes());
+        bytes[2..6].copy_from_slice(&self.scans.to_be_bytes());
+        bytes[6..10].copy_from_slice(&self.worst_loop_us.to_be_bytes());
+        bytes[10..14].copy_from_slice(&self.watchdog_us.to_be_bytes());
+        bytes[14..16].copy_from_slice(&self.tx_waits.to_be_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; TELEMETRY_LEN] = bytes.try_into().ok()?;
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at =
+            |i: usize| u32::from_be_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+
+        Some(Self {
+            period_ms: u16_at(0),
+            scans: u32_at(2),
+            worst_loop_us: u32_at(6),
+            watchdog_us: u32_at(10),
+            tx_waits: u16_at(14),
+        })
+    }
+}
+
+// Counts saturate at 255, a key that gets there is broken enough either way
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ContactFault {
+    pub key: u8,
+    // A down while B is up
+    pub impossible: u8,
+    // Presses and releases that bounced back (see CHATTER_US)
+    pub chatter: u8,
+}
+
+impl ContactFault {
+    pub fn encode(&self) -> [u8; CONTACT_FAULT_LEN] {
+        [self.key, self.impossible, self.chatter]
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        Self {
+            key: bytes[0],
+            impossible: bytes[1],
+            chatter: bytes[2],
+        }
+    }
+}
+
+// Like KeyChanges
+#[derive(Copy, Clone, Debug)]
+pub enum ContactFaults<'a> {
+    List(&'a [ContactFault]),
+    Encoded(&'a [u8]),
+}
+
+impl<'a> ContactFaults<'a> {
+    pub fn len(&self) -> usize {
+        match self {
+            ContactFaults::List(faults) => faults.len(),
+            ContactFaults::Encoded(bytes) => bytes.len() / CONTACT_FAULT_LEN,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ContactFault> + 'a {
+        let faults = *self;
+        (0..faults.len()).map(move |i| match faults {
+            ContactFaults::List(list) => list[i],
+            ContactFaults::Encoded(bytes) => ContactFault::decode(&bytes[i * CONTACT_FAULT_LEN..]),
+        })
+    }
+}
+
+impl PartialEq for ContactFaults<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Message<'a> {
+    Version(&'a str),
+    Panic(PanicReport<'a>),
+    LastPanic(PanicReport<'a>),
+    Response {
+        id: u8,
+        status: Status,
+        data: &'a [u8],
+    },
+    Telemetry(Telemetry),
+
+    Keys {
+        sequence: u8,
+        changes: KeyChanges<'a>,
+    },
+    Contacts(ContactFaults<'a>),
+
+    Volume(u16),
+    Pitch(u16),
+    Modulation(u16),
+    // The firmware works out the pedal's polarity, this is always true for pressed
+    Pedal(bool),
+    Button {
+        button: u8,
+        pressed: bool,
+    },
+}
+
+impl<'a> Message<'a> {
+    pub fn kind(&self) -> u8 {
+        match self {
+            Message::Version(_) => MSG_VERSION,
+            Message::Panic(_) => MSG_PANIC,
+            Message::LastPanic(_) => MSG_LAST_PANIC,
+            Message::Response { .. } => MSG_RESPONSE,
+            Message::Telemetry(_) => MSG_TELEMETRY,
+            Message::Keys { .. } => MSG_KEYS,
+            Message::Contacts(_) => MSG_CONTACTS,
+            Message::Volume(_) => MSG_FADER_VOLUME,
+            Message::Pitch(_) => MSG_FADER_PITCH,
+            Message::Modulation(_) => MSG_FADER_MODULATION,
+            Message::Pedal(_) => MSG_PEDAL,
+            Message::Button { .. } => MSG_BUTTON,
+        }
+    }
+
+    fn payload_len(&self) -> usize {
+        match self {
+            Message::Version(text) => text.len(),
+            Message::Panic(report) | Message::LastPanic(report) => report.encoded_len(),
+            Message::Response { data, .. } => 2 + data.len(),
+            Message::Telemetry(_) => TELEMETRY_LEN,
+            Message::Keys { changes, .. } => 2 + changes.len() * KEY_CHANGE_LEN,
+            Message::Contacts(faults) => 1 + faults.len() * CONTACT_FAULT_LEN,
+            Message::Volume(_) | Message::Pitch(_) | Message::Modulation(_) => 2,
+            Message::Pedal(_) | Message::Button { .. } => 1,
+        }
+    }
+
+    // Writes the whole frame
+    pub fn encode(&self, sink: &mut impl Sink) {
+        let mut frame = FrameWriter::new(sink, self.kind(), self.payload_len());
+
+        match *self {
+            Message::Version(text) => frame.write_all(text.as_bytes()),
+            Message::Panic(report) | Message::LastPanic(report) => {
+                report.encode(&mut |byte| frame.write(byte))
+            }
+            Message::Response { id, status, data } => {
+                frame.write(id);
+                frame.write(status as u8);
+                frame.write_all(data);
+            }
+            Message::Telemetry(telemetry) => frame.write_all(&telemetry.encode()),
+            Message::Keys { sequence, changes } => {
+                frame.write(sequence);
+                frame.write(changes.len() as u8);
+                for change in changes.iter() {
+                    frame.write_all(&change.encode());
+                }
+            }
+            Message::Contacts(faults) => {
+                frame.write(faults.len() as u8);
+                for fault in faults.iter() {
+                    frame.write_all(&fault.encode());
+                }
+            }
+            Message::Volume(value) | Message::Pitch(value) | Message::Modulation(value) => {
+                frame.write_all(&value.to_be_bytes())
+            }
+            Message::Pedal(pressed) => frame.write(pressed as u8),
+            Message::Button { button, pressed } => frame.write(button << 4 | pressed as u8),
+        }
+
+        frame.finish();
+    }
+
+    pub fn decode(kind: u8, payload: &'a [u8]) -> Result<Self, DecodeError> {
+        let bad = DecodeError::BadPayload(kind);
+        let report = || PanicReport::decode(payload).ok_or(bad);
+
+        match (kind, payload) {
+            (MSG_VERSION, _) => Ok(Message::Version(
+                core::str::from_utf8(payload).map_err(|_| bad)?,
+            )),
+            (MSG_PANIC, _) => Ok(Message::Panic(report()?)),
+            (MSG_LAST_PANIC, _) => Ok(Message::LastPanic(report()?)),
+            (MSG_RESPONSE, &[id, status, ref data @ ..]) => Ok(Message::Response {
+                id,
+                status: Status::from_u8(status).ok_or(bad)?,
+                data,
+            }),
+            (MSG_TELEMETRY, _) => Ok(Message::Telemetry(Telemetry::decode(payload).ok_or(bad)?)),
+            (MSG_KEYS, &[sequence, count, ref changes @ ..]) => {
+                if changes.len() != count as usize * KEY_CHANGE_LEN {
+                    return Err(bad);
+                }
+                Ok(Message::Keys {
+                    sequence,
+                    changes: KeyChanges::Encoded(changes),
+                })
+            }
+            (MSG_CONTACTS, &[count, ref faults @ ..]) => {
+                if faults.len() != count as usize * CONTACT_FAULT_LEN {
+                    return Err(bad);
+                }
+                Ok(Message::Contacts(ContactFaults::Encoded(faults)))
+            }
+            (MSG_FADER_VOLUME, &[high, low]) => {
+                Ok(Message::Volume(u16::from_be_bytes([high, low])))
+            }
+            (MSG_FADER_PITCH, &[high, low]) => Ok(Message::Pitch(u16::from_be_bytes([high, low]))),
+            (MSG_FADER_MODULATION, &[high, low]) => {
+                Ok(Message::Modulation(u16::from_be_bytes([high, low])))
+            }
+            (MSG_PEDAL, &[pressed]) => Ok(Message::Pedal(pressed != 0)),
+            (MSG_BUTTON, &[button]) => Ok(Message::Button {
+                button: button >> 4,
+                pressed: button & 0x0F != 0,
+            }),
+            (
+                MSG_RESPONSE | MSG_KEYS | MSG_CONTACTS | MSG_FADER_VOLUME | MSG_FADER_PITCH
+                | MSG_FADER_MODULATION | MSG_PEDAL | MSG_BUTTON,
+                _,
+            ) => Err(bad),
+            _ => Err(DecodeError::UnknownKind(kind)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::FrameDecoder;
+
+    // Through a whole frame and back, the same way the firmware and daemon see it
+    fn round_trip(message: Message) {
+        let mut bytes = vec![];
+        message.encode(&mut |byte| bytes.push(byte));
+
+        let mut decoder = FrameDecoder::<255>::new();
+        let frames: Vec<_> = bytes
+            .iter()
+            .filter_map(|&byte| {
+                decoder
+                    .push(byte)
+                    .map(|(kind, payload)| (kind, payload.to_vec()))
+            })
+            .collect();
+        assert_eq!(
+            frames.len(),
+            1,
+            "{:?} didn't make exactly one frame",
+            message
+        );
+
+        let (kind, payload) = &frames[0];
+        assert_eq!(Message::decode(*kind, payload), Ok(message));
+    }
+
+    #[test]
+    fn built_in_messages() {
+        round_trip(Message::Version("I am dials! :3 2024-03-22"));
+        round_trip(Message::Panic(PanicReport::new(
+            1,
+            "src/main.rs",
+            12,
+            5,
+            "TESTTESTTEST",
+        )));
+        round_trip(Message::LastPanic(PanicReport::new(
+            65535,
+            "src/keybed.rs",
+            100,
+            9,
+            "",
+        )));
+        round_trip(Message::Response {
+            id: 7,
+            status: Status::Ok,
+            data: b"I am a keyboard! :3 2024-03-22",
+        });
+        round_trip(Message::Response {
+            id: 255,
+            status: Status::UnknownCommand,
+            data: &[],
+        });
+        round_trip(Message::Telemetry(Telemetry {
+            period_ms: 1002,
+            scans: 70_000,
+            worst_loop_us: 1234,
+            watchdog_us: 500_000,
+            tx_waits: 3,
+        }));
+    }
+
+    #[test]
+    fn keys() {
+        round_trip(Message::Keys {
+            sequence: 200,
+            changes: KeyChanges::List(&[
+                KeyChange {
+                    key: 0,
+                    event: KeyEvent::Down(20),
+                },
+                KeyChange {
+                    key: 48,
+                    event: KeyEvent::Up(u16::MAX),
+                },
+                KeyChange {
+                    key: 24,
+                    event: KeyEvent::Down(1234),
+                },
+            ]),
+        });
+        round_trip(Message::Keys {
+            sequence: 0,
+            changes: KeyChanges::List(&[]),
+        });
+    }
+
+    #[test]
+    fn every_key_can_change_at_once() {
+        let changes: Vec<KeyChange> = (0..49)
+            .map(|key| KeyChange {
+                key,
+                event: KeyEvent::Down(key as u16 * 100),
+            })
+            .collect();
+        round_trip(Message::Keys {
+            sequence: 1,
+            changes: KeyChanges::List(&changes),
+        });
+    }
+
+    #[test]
+    fn contacts() {
+        round_trip(Message::Contacts(ContactFaults::List(&[
+            ContactFault {
+                key: 3,
+                impossible: 1,
+                chatter: 0,
+            },
+            ContactFault {
+                key: 55,
+                impossible: 0,
+                chatter: 255,
+            },
+        ])));
+        round_trip(Message::Contacts(ContactFaults::List(&[])));
+    }
+
+    #[test]
+    fn dials() {
+        round_trip(Message::Volume(0));
+        round_trip(Message::Pitch(512));
+        round_trip(Message::Modulation(1023));
+        round_trip(Message::Pedal(true));
+        round_trip(Message::Pedal(false));
+        round_trip(Message::Button {
+            button: 2,
+            pressed: true,
+        });
+        round_trip(Message::Button {
+            button: 0,
+            pressed: false,
+        });
+    }
+
+    #[test]
+    fn key_direction_is_the_top_bit() {
+        let mut bytes = vec![];
+        Message::Keys {
+            sequence: 3,
+            changes: KeyChanges::List(&[KeyChange {
+                key: 5,
+                event: KeyEvent::Down(0x0102),
+            }]),
+        }
+        .encode(&mut |byte| bytes.push(byte));
+
+        assert_eq!(&bytes[3..8], &[3, 1, 0x85, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn bad_payloads() {
+        assert_eq!(
+            Message::decode(MSG_FADER_VOLUME, &[1]),
+            Err(DecodeError::BadPayload(MSG_FADER_VOLUME))
+        );
+        // Count says two changes, there's only one
+        assert_eq!(
+            Message::decode(MSG_KEYS, &[0, 2, 0x85, 0, 20]),
+            Err(DecodeError::BadPayload(MSG_KEYS))
+        );
+        assert_eq!(
+            Message::decode(MSG_TELEMETRY, &[0; 15]),
+            Err(DecodeError::BadPayload(MSG_TELEMETRY))
+        );
+        assert_eq!(
+            Message::decode(MSG_RESPONSE, &[1, 99]),
+            Err(DecodeError::BadPayload(MSG_RESPONSE))
+        );
+        assert_eq!(
+            Message::decode(b'Z', &[]),
+            Err(DecodeError::UnknownKind(b'Z'))
+        );
+    }
+}
@@ -0,0 +1,127 @@
+// Where and why the firmware panicked. The firmware keeps the last one in EEPROM, encoded the same way
+//   as it's sent, so it can be sent again after the watchdog resets it:
+//
+//   <u16 number><u32 line><u32 column><file length><file><message>
+//
+// Numbers go up by one with every panic, so the same report sent twice can be told apart from the
+//   same panic happening again
+
+use crate::Sink;
+
+// Both are cut short to keep the report small, the end of the file's path is the useful bit
+pub const MAX_FILE_LEN: usize = 40;
+pub const MAX_MESSAGE_LEN: usize = 40;
+
+// number, line, column and the file's length
+const HEADER_LEN: usize = 11;
+pub const MAX_REPORT_LEN: usize = HEADER_LEN + MAX_FILE_LEN + MAX_MESSAGE_LEN;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PanicReport<'a> {
+    pub number: u16,
+    pub file: &'a str,
+    pub line: u32,
+    pub column: u32,
+    // Only panics with a plain string message have one
+    pub message: &'a str,
+}
+
+impl<'a> PanicReport<'a> {
+    // Cuts the file and message down to size
+    pub fn new(number: u16, file: &'a str, line: u32, column: u32, message: &'a str) -> Self {
+        Self {
+            number,
+            file: tail(file, MAX_FILE_LEN),
+            line,
+            column,
+            message: head(message, MAX_MESSAGE_LEN),
+        }
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN + self.file.len() + self.message.len()
+    }
+
+    pub fn encode(&self, sink: &mut impl Sink) {
+        sink.write_all(&self.number.to_be_bytes());
+        sink.write_all(&self.line.to_be_bytes());
+        sink.write_all(&self.column.to_be_bytes());
+        sink.write(self.file.len() as u8);
+        sink.write_all(self.file.as_bytes());
+        sink.write_all(self.message.as_bytes());
+    }
+
+    pub fn decode(payload: &'a [u8]) -> Option<Self> {
+        if payload.len() < HEADER_LEN {
+            return None;
+        }
+        let (header, rest) = payload.split_at(HEADER_LEN);
+        let file_len = header[10] as usize;
+        if rest.len() < file_len {
+            return None;
+        }
+        let (file, message) = rest.split_at(file_len);
+
+        Some(Self {
+            number: u16::from_be_bytes([header[0], header[1]]),
+            line: u32::from_be_bytes([header[2], header[3], header[4], header[5]]),
+            column: u32::from_be_bytes([header[6], header[7], header[8], header[9]]),
+            file: core::str::from_utf8(file).ok()?,
+            message: core::str::from_utf8(message).ok()?,
+        })
+    }
+}
+
+fn head(text: &str, len: usize) -> &str {
+    let mut end = len.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+fn tail(text: &str, len: usize) -> &str {
+    let mut start = text.len().saturating_sub(len);
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    &text[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let report = PanicReport::new(3, "src/main.rs", 90, 13, "TESTTESTTEST");
+        let mut bytes = vec![];
+        report.encode(&mut |byte| bytes.push(byte));
+
+        assert_eq!(bytes.len(), report.encoded_len());
+        assert_eq!(PanicReport::decode(&bytes), Some(report));
+    }
+
+    #[test]
+    fn long_text_is_cut_short() {
+        let file = "/home/someone/.cargo/git/checkouts/avr-hal/avr-hal-generic/src/usart.rs";
+        let message = "called `Option::unwrap()` on a `None` value, and then some more";
+        let report = PanicReport::new(1, file, 1, 1, message);
+
+        assert_eq!(report.file.len(), MAX_FILE_LEN);
+        assert!(file.ends_with(report.file));
+        assert_eq!(report.message.len(), MAX_MESSAGE_LEN);
+        assert!(message.starts_with(report.message));
+        assert!(report.encoded_len() <= MAX_REPORT_LEN);
+    }
+
+    #[test]
+    fn truncated_reports_dont_decode() {
+        let report = PanicReport::new(1, "src/keybed.rs", 2, 3, "");
+        let mut bytes = vec![];
+        report.encode(&mut |byte| bytes.push(byte));
+
+        assert_eq!(PanicReport::decode(&bytes[..HEADER_LEN + 4]), None);
+        assert_eq!(PanicReport::decode(&bytes[..5]), None);
+    }
+}
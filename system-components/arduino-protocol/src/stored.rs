@@ -0,0 +1,221 @@
+// Settings kept in the arduinos' EEPROM, so a board keeps its calibration when the Pi's image is
+//   reflashed. Both boards have the same block of STORED_LEN u16s, numbered by STORED_*. Each one
+//   uses the settings it needs and keeps the rest for the daemon to read back (see Command::Load).
+//
+//   0   STORED_VERSION
+//   1.. STORED_LEN big endian u16s
+//   then a CRC-8 (see frame.rs) of everything before it
+//
+// A block with the wrong version or CRC reads as if nothing was stored, it's started again the next
+//   time something's stored. So is one left half written by a reset, a board that forgets its
+//   settings is better than one that trusts garbage
+
+use crate::frame::crc8_update;
+
+// Bumped whenever a setting moves, boards with an older block start again from nothing
+pub const STORED_VERSION: u8 = 1;
+pub const STORED_LEN: u8 = 96;
+// How much EEPROM the block takes
+pub const STORED_SIZE: u16 = 1 + STORED_LEN as u16 * 2 + 1;
+
+// What erased EEPROM reads as. Storing it clears a setting
+pub const UNSET: u16 = 0xFFFF;
+
+// The first STORED_PARAMS are the board's own parameters (see PARAM_*), numbered the same. The
+//   firmware sets them as it starts, before the daemon's around to
+pub const STORED_PARAMS: u8 = 8;
+// Dials: min, max, center and deadzone for the volume, pitch and modulation faders, in that order
+pub const STORED_FADER_CALIBRATION: u8 = 16;
+// Keyboard: the matrix slot of every key, from key 0 up
+pub const STORED_KEY_MAP: u8 = 32;
+
+// Byte by byte access to wherever the block is kept. The firmware's is the EEPROM
+pub trait Storage {
+    fn read(&self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, byte: u8);
+}
+
+pub struct StoredSettings<S: Storage> {
+    storage: S,
+    // Where the block starts
+    start: u16,
+}
+
+impl<S: Storage> StoredSettings<S> {
+    pub fn new(storage: S, start: u16) -> Self {
+        Self { storage, start }
+    }
+
+    // None when it's out of range or nothing's stored
+    pub fn get(&self, setting: u8) -> Option<u16> {
+        if setting >= STORED_LEN || !self.is_valid() {
+            return None;
+        }
+
+        let addr = self.value_addr(setting);
+        let value = u16::from_be_bytes([self.storage.read(addr), self.storage.read(addr + 1)]);
+        (value != UNSET).then_some(value)
+    }
+
+    // False when it's out of range
+    pub fn set(&mut self, setting: u8, value: u16) -> bool {
+        if setting >= STORED_LEN {
+            return false;
+        }
+
+        if !self.is_valid() {
+            self.write(self.start, STORED_VERSION);
+            for other in 0..STORED_LEN {
+                self.write_value(other, UNSET);
+            }
+        }
+
+        self.write_value(setting, value);
+        self.write(self.crc_addr(), self.crc());
+        true
+    }
+
+    fn is_valid(&self) -> bool {
+        self.storage.read(self.start) == STORED_VERSION
+            && self.storage.read(self.crc_addr()) == self.crc()
+    }
+
+    fn crc(&self) -> u8 {
+        (self.start..self.crc_addr()).fold(0, |crc, addr| crc8_update(crc, self.storage.read(addr)))
+    }
+
+    fn write_value(&mut self, setting: u8, value: u16) {
+        let addr = self.value_addr(setting);
+        let [high, low] = value.to_be_bytes();
+        self.write(addr, high);
+        self.write(addr + 1, low);
+    }
+
+    // EEPROM wears out, and each write takes a few ms. Bytes that are already right are left alone
+    fn write(&mut self, addr: u16, byte: u8) {
+        if self.storage.read(addr) != byte {
+            self.storage.write(addr, byte);
+        }
+    }
+
+    fn value_addr(&self, setting: u8) -> u16 {
+        self.start + 1 + setting as u16 * 2
+    }
+
+    fn crc_addr(&self) -> u16 {
+        self.start + STORED_SIZE - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: u16 = 10;
+
+    // Erased, like a new board's
+    struct FakeEeprom {
+        bytes: Vec<u8>,
+        writes: usize,
+    }
+
+    impl Storage for &mut FakeEeprom {
+        fn read(&self, addr: u16) -> u8 {
+            self.bytes[addr as usize]
+        }
+
+        fn write(&mut self, addr: u16, byte: u8) {
+            self.bytes[addr as usize] = byte;
+            self.writes += 1;
+        }
+    }
+
+    fn eeprom() -> FakeEeprom {
+        FakeEeprom {
+            bytes: vec![0xFF; (START + STORED_SIZE) as usize],
+            writes: 0,
+        }
+    }
+
+    #[test]
+    fn nothing_is_stored_at_first() {
+        let mut eeprom = eeprom();
+        let settings = StoredSettings::new(&mut eeprom, START);
+
+        assert!((0..STORED_LEN).all(|setting| settings.get(setting).is_none()));
+    }
+
+    #[test]
+    fn stored_settings_read_back() {
+        let mut eeprom = eeprom();
+        let mut settings = StoredSettings::new(&mut eeprom, START);
+
+        assert!(settings.set(0, 5));
+        assert!(settings.set(STORED_LEN - 1, 1000));
+        assert!(!settings.set(STORED_LEN, 1));
+
+        assert_eq!(settings.get(0), Some(5));
+        assert_eq!(settings.get(1), None);
+        assert_eq!(settings.get(STORED_LEN - 1), Some(1000));
+        assert_eq!(settings.get(STORED_LEN), None);
+
+        settings.set(0, UNSET);
+        assert_eq!(settings.get(0), None);
+    }
+
+    #[test]
+    fn nothing_before_the_block_is_touched() {
+        let mut eeprom = eeprom();
+        eeprom.bytes[..START as usize].fill(0x12);
+        StoredSettings::new(&mut eeprom, START).set(3, 3);
+
+        assert!(eeprom.bytes[..START as usize]
+            .iter()
+            .all(|&byte| byte == 0x12));
+    }
+
+    #[test]
+    fn corrupt_blocks_are_ignored_and_started_again() {
+        let mut eeprom = eeprom();
+        let mut settings = StoredSettings::new(&mut eeprom, START);
+        settings.set(0, 5);
+        settings.set(1, 6);
+
+        // Half way through storing a setting, say
+        eeprom.bytes[START as usize + 3] ^= 0x40;
+        let mut settings = StoredSettings::new(&mut eeprom, START);
+        assert_eq!(settings.get(0), None);
+
+        settings.set(2, 7);
+        assert_eq!(settings.get(0), None);
+        assert_eq!(settings.get(1), None);
+        assert_eq!(settings.get(2), Some(7));
+    }
+
+    #[test]
+    fn other_versions_are_ignored() {
+        let mut eeprom = eeprom();
+        StoredSettings::new(&mut eeprom, START).set(0, 5);
+
+        // What an older firmware would have left, with a CRC that matches
+        eeprom.bytes[START as usize] = STORED_VERSION - 1;
+        let crc_addr = (START + STORED_SIZE - 1) as usize;
+        eeprom.bytes[crc_addr] = crate::frame::crc8(&eeprom.bytes[START as usize..crc_addr]);
+
+        assert_eq!(StoredSettings::new(&mut eeprom, START).get(0), None);
+    }
+
+    #[test]
+    fn storing_the_same_value_writes_nothing() {
+        let mut eeprom = eeprom();
+        StoredSettings::new(&mut eeprom, START).set(4, 0x1234);
+        let writes = eeprom.writes;
+
+        StoredSettings::new(&mut eeprom, START).set(4, 0x1234);
+        assert_eq!(eeprom.writes, writes);
+
+        // Just the value and the CRC
+        StoredSettings::new(&mut eeprom, START).set(4, 0x1235);
+        assert_eq!(eeprom.writes, writes + 2);
+    }
+}
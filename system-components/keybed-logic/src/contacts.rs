@@ -0,0 +1,119 @@
+// Dirty or worn contacts give readings a healthy key can't: A without B, when A is only ever reached
+//   through B, or a press or release that turns back faster than a finger could. The state machine
+//   shrugs them off (see key_state.rs), they're counted here so the daemon can point out the keys
+//   that need cleaning.
+
+use crate::key_state::KeyState;
+
+// A press or release that turns back sooner than this is a contact bouncing, not the player
+pub const CHATTER_US: u32 = 2_000;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ContactHealth {
+    // Since the last `take`, saturating
+    impossible: u8,
+    chatter: u8,
+    // A stuck contact only counts once, not on every scan it's stuck for
+    was_impossible: bool,
+}
+
+impl ContactHealth {
+    pub const fn new() -> Self {
+        Self {
+            impossible: 0,
+            chatter: 0,
+            was_impossible: false,
+        }
+    }
+
+    // Called with every reading, before the key's state moves on
+    pub fn check(&mut self, state: KeyState, a_down: bool, b_down: bool, now: u32) {
+        let impossible = a_down && !b_down;
+        if impossible && !self.was_impossible {
+            self.impossible = self.impossible.saturating_add(1);
+        }
+        self.was_impossible = impossible;
+        // It'll end a partial press too, that's already been counted
+        if impossible {
+            return;
+        }
+
+        let turned_back_at = match (state, state.next(a_down, b_down, now)) {
+            // Touched B and left it again
+            (KeyState::DownPartial(at), Some(KeyState::Up)) => at,
+            // Left A and came straight back to it
+            (KeyState::UpPartial(at), Some(KeyState::Down(_))) => at,
+            _ => return,
+        };
+        if now.wrapping_sub(turned_back_at) < CHATTER_US {
+            self.chatter = self.chatter.saturating_add(1);
+        }
+    }
+
+    // Impossible readings and chatter since the last time, None if there weren't any
+    pub fn take(&mut self) -> Option<(u8, u8)> {
+        if self.impossible == 0 && self.chatter == 0 {
+            return None;
+        }
+
+        let counts = (self.impossible, self.chatter);
+        self.impossible = 0;
+        self.chatter = 0;
+        Some(counts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the readings through the state machine like the firmware does, `ms` apart
+    fn play(readings: &[(bool, bool)], ms: u32) -> Option<(u8, u8)> {
+        let mut state = KeyState::Up;
+        let mut health = ContactHealth::new();
+
+        for (i, &(b_down, a_down)) in readings.iter().enumerate() {
+            let now = i as u32 * ms * 1000;
+            health.check(state, a_down, b_down, now);
+            state.update(a_down, b_down, now);
+        }
+
+        health.take()
+    }
+
+    const UP: (bool, bool) = (false, false);
+    const HALF: (bool, bool) = (true, false);
+    const DOWN: (bool, bool) = (true, true);
+    // A without B
+    const BROKEN: (bool, bool) = (false, true);
+
+    #[test]
+    fn healthy_keys_have_nothing_to_report() {
+        assert_eq!(play(&[UP, HALF, DOWN, DOWN, HALF, UP], 1), None);
+        // Pressed half way and thought better of it
+        assert_eq!(play(&[UP, HALF, HALF, UP], 50), None);
+    }
+
+    #[test]
+    fn impossible_readings_count_once_each() {
+        assert_eq!(play(&[UP, BROKEN, BROKEN, BROKEN, UP], 1), Some((1, 0)));
+        assert_eq!(play(&[UP, HALF, BROKEN, HALF, BROKEN, UP], 1), Some((2, 0)));
+    }
+
+    #[test]
+    fn bouncing_contacts_are_chatter() {
+        // B bouncing on the way down
+        assert_eq!(play(&[UP, HALF, UP, HALF, DOWN], 1), Some((0, 1)));
+        // A bouncing on the way up
+        assert_eq!(play(&[DOWN, HALF, DOWN, HALF, UP], 1), Some((0, 1)));
+    }
+
+    #[test]
+    fn taking_the_counts_starts_again() {
+        let mut health = ContactHealth::new();
+        health.check(KeyState::Up, true, false, 0);
+
+        assert_eq!(health.take(), Some((1, 0)));
+        assert_eq!(health.take(), None);
+    }
+}
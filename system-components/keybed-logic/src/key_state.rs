@@ -0,0 +1,221 @@
+// Each key has two contacts under it. When a key is pressed, it hits B first and then A, the time
+//   between the two is how hard it was pressed. Times are micros from the firmware's clock, which
+//   wraps around every 71 minutes.
+
+use arduino_protocol::KeyEvent;
+
+// Both contacts changed between two scans, report as the smallest resolution we can trust
+pub const FASTEST_TRAVEL_TIME: u16 = 20;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum KeyState {
+    // Key goes through these states in order
+    // B up, A up
+    Up,
+    // B down, A up (Key may go to "up" after "partial")
+    DownPartial(u32), // micros when B triggered
+    // B down, A down (Key may only go to "down" after "partial")
+    Down(u16), // travel time
+    // B down, A up after being down (Key is on its way back up)
+    UpPartial(u32), // micros when A released
+}
+
+// Tenths of a millisecond from `since` to `now`, saturating at the longest time we can send
+pub fn travel_time(since: u32, now: u32) -> u16 {
+    let tenths = now.wrapping_sub(since) / 100;
+    tenths.min(u16::MAX as u32) as u16
+}
+
+impl KeyState {
+    // | B | A | STATE | NEW_STATE | DESC                                        |
+    // |---|---|-------|-----------|---------------------------------------------|
+    // | 0 | 0 | Up    | Up        | Key is in the neutral position              |
+    // | 1 | 0 | Up    | DownP     | A key press has started                     |
+    // | 0 | 1 | Up    | Up        | Physically impossible                       |
+    // | 1 | 1 | Up    | Down      | Key was pressed faster than we could detect |
+    // | 0 | 0 | DownP | Up        | Key was pressed halfway, then released      |
+    // | 1 | 0 | DownP | DownP     | Key is travelling                           |
+    // | 0 | 1 | DownP | Up        | Physically impossible                       |
+    // | 1 | 1 | DownP | Down      | Key was pressed all the way                 |
+    // | 0 | 0 | Down  | Up        | Key released faster than we could detect    |
+    // | 1 | 0 | Down  | UpP       | Key release has started                     |
+    // | 0 | 1 | Down  | Up        | Physically impossible.                      |
+    // | 1 | 1 | Down  | Down      | Key is being held down                      |
+    // | 0 | 0 | UpP   | Up        | Key release has finished                    |
+    // | 1 | 0 | UpP   | UpP       | Key is travelling                           |
+    // | 0 | 1 | UpP   | Up        | Physically impossible                       |
+    // | 1 | 1 | UpP   | Down      | Key was released halfway, then pressed      |
+    //
+    // None when the state doesn't change
+    pub fn next(self, a_down: bool, b_down: bool, now: u32) -> Option<KeyState> {
+        match (b_down, a_down, self) {
+            // Key touched first contact
+            (true, false, KeyState::Up) => Some(KeyState::DownPartial(now)),
+            // Key touched both contacts, calculate travel time
+            (true, true, KeyState::DownPartial(at)) => Some(KeyState::Down(travel_time(at, now))),
+            // Key touched both contacts before we could register the first, report as the smallest resolution
+            (true, true, KeyState::Up) => Some(KeyState::Down(FASTEST_TRAVEL_TIME)),
+            // Key left the second contact on its way up
+            (true, false, KeyState::Down(_)) => Some(KeyState::UpPartial(now)),
+            // Key went back down before it was released, it's still the same press
+            (true, true, KeyState::UpPartial(_)) => Some(KeyState::Down(0)),
+            // Key is always up if the first contact is up
+            (false, _, KeyState::Up) => None,
+            (false, _, _) => Some(KeyState::Up),
+            // Anything else shouldn't change the current state
+            _ => None,
+        }
+    }
+
+    // What to send when the key goes from this state to `next`, if anything
+    pub fn event(self, next: KeyState, now: u32) -> Option<KeyEvent> {
+        match (self, next) {
+            // Going back down part way through a release isn't a new press
+            (KeyState::UpPartial(_), KeyState::Down(_)) => None,
+            // Down state is always reported
+            (_, KeyState::Down(travel_time)) => Some(KeyEvent::Down(travel_time)),
+            // Up state is only reported when key had been fully depressed
+            (KeyState::UpPartial(at), KeyState::Up) => Some(KeyEvent::Up(travel_time(at, now))),
+            // Released faster than we could detect, same as pressing
+            (KeyState::Down(_), KeyState::Up) => Some(KeyEvent::Up(FASTEST_TRAVEL_TIME)),
+            _ => None,
+        }
+    }
+
+    // Moves on to the next state for these contact readings, returns the event to send
+    pub fn update(&mut self, a_down: bool, b_down: bool, now: u32) -> Option<KeyEvent> {
+        let next = self.next(a_down, b_down, now)?;
+        let event = self.event(next, now);
+        *self = next;
+        event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THEN: u32 = 10_000;
+    const NOW: u32 = 22_345;
+    // From THEN to NOW
+    const TRAVEL: u16 = 123;
+
+    // The table above, with the state the key ends up in
+    #[test]
+    fn transitions() {
+        use KeyState::*;
+
+        #[rustfmt::skip]
+        let table = [
+            // B, A, state, new state
+            (false, false, Up, Up),
+            (true, false, Up, DownPartial(NOW)),
+            (false, true, Up, Up),
+            (true, true, Up, Down(FASTEST_TRAVEL_TIME)),
+            (false, false, DownPartial(THEN), Up),
+            (true, false, DownPartial(THEN), DownPartial(THEN)),
+            (false, true, DownPartial(THEN), Up),
+            (true, true, DownPartial(THEN), Down(TRAVEL)),
+            (false, false, Down(TRAVEL), Up),
+            (true, false, Down(TRAVEL), UpPartial(NOW)),
+            (false, true, Down(TRAVEL), Up),
+            (true, true, Down(TRAVEL), Down(TRAVEL)),
+            (false, false, UpPartial(THEN), Up),
+            (true, false, UpPartial(THEN), UpPartial(THEN)),
+            (false, true, UpPartial(THEN), Up),
+            (true, true, UpPartial(THEN), Down(0)),
+        ];
+
+        for (b_down, a_down, state, expected) in table {
+            let next = state.next(a_down, b_down, NOW).unwrap_or(state);
+            assert_eq!(next, expected, "B {} A {} from {:?}", b_down, a_down, state);
+        }
+    }
+
+    #[test]
+    fn events() {
+        use KeyState::*;
+
+        #[rustfmt::skip]
+        let table = [
+            // state, new state, event
+            (Up, DownPartial(NOW), None),
+            (Up, Down(FASTEST_TRAVEL_TIME), Some(KeyEvent::Down(FASTEST_TRAVEL_TIME))),
+            (DownPartial(THEN), Up, None),
+            (DownPartial(THEN), Down(TRAVEL), Some(KeyEvent::Down(TRAVEL))),
+            (Down(TRAVEL), UpPartial(NOW), None),
+            (Down(TRAVEL), Up, Some(KeyEvent::Up(FASTEST_TRAVEL_TIME))),
+            (UpPartial(THEN), Up, Some(KeyEvent::Up(TRAVEL))),
+            (UpPartial(THEN), Down(0), None),
+        ];
+
+        for (state, next, expected) in table {
+            assert_eq!(
+                state.event(next, NOW),
+                expected,
+                "{:?} to {:?}",
+                state,
+                next
+            );
+        }
+    }
+
+    #[test]
+    fn travel_time_wraps_and_saturates() {
+        assert_eq!(travel_time(u32::MAX - 49, 150), 2);
+        assert_eq!(travel_time(0, 100 * u16::MAX as u32 + 99), u16::MAX);
+        assert_eq!(travel_time(0, u32::MAX), u16::MAX);
+    }
+
+    // xorshift, so the random contact readings are the same on every run
+    struct Random(u32);
+
+    impl Random {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+    }
+
+    // Whatever the contacts do, a key is never pressed twice without being released in between, and
+    //   it's up whenever its first contact is
+    #[test]
+    fn random_readings_keep_presses_and_releases_paired() {
+        for seed in 1..=100 {
+            let mut random = Random(seed);
+            let mut state = KeyState::Up;
+            let mut down = false;
+            let mut now = random.next();
+
+            for _ in 0..1000 {
+                let bits = random.next();
+                let (a_down, b_down) = (bits & 1 != 0, bits & 2 != 0);
+                now = now.wrapping_add(bits >> 16);
+
+                match state.update(a_down, b_down, now) {
+                    Some(KeyEvent::Down(_)) => {
+                        assert!(!down, "pressed twice, seed {}", seed);
+                        assert!(a_down && b_down);
+                        down = true;
+                    }
+                    Some(KeyEvent::Up(_)) => {
+                        assert!(down, "released twice, seed {}", seed);
+                        down = false;
+                    }
+                    None => {}
+                }
+
+                if !b_down {
+                    assert_eq!(state, KeyState::Up);
+                    assert!(!down);
+                }
+                assert_eq!(
+                    down,
+                    matches!(state, KeyState::Down(_) | KeyState::UpPartial(_))
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,14 @@
+// The parts of the keyboard firmware's keybed scan that don't touch the pins: which key each contact
+//   pair belongs to (matrix.rs), how each key moves between states as its contacts change
+//   (key_state.rs) and which readings point to a bad contact (contacts.rs). The firmware reads the
+//   pins and passes the readings in along with the time.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod contacts;
+pub mod key_state;
+pub mod matrix;
+
+pub use contacts::{ContactHealth, CHATTER_US};
+pub use key_state::{KeyState, FASTEST_TRAVEL_TIME};
+pub use matrix::{key_index, slot, KEYS, KEYS_PER_SECTION, SECTIONS, SLOTS};
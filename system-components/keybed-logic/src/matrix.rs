@@ -0,0 +1,101 @@
+// The key matrix has 8 sections, selected one at a time by the shift register, and 7 inputs for
+//   each of the two contacts under a key. That's room for 56 keys, 49 are wired up.
+//
+// Each place in the matrix is a slot, numbered in the order they're scanned. key_index is how this
+//   keybed happens to be wired, the daemon can map slots to keys itself instead (see PARAM_RAW_KEYS)
+
+// The leftmost key on the keyboard is 0 and the rightmost key is KEYS-1
+pub const KEYS: usize = 49;
+pub const SECTIONS: usize = 8;
+pub const KEYS_PER_SECTION: usize = 7;
+pub const SLOTS: usize = SECTIONS * KEYS_PER_SECTION;
+
+pub fn slot(section: usize, input: usize) -> usize {
+    section * KEYS_PER_SECTION + input
+}
+
+// Which key is under `input` while `section` is selected, if there is one.
+// This is funky because I wired things bad: input n has keys n*8 to n*8+7, but the first section
+//   selected is the last of those eight
+pub fn key_index(section: usize, input: usize) -> Option<usize> {
+    if section >= SECTIONS || input >= KEYS_PER_SECTION {
+        return None;
+    }
+
+    let column = if section == 0 {
+        SECTIONS - 1
+    } else {
+        section - 1
+    };
+    let key = input * SECTIONS + column;
+
+    // The matrix supports more keys than we actually have
+    (key < KEYS).then_some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_slots() -> impl Iterator<Item = (usize, usize)> {
+        (0..SECTIONS).flat_map(|section| (0..KEYS_PER_SECTION).map(move |input| (section, input)))
+    }
+
+    #[test]
+    fn every_key_is_hit_exactly_once() {
+        let mut hits = [0; KEYS];
+        for (section, input) in all_slots() {
+            if let Some(key) = key_index(section, input) {
+                hits[key] += 1;
+            }
+        }
+
+        assert_eq!(hits, [1; KEYS]);
+    }
+
+    #[test]
+    fn every_slot_is_numbered_once() {
+        let mut hits = [0; SLOTS];
+        for (section, input) in all_slots() {
+            hits[slot(section, input)] += 1;
+        }
+
+        assert_eq!(hits, [1; SLOTS]);
+    }
+
+    #[test]
+    fn only_the_missing_keys_are_unwired() {
+        let unwired = all_slots().filter(|&(section, input)| key_index(section, input).is_none());
+
+        assert_eq!(unwired.count(), SECTIONS * KEYS_PER_SECTION - KEYS);
+    }
+
+    // The keys at each end of the keyboard and either side of the first section's
+    #[test]
+    fn matches_the_wiring() {
+        assert_eq!(key_index(1, 0), Some(0));
+        assert_eq!(key_index(0, 0), Some(7));
+        assert_eq!(key_index(1, 1), Some(8));
+        assert_eq!(key_index(0, 5), Some(47));
+        assert_eq!(key_index(1, 6), Some(48));
+        assert_eq!(key_index(2, 6), None);
+        assert_eq!(key_index(0, 6), None);
+    }
+
+    #[test]
+    fn out_of_range_slots_have_no_key() {
+        assert_eq!(key_index(SECTIONS, 0), None);
+        assert_eq!(key_index(1, KEYS_PER_SECTION), None);
+    }
+
+    // The firmware used to work it out as `((j * 8) + (if i == 0 { 8 } else { i })) - 1`
+    #[test]
+    fn original_formula_agrees() {
+        for (section, input) in all_slots() {
+            let original = (input * 8) + (if section == 0 { 8 } else { section }) - 1;
+            let expected = (original < KEYS).then_some(original);
+
+            assert_eq!(key_index(section, input), expected, "{} {}", section, input);
+        }
+    }
+}
@@ -0,0 +1,710 @@
+// Arpeggiator, sitting between the drivers and the midi sink.
+// While it's on, held notes are swallowed and played back one at a time from an internal clock.
+// Everything else (controllers, pitch bend, sysex) passes straight through.
+//
+// The arpeggiator itself never looks at the real clock, it's handed the time since the stage started.
+// That keeps it deterministic, so the tests can drive it with a made up clock.
+
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender};
+use midly::num::{u4, u7};
+use midly::MidiMessage::{NoteOff, NoteOn};
+
+use crate::config::Section;
+use crate::midi_sender::MidiEvent;
+use crate::settings::SharedSettings;
+
+// Steps are sixteenth notes
+const STEPS_PER_BEAT: u32 = 4;
+
+const MIN_BPM: u16 = 20;
+const MAX_BPM: u16 = 300;
+const MAX_OCTAVES: u8 = 4;
+const MIN_GATE: u8 = 10;
+const MAX_GATE: u8 = 100;
+
+// How often settings are checked while nothing is playing, so turning the arp off is noticed
+const IDLE_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ArpOrder {
+    Up,
+    Down,
+    UpDown,
+    Random,
+    AsPlayed,
+}
+
+const ORDERS: [ArpOrder; 5] = [
+    ArpOrder::Up,
+    ArpOrder::Down,
+    ArpOrder::UpDown,
+    ArpOrder::Random,
+    ArpOrder::AsPlayed,
+];
+
+impl ArpOrder {
+    pub fn name(self) -> &'static str {
+        match self {
+            ArpOrder::Up => "up",
+            ArpOrder::Down => "down",
+            ArpOrder::UpDown => "up-down",
+            ArpOrder::Random => "random",
+            ArpOrder::AsPlayed => "as-played",
+        }
+    }
+
+    fn from_name(name: &str) -> Result<Self> {
+        match ORDERS.iter().find(|order| order.name() == name) {
+            Some(order) => Ok(*order),
+            None => bail!("[arpeggiator] unknown order '{}'", name),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct ArpSettings {
+    pub enabled: bool,
+    pub order: ArpOrder,
+    pub octaves: u8,
+    // Percent of each step the note is held for
+    pub gate: u8,
+    // Keep playing after the keys are let go, until the next chord
+    pub latch: bool,
+    pub bpm: u16,
+}
+
+impl Default for ArpSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            order: ArpOrder::Up,
+            octaves: 1,
+            gate: 50,
+            latch: false,
+            bpm: 120,
+        }
+    }
+}
+
+impl ArpSettings {
+    // Always starts disabled, it's confusing to boot into an arpeggiator
+    pub fn from_section(section: &Section) -> Result<Self> {
+        let default = Self::default();
+
+        Ok(Self {
+            enabled: false,
+            order: match section.get("order") {
+                Some(name) => ArpOrder::from_name(name)?,
+                None => default.order,
+            },
+            octaves: section
+                .parse_or("octaves", default.octaves)?
+                .clamp(1, MAX_OCTAVES),
+            gate: section
+                .parse_or("gate", default.gate)?
+                .clamp(MIN_GATE, MAX_GATE),
+            latch: section.parse_or("latch", default.latch)?,
+            bpm: section
+                .parse_or("bpm", default.bpm)?
+                .clamp(MIN_BPM, MAX_BPM),
+        })
+    }
+
+    pub fn to_section(self) -> Section {
+        let mut section = Section::new("arpeggiator");
+        section.set("order", self.order.name());
+        section.set("octaves", self.octaves);
+        section.set("gate", self.gate);
+        section.set("latch", self.latch);
+        section.set("bpm", self.bpm);
+
+        section
+    }
+
+    pub fn step_order(&mut self, step: i32) {
+        let current = ORDERS
+            .iter()
+            .position(|order| *order == self.order)
+            .unwrap();
+        self.order = ORDERS[(current as i32 + step).rem_euclid(ORDERS.len() as i32) as usize];
+    }
+
+    pub fn step_octaves(&mut self, step: i32) {
+        self.octaves = (self.octaves as i32 + step).clamp(1, MAX_OCTAVES as i32) as u8;
+    }
+
+    pub fn step_gate(&mut self, step: i32) {
+        self.gate = (self.gate as i32 + step * 10).clamp(MIN_GATE as i32, MAX_GATE as i32) as u8;
+    }
+
+    pub fn step_bpm(&mut self, step: i32) {
+        self.bpm = (self.bpm as i32 + step).clamp(MIN_BPM as i32, MAX_BPM as i32) as u16;
+    }
+
+    fn step_length(&self) -> Duration {
+        Duration::from_secs(60) / (self.bpm as u32 * STEPS_PER_BEAT)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+struct HeldNote {
+    channel: u4,
+    key: u7,
+    vel: u7,
+}
+
+struct Sounding {
+    channel: u4,
+    key: u7,
+    off_at: Duration,
+}
+
+pub struct Arpeggiator {
+    settings: ArpSettings,
+
+    // Keys that are physically down, in the order they were pressed
+    pressed: Vec<HeldNote>,
+    // What's being arpeggiated. Same as pressed, unless latch is holding on to released notes
+    notes: Vec<HeldNote>,
+
+    sounding: Option<Sounding>,
+    next_step_at: Option<Duration>,
+    step: usize,
+    rng: u32,
+}
+
+impl Arpeggiator {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            settings: ArpSettings::default(),
+            pressed: vec![],
+            notes: vec![],
+            sounding: None,
+            next_step_at: None,
+            step: 0,
+            // Xorshift gets stuck on 0
+            rng: seed.max(1),
+        }
+    }
+
+    pub fn configure(&mut self, settings: ArpSettings) -> Vec<MidiEvent> {
+        let old = std::mem::replace(&mut self.settings, settings);
+
+        let mut events = vec![];
+        if old.enabled && !settings.enabled {
+            events.extend(self.release_all());
+        } else if old.latch && !settings.latch {
+            self.notes = self.pressed.clone();
+            if self.notes.is_empty() {
+                events.extend(self.stop());
+            }
+        }
+
+        events
+    }
+
+    // Anything that isn't a note, or arrives while the arp is off, goes straight through
+    pub fn process(&mut self, event: MidiEvent, now: Duration) -> Vec<MidiEvent> {
+        if !self.settings.enabled {
+            return vec![event];
+        }
+
+        match event {
+            MidiEvent::Channel {
+                channel,
+                message: NoteOn { key, vel },
+            } if vel > 0 => {
+                self.note_on(HeldNote { channel, key, vel }, now);
+                vec![]
+            }
+            MidiEvent::Channel {
+                channel,
+                message: NoteOn { key, .. } | NoteOff { key, .. },
+            } if self
+                .pressed
+                .iter()
+                .any(|note| note.channel == channel && note.key == key) =>
+            {
+                self.note_off(channel, key)
+            }
+            // Whatever we were playing is released by the sink, we just need to forget about it
+            MidiEvent::AllNotesOff => {
+                self.release_all();
+                vec![MidiEvent::AllNotesOff]
+            }
+            // Includes releases of notes that started before the arp was turned on
+            event => vec![event],
+        }
+    }
+
+    fn note_on(&mut self, note: HeldNote, now: Duration) {
+        // Latched notes are replaced by the next chord, not added to
+        if self.settings.latch && self.pressed.is_empty() {
+            self.notes.clear();
+        }
+
+        self.pressed.push(note);
+        self.notes.push(note);
+
+        // Start on the first note rather than waiting for the next step
+        if self.next_step_at.is_none() {
+            self.next_step_at = Some(now);
+            self.step = 0;
+        }
+    }
+
+    fn note_off(&mut self, channel: u4, key: u7) -> Vec<MidiEvent> {
+        let is_note = |note: &HeldNote| note.channel == channel && note.key == key;
+        self.pressed.retain(|note| !is_note(note));
+
+        if self.settings.latch {
+            return vec![];
+        }
+
+        self.notes.retain(|note| !is_note(note));
+        if self.notes.is_empty() {
+            return self.stop();
+        }
+
+        vec![]
+    }
+
+    // When the arpeggiator next has something to do
+    pub fn next_deadline(&self) -> Option<Duration> {
+        let off_at = self.sounding.as_ref().map(|sounding| sounding.off_at);
+
+        match (off_at, self.next_step_at) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    pub fn tick(&mut self, now: Duration) -> Vec<MidiEvent> {
+        let mut events = vec![];
+
+        if self
+            .sounding
+            .as_ref()
+            .is_some_and(|sounding| sounding.off_at <= now)
+        {
+            events.extend(self.release_sounding());
+        }
+
+        let Some(mut step_at) = self.next_step_at else {
+            return events;
+        };
+        if step_at > now {
+            return events;
+        }
+
+        // Steps are spaced from when they should have happened, not when we got round to them, so
+        //   the tempo doesn't drift. If we've fallen a whole step behind, give up and start again from now
+        let step_length = self.settings.step_length();
+        if step_at + step_length <= now {
+            step_at = now;
+        }
+
+        // A full gate runs right up to the next step, release it before starting the next note
+        events.extend(self.release_sounding());
+
+        if let Some(note) = self.next_note() {
+            events.push(MidiEvent::new(
+                note.channel,
+                NoteOn {
+                    key: note.key,
+                    vel: note.vel,
+                },
+            ));
+            self.sounding = Some(Sounding {
+                channel: note.channel,
+                key: note.key,
+                off_at: step_at + step_length * self.settings.gate as u32 / 100,
+            });
+        }
+
+        self.next_step_at = Some(step_at + step_length);
+        self.step += 1;
+
+        events
+    }
+
+    fn next_note(&mut self) -> Option<HeldNote> {
+        let mut sequence = self.notes.clone();
+        if sequence.is_empty() {
+            return None;
+        }
+
+        match self.settings.order {
+            ArpOrder::AsPlayed | ArpOrder::Random => {}
+            ArpOrder::Up | ArpOrder::UpDown => sequence.sort_by_key(|note| note.key),
+            ArpOrder::Down => sequence.sort_by_key(|note| std::cmp::Reverse(note.key)),
+        }
+
+        // Each octave plays the whole sequence again, higher. Notes shifted off the top are dropped
+        let mut sequence: Vec<HeldNote> = (0..self.settings.octaves)
+            .flat_map(|octave| {
+                sequence.iter().filter_map(move |note| {
+                    let key = u7::try_from(note.key.as_int() + 12 * octave)?;
+                    Some(HeldNote { key, ..*note })
+                })
+            })
+            .collect();
+        if self.settings.order == ArpOrder::Down {
+            sequence.sort_by_key(|note| std::cmp::Reverse(note.key));
+        }
+
+        // Back down without repeating the top and bottom notes
+        if self.settings.order == ArpOrder::UpDown && sequence.len() > 2 {
+            let down: Vec<HeldNote> = sequence[1..sequence.len() - 1]
+                .iter()
+                .rev()
+                .copied()
+                .collect();
+            sequence.extend(down);
+        }
+
+        let index = match self.settings.order {
+            ArpOrder::Random => self.random() as usize % sequence.len(),
+            _ => self.step % sequence.len(),
+        };
+
+        Some(sequence[index])
+    }
+
+    fn release_sounding(&mut self) -> Vec<MidiEvent> {
+        match self.sounding.take() {
+            None => vec![],
+            Some(sounding) => vec![MidiEvent::new(
+                sounding.channel,
+                NoteOff {
+                    key: sounding.key,
+                    vel: Default::default(),
+                },
+            )],
+        }
+    }
+
+    fn stop(&mut self) -> Vec<MidiEvent> {
+        self.notes.clear();
+        self.next_step_at = None;
+
+        self.release_sounding()
+    }
+
+    // Turning off forgets everything, keys still held when it comes back on won't play until pressed again
+    fn release_all(&mut self) -> Vec<MidiEvent> {
+        self.pressed.clear();
+
+        self.stop()
+    }
+
+    fn random(&mut self) -> u32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+
+        self.rng
+    }
+}
+
+// Start a thread to run midi events through the arpeggiator on their way to the sink
+pub fn start_arpeggiator(
+    settings: SharedSettings,
+    input: Receiver<MidiEvent>,
+    output: Sender<MidiEvent>,
+) -> JoinHandle<Result<()>> {
+    thread::spawn(move || -> Result<()> {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH)?.subsec_nanos();
+        let mut arpeggiator = Arpeggiator::new(seed);
+        let start = Instant::now();
+
+        loop {
+            let arp_settings = settings.read().unwrap().arpeggiator();
+            for event in arpeggiator.configure(arp_settings) {
+                output.try_send(event)?;
+            }
+
+            let timeout = arpeggiator
+                .next_deadline()
+                .map_or(IDLE_POLL, |deadline| {
+                    deadline.saturating_sub(start.elapsed())
+                })
+                .min(IDLE_POLL);
+
+            match input.recv_timeout(timeout) {
+                Ok(event) => {
+                    for event in arpeggiator.process(event, start.elapsed()) {
+                        output.try_send(event)?;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+
+            for event in arpeggiator.tick(start.elapsed()) {
+                output.try_send(event)?;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNEL: u4 = u4::new(0);
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn on(key: u8) -> MidiEvent {
+        MidiEvent::new(
+            CHANNEL,
+            NoteOn {
+                key: u7::new(key),
+                vel: u7::new(100),
+            },
+        )
+    }
+
+    fn off(key: u8) -> MidiEvent {
+        MidiEvent::new(
+            CHANNEL,
+            NoteOff {
+                key: u7::new(key),
+                vel: u7::new(0),
+            },
+        )
+    }
+
+    // 150bpm sixteenths are exactly 100ms, which keeps the timings readable
+    fn arpeggiator(order: ArpOrder, octaves: u8, gate: u8, latch: bool) -> Arpeggiator {
+        let mut arpeggiator = Arpeggiator::new(1234);
+        arpeggiator.configure(ArpSettings {
+            enabled: true,
+            order,
+            octaves,
+            gate,
+            latch,
+            bpm: 150,
+        });
+
+        arpeggiator
+    }
+
+    // Runs the simulated clock forward one millisecond at a time, collecting (time, event)
+    fn run(arpeggiator: &mut Arpeggiator, from: u64, to: u64) -> Vec<(u64, MidiEvent)> {
+        (from..to)
+            .flat_map(|t| {
+                arpeggiator
+                    .tick(ms(t))
+                    .into_iter()
+                    .map(move |event| (t, event))
+            })
+            .collect()
+    }
+
+    fn press(arpeggiator: &mut Arpeggiator, keys: &[u8], t: u64) {
+        for &key in keys {
+            assert!(arpeggiator.process(on(key), ms(t)).is_empty());
+        }
+    }
+
+    fn note_ons(events: &[(u64, MidiEvent)]) -> Vec<u8> {
+        events
+            .iter()
+            .filter_map(|(_, event)| match event {
+                MidiEvent::Channel {
+                    message: NoteOn { key, .. },
+                    ..
+                } => Some(key.as_int()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn passes_everything_through_when_disabled() {
+        let mut arpeggiator = Arpeggiator::new(1);
+
+        assert_eq!(arpeggiator.process(on(60), ms(0)), vec![on(60)]);
+        assert_eq!(arpeggiator.process(off(60), ms(5)), vec![off(60)]);
+        assert!(run(&mut arpeggiator, 0, 1000).is_empty());
+    }
+
+    #[test]
+    fn passes_controllers_through_when_enabled() {
+        let mut arpeggiator = arpeggiator(ArpOrder::Up, 1, 50, false);
+        let sustain = MidiEvent::new(
+            CHANNEL,
+            midly::MidiMessage::Controller {
+                controller: u7::new(64),
+                value: u7::new(127),
+            },
+        );
+
+        assert_eq!(arpeggiator.process(sustain.clone(), ms(0)), vec![sustain]);
+    }
+
+    #[test]
+    fn steps_and_gates_are_timed() {
+        let mut arpeggiator = arpeggiator(ArpOrder::Up, 1, 50, false);
+        press(&mut arpeggiator, &[64, 60], 0);
+
+        assert_eq!(
+            run(&mut arpeggiator, 0, 250),
+            vec![
+                (0, on(60)),
+                (50, off(60)),
+                (100, on(64)),
+                (150, off(64)),
+                (200, on(60)),
+            ]
+        );
+    }
+
+    #[test]
+    fn full_gate_releases_before_the_next_note() {
+        let mut arpeggiator = arpeggiator(ArpOrder::Up, 1, 100, false);
+        press(&mut arpeggiator, &[60, 64], 0);
+
+        assert_eq!(
+            run(&mut arpeggiator, 0, 101),
+            vec![(0, on(60)), (100, off(60)), (100, on(64))]
+        );
+    }
+
+    #[test]
+    fn orders() {
+        let cases = [
+            (ArpOrder::Up, vec![60, 64, 67, 60, 64]),
+            (ArpOrder::Down, vec![67, 64, 60, 67, 64]),
+            (ArpOrder::UpDown, vec![60, 64, 67, 64, 60, 64]),
+            (ArpOrder::AsPlayed, vec![64, 60, 67, 64, 60]),
+        ];
+
+        for (order, expected) in cases {
+            let mut arpeggiator = arpeggiator(order, 1, 50, false);
+            press(&mut arpeggiator, &[64, 60, 67], 0);
+
+            let played = note_ons(&run(&mut arpeggiator, 0, expected.len() as u64 * 100));
+            assert_eq!(played, expected, "{:?}", order);
+        }
+    }
+
+    #[test]
+    fn random_only_plays_held_notes() {
+        let mut arpeggiator = arpeggiator(ArpOrder::Random, 2, 50, false);
+        press(&mut arpeggiator, &[60, 64], 0);
+
+        let played = note_ons(&run(&mut arpeggiator, 0, 10_000));
+        assert_eq!(played.len(), 100);
+        assert!(played.iter().all(|key| [60, 64, 72, 76].contains(key)));
+        for key in [60, 64, 72, 76] {
+            assert!(played.contains(&key));
+        }
+    }
+
+    #[test]
+    fn octave_range() {
+        let mut arpeggiator = arpeggiator(ArpOrder::Up, 3, 50, false);
+        press(&mut arpeggiator, &[60, 64], 0);
+
+        let played = note_ons(&run(&mut arpeggiator, 0, 700));
+        assert_eq!(played, vec![60, 64, 72, 76, 84, 88, 60]);
+    }
+
+    #[test]
+    fn octaves_above_midi_range_are_dropped() {
+        let mut arpeggiator = arpeggiator(ArpOrder::Up, 2, 50, false);
+        press(&mut arpeggiator, &[120], 0);
+
+        let played = note_ons(&run(&mut arpeggiator, 0, 300));
+        assert_eq!(played, vec![120, 120, 120]);
+    }
+
+    #[test]
+    fn releasing_every_key_stops_immediately() {
+        let mut arpeggiator = arpeggiator(ArpOrder::Up, 1, 100, false);
+        press(&mut arpeggiator, &[60], 0);
+        run(&mut arpeggiator, 0, 30);
+
+        assert_eq!(arpeggiator.process(off(60), ms(30)), vec![off(60)]);
+        assert!(run(&mut arpeggiator, 30, 1000).is_empty());
+        assert_eq!(arpeggiator.next_deadline(), None);
+    }
+
+    #[test]
+    fn latch_holds_until_the_next_chord() {
+        let mut arpeggiator = arpeggiator(ArpOrder::Up, 1, 50, true);
+        press(&mut arpeggiator, &[60, 64], 0);
+        arpeggiator.process(off(60), ms(10));
+        arpeggiator.process(off(64), ms(10));
+
+        let played = note_ons(&run(&mut arpeggiator, 0, 400));
+        assert_eq!(played, vec![60, 64, 60, 64]);
+
+        // A new chord replaces the latched one
+        press(&mut arpeggiator, &[67], 400);
+        let played = note_ons(&run(&mut arpeggiator, 400, 600));
+        assert_eq!(played, vec![67, 67]);
+    }
+
+    #[test]
+    fn turning_off_releases_everything() {
+        let mut arpeggiator = arpeggiator(ArpOrder::Up, 1, 100, true);
+        press(&mut arpeggiator, &[60, 64], 0);
+        run(&mut arpeggiator, 0, 150);
+
+        let mut settings = arpeggiator.settings;
+        settings.enabled = false;
+        assert_eq!(arpeggiator.configure(settings), vec![off(64)]);
+        assert!(run(&mut arpeggiator, 150, 1000).is_empty());
+
+        // Keys released after turning off pass through like normal
+        assert_eq!(arpeggiator.process(off(60), ms(1000)), vec![off(60)]);
+    }
+
+    #[test]
+    fn passes_through_releases_of_notes_from_before_it_was_on() {
+        let mut arpeggiator = Arpeggiator::new(1);
+        assert_eq!(arpeggiator.process(on(60), ms(0)), vec![on(60)]);
+
+        arpeggiator.configure(ArpSettings {
+            enabled: true,
+            ..ArpSettings::default()
+        });
+        assert_eq!(arpeggiator.process(off(60), ms(10)), vec![off(60)]);
+    }
+
+    #[test]
+    fn all_notes_off_forgets_everything() {
+        let mut arpeggiator = arpeggiator(ArpOrder::Up, 1, 50, true);
+        press(&mut arpeggiator, &[60, 64], 0);
+        run(&mut arpeggiator, 0, 10);
+
+        assert_eq!(
+            arpeggiator.process(MidiEvent::AllNotesOff, ms(10)),
+            vec![MidiEvent::AllNotesOff]
+        );
+        assert!(run(&mut arpeggiator, 10, 1000).is_empty());
+    }
+
+    #[test]
+    fn catches_up_without_bursting_after_a_stall() {
+        let mut arpeggiator = arpeggiator(ArpOrder::Up, 1, 50, false);
+        press(&mut arpeggiator, &[60], 0);
+        arpeggiator.tick(ms(0));
+
+        // Nothing ran for a long time, only one step should play when it does
+        let events = arpeggiator.tick(ms(1000));
+        assert_eq!(events, vec![off(60), on(60)]);
+        assert_eq!(arpeggiator.next_deadline(), Some(ms(1050)));
+    }
+}
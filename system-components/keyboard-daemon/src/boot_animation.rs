@@ -32,7 +32,7 @@ pub fn do_logo_scroll<D: Display>(display: &mut D) {
 
         display.flush().unwrap();
 
-        return t < animation_duration;
+        t < animation_duration
     });
 }
 
@@ -0,0 +1,152 @@
+// What the buttons on the dials board do. Each one can be given any of these in the [buttons] section
+//   of the settings, or from the menu:
+//
+//   [buttons]
+//   af = arp
+//   plus = octave-up
+//   minus = cc:80        # any controller, 127 while held and 0 when let go
+//
+// The LED next to each button shows the state of whatever it's assigned to
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::config::Section;
+#[cfg(not(feature = "simulator"))]
+use crate::settings::Settings;
+use crate::user_interface::Button;
+
+// In the order the firmware numbers them
+pub const DIALS_BUTTONS: [&str; 3] = ["af", "plus", "minus"];
+
+// Only the dials driver sends the buttons' controllers, the simulator has no buttons
+#[cfg(not(feature = "simulator"))]
+const CC_SUSTAIN: u8 = 64;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ButtonAction {
+    OctaveUp,
+    OctaveDown,
+    TransposeUp,
+    TransposeDown,
+    Arp,
+    Sustain,
+    Controller(u8),
+    Ui(Button),
+}
+
+// Everything the menu cycles through. Other controllers can only be picked in the file
+const ACTIONS: [ButtonAction; 11] = [
+    ButtonAction::OctaveUp,
+    ButtonAction::OctaveDown,
+    ButtonAction::TransposeUp,
+    ButtonAction::TransposeDown,
+    ButtonAction::Arp,
+    ButtonAction::Sustain,
+    ButtonAction::Ui(Button::DpadUp),
+    ButtonAction::Ui(Button::DpadDown),
+    ButtonAction::Ui(Button::DpadLeft),
+    ButtonAction::Ui(Button::DpadRight),
+    ButtonAction::Ui(Button::DpadCenter),
+];
+
+impl ButtonAction {
+    pub fn defaults() -> [Self; 3] {
+        [
+            ButtonAction::Arp,
+            ButtonAction::OctaveUp,
+            ButtonAction::OctaveDown,
+        ]
+    }
+
+    pub fn name(self) -> String {
+        match self {
+            ButtonAction::OctaveUp => "octave-up".to_string(),
+            ButtonAction::OctaveDown => "octave-down".to_string(),
+            ButtonAction::TransposeUp => "transpose-up".to_string(),
+            ButtonAction::TransposeDown => "transpose-down".to_string(),
+            ButtonAction::Arp => "arp".to_string(),
+            ButtonAction::Sustain => "sustain".to_string(),
+            ButtonAction::Controller(cc) => format!("cc:{}", cc),
+            ButtonAction::Ui(Button::DpadUp) => "menu-up".to_string(),
+            ButtonAction::Ui(Button::DpadDown) => "menu-down".to_string(),
+            ButtonAction::Ui(Button::DpadLeft) => "menu-left".to_string(),
+            ButtonAction::Ui(Button::DpadRight) => "menu-right".to_string(),
+            ButtonAction::Ui(Button::DpadCenter) => "menu-select".to_string(),
+            ButtonAction::Ui(Button::A) => "menu-a".to_string(),
+            ButtonAction::Ui(Button::B) => "menu-b".to_string(),
+        }
+    }
+
+    fn from_name(name: &str) -> Result<Self> {
+        if let Some(cc) = name.strip_prefix("cc:") {
+            let cc: u8 = cc
+                .parse()
+                .map_err(|_| anyhow!("'{}' isn't a controller number", cc))?;
+            if cc > 119 {
+                bail!("controller {} is out of range", cc);
+            }
+            return Ok(ButtonAction::Controller(cc));
+        }
+
+        ACTIONS
+            .iter()
+            .chain(&[ButtonAction::Ui(Button::A), ButtonAction::Ui(Button::B)])
+            .find(|action| action.name() == name)
+            .copied()
+            .ok_or_else(|| anyhow!("unknown button action '{}'", name))
+    }
+
+    pub fn from_section(section: &Section) -> Result<[Self; 3]> {
+        let mut actions = Self::defaults();
+        for (action, button) in actions.iter_mut().zip(DIALS_BUTTONS) {
+            if let Some(name) = section.get(button) {
+                *action = Self::from_name(name).map_err(|e| anyhow!("[buttons] {}", e))?;
+            }
+        }
+
+        Ok(actions)
+    }
+
+    pub fn to_section(actions: &[Self; 3]) -> Section {
+        let mut section = Section::new("buttons");
+        for (action, button) in actions.iter().zip(DIALS_BUTTONS) {
+            section.set(button, action.name());
+        }
+
+        section
+    }
+
+    pub fn cycle(&mut self, step: i32) {
+        // A controller from the file steps into the list from the start
+        let current = ACTIONS.iter().position(|action| action == self);
+        let next = match current {
+            Some(i) => (i as i32 + step).rem_euclid(ACTIONS.len() as i32) as usize,
+            None => 0,
+        };
+
+        *self = ACTIONS[next];
+    }
+
+    // Controller number for the actions that send one while held
+    #[cfg(not(feature = "simulator"))]
+    pub fn controller(self) -> Option<u8> {
+        match self {
+            ButtonAction::Sustain => Some(CC_SUSTAIN),
+            ButtonAction::Controller(cc) => Some(cc),
+            _ => None,
+        }
+    }
+
+    // LED state for actions that toggle or shift something. None means the LED follows the button
+    #[cfg(not(feature = "simulator"))]
+    pub fn lit(self, settings: &Settings) -> Option<bool> {
+        match self {
+            ButtonAction::OctaveUp => Some(settings.octave() > 0),
+            ButtonAction::OctaveDown => Some(settings.octave() < 0),
+            ButtonAction::TransposeUp => Some(settings.semitones() > 0),
+            ButtonAction::TransposeDown => Some(settings.semitones() < 0),
+            ButtonAction::Arp => Some(settings.arpeggiator().enabled),
+            _ => None,
+        }
+    }
+}
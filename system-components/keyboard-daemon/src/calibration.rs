@@ -0,0 +1,200 @@
+// Per-key velocity calibration.
+// Every key's contacts are a little different, so the same strike doesn't produce the same travel time on every key.
+// While calibrating we record travel times for each key, then keep the range each key actually produces.
+// Velocity is then normalized against that key's own range instead of the curve's.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+use crate::config::{read_config, write_config, Section};
+use crate::velocity::{travel_time_ms, TravelWindow};
+
+pub const KEYS: usize = 49;
+
+const PROFILE_FILE: &str = "velocity-profile.conf";
+
+// A key needs a few presses before its range means anything
+const MIN_SAMPLES: usize = 5;
+
+// Ignore the most extreme presses, a single bounced contact shouldn't stretch the whole range
+const FAST_PERCENTILE: usize = 5;
+const SLOW_PERCENTILE: usize = 95;
+
+pub struct VelocityProfile {
+    keys: [Option<TravelWindow>; KEYS],
+}
+
+impl VelocityProfile {
+    pub fn empty() -> Self {
+        Self { keys: [None; KEYS] }
+    }
+
+    pub fn load(dir: &Path) -> Self {
+        let path = profile_path(dir);
+        if !path.exists() {
+            return Self::empty();
+        }
+
+        match read_config(&path).and_then(|sections| Self::from_section(&sections[0])) {
+            Ok(profile) => profile,
+            Err(e) => {
+                println!("Couldn't load velocity profile, ignoring it: {}", e);
+                Self::empty()
+            }
+        }
+    }
+
+    fn from_section(section: &Section) -> Result<Self> {
+        let mut profile = Self::empty();
+
+        for (key, window) in profile.keys.iter_mut().enumerate() {
+            let Some(value) = section.get(&format!("key_{}", key)) else {
+                continue;
+            };
+
+            let (min, max) = value
+                .split_once(',')
+                .and_then(|(min, max)| Some((min.trim().parse().ok()?, max.trim().parse().ok()?)))
+                .ok_or_else(|| anyhow!("key_{}: expected '<min ms>, <max ms>'", key))?;
+
+            // Non-finite times are rejected here too, they'd panic the driver on the key's next press
+            *window = Some(TravelWindow::new(min, max).map_err(|e| anyhow!("key_{}: {}", key, e))?);
+        }
+
+        Ok(profile)
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        write_config(profile_path(dir), &[self.to_section()])
+    }
+
+    fn to_section(&self) -> Section {
+        let mut section = Section::new("");
+        for (key, window) in self.keys.iter().enumerate() {
+            if let Some(window) = window {
+                section.set(
+                    format!("key_{}", key),
+                    format!("{}, {}", window.min, window.max),
+                );
+            }
+        }
+
+        section
+    }
+
+    pub fn key_window(&self, key: usize) -> Option<&TravelWindow> {
+        self.keys.get(key)?.as_ref()
+    }
+}
+
+// Collects travel times while calibration mode is running
+pub struct Calibration {
+    samples: Vec<Vec<u16>>,
+}
+
+impl Calibration {
+    pub fn new() -> Self {
+        Self {
+            samples: vec![vec![]; KEYS],
+        }
+    }
+
+    pub fn record(&mut self, key: usize, travel_time: u16) {
+        if let Some(samples) = self.samples.get_mut(key) {
+            samples.push(travel_time);
+        }
+    }
+
+    // How many keys have been pressed enough to be calibrated
+    pub fn keys_done(&self) -> usize {
+        self.samples
+            .iter()
+            .filter(|samples| samples.len() >= MIN_SAMPLES)
+            .count()
+    }
+
+    // Keys that weren't pressed enough keep whatever they had in the old profile
+    pub fn finish(mut self, old_profile: &VelocityProfile) -> VelocityProfile {
+        let mut profile = VelocityProfile::empty();
+
+        for (key, samples) in self.samples.iter_mut().enumerate() {
+            profile.keys[key] = old_profile.keys[key];
+
+            if samples.len() < MIN_SAMPLES {
+                continue;
+            }
+
+            samples.sort_unstable();
+            let fastest = travel_time_ms(samples[samples.len() * FAST_PERCENTILE / 100]);
+            let slowest = travel_time_ms(samples[samples.len() * SLOW_PERCENTILE / 100]);
+
+            // Every press took the same time, there's no range to normalize against
+            if let Ok(window) = TravelWindow::new(fastest, slowest) {
+                profile.keys[key] = Some(window);
+            }
+        }
+
+        profile
+    }
+}
+
+fn profile_path(dir: &Path) -> PathBuf {
+    dir.join(PROFILE_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+
+    fn profile(text: &str) -> Result<VelocityProfile> {
+        VelocityProfile::from_section(&parse_config(text)?[0])
+    }
+
+    #[test]
+    fn saved_profiles_load_back() {
+        let profile = profile("key_0 = 1.5, 30\nkey_48 = 2, 40.25\n").unwrap();
+
+        let window = profile.key_window(48).unwrap();
+        assert_eq!((window.min, window.max), (2.0, 40.25));
+        assert!(profile.key_window(1).is_none());
+        assert!(profile.key_window(KEYS).is_none());
+
+        let loaded = VelocityProfile::from_section(&profile.to_section()).unwrap();
+        let window = loaded.key_window(0).unwrap();
+        assert_eq!((window.min, window.max), (1.5, 30.0));
+    }
+
+    #[test]
+    fn bad_windows_are_rejected() {
+        for text in [
+            "key_0 = 1\n",
+            "key_0 = fast, slow\n",
+            "key_0 = 30, 1\n",
+            "key_0 = nan, 30\n",
+            "key_0 = 1, NaN\n",
+            "key_0 = 1, inf\n",
+            "key_0 = -inf, 30\n",
+        ] {
+            assert!(profile(text).is_err(), "{:?} was accepted", text);
+        }
+    }
+
+    #[test]
+    fn calibration_keeps_each_keys_range() {
+        let mut calibration = Calibration::new();
+        for travel_time in (10..=300).step_by(10) {
+            calibration.record(3, travel_time);
+        }
+        // Not enough presses, so the old window stays
+        calibration.record(4, 50);
+        let old = profile("key_4 = 1, 2\n").unwrap();
+
+        assert_eq!(calibration.keys_done(), 1);
+        let profile = calibration.finish(&old);
+        let window = profile.key_window(3).unwrap();
+        assert_eq!((window.min, window.max), (2.0, 29.0));
+        assert_eq!(profile.key_window(4).unwrap().max, 2.0);
+    }
+}
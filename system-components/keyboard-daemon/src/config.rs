@@ -0,0 +1,177 @@
+// A tiny ini-like config format, so settings can be edited by hand on the boot partition
+//
+//   # Comments start with a '#'
+//   top_level_key = value
+//
+//   [section name]
+//   key = value
+//
+// Keys before the first section header end up in a section with an empty name.
+// Sections may share a name, they're kept in the order they're written.
+
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+
+// /boot is the only partition shared between both rootfs slots, so anything kept here survives updates
+pub const CONFIG_DIR: &str = "/boot/keystation";
+
+pub struct Section {
+    pub name: String,
+    values: Vec<(String, String)>,
+}
+
+impl Section {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            values: vec![],
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn require(&self, key: &str) -> Result<&str> {
+        self.get(key)
+            .ok_or_else(|| anyhow!("[{}] is missing '{}'", self.name, key))
+    }
+
+    // Parse a value, or use the default if the key isn't there
+    pub fn parse_or<T: FromStr>(&self, key: &str, default: T) -> Result<T>
+    where
+        T::Err: Display,
+    {
+        match self.get(key) {
+            None => Ok(default),
+            Some(value) => value
+                .parse()
+                .map_err(|e| anyhow!("[{}] bad value for '{}': {}", self.name, key, e)),
+        }
+    }
+
+    // Like parse_or, for numbers that are used in arithmetic. "nan" and "inf" parse fine, then break
+    //   whatever they're used in
+    pub fn parse_finite_or(&self, key: &str, default: f32) -> Result<f32> {
+        let value = self.parse_or(key, default)?;
+        if !value.is_finite() {
+            bail!("[{}] '{}' must be a finite number", self.name, key);
+        }
+
+        Ok(value)
+    }
+
+    pub fn set(&mut self, key: impl Into<String>, value: impl ToString) {
+        self.values.push((key.into(), value.to_string()));
+    }
+}
+
+pub fn parse_config(contents: &str) -> Result<Vec<Section>> {
+    let mut sections = vec![Section::new("")];
+
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[') {
+            let name = name
+                .strip_suffix(']')
+                .ok_or_else(|| anyhow!("line {}: unclosed section header", line_number + 1))?;
+            sections.push(Section::new(name.trim()));
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("line {}: expected 'key = value'", line_number + 1))?;
+
+        sections.last_mut().unwrap().set(key.trim(), value.trim());
+    }
+
+    Ok(sections)
+}
+
+pub fn read_config(path: impl AsRef<Path>) -> Result<Vec<Section>> {
+    let path = path.as_ref();
+    let contents =
+        fs::read_to_string(path).map_err(|e| anyhow!("couldn't read {}: {}", path.display(), e))?;
+
+    parse_config(&contents).map_err(|e| anyhow!("{}: {}", path.display(), e))
+}
+
+pub fn write_config(path: impl AsRef<Path>, sections: &[Section]) -> Result<()> {
+    let mut contents = String::new();
+
+    for section in sections {
+        if !section.name.is_empty() {
+            contents.push_str(&format!("\n[{}]\n", section.name));
+        }
+        for (key, value) in &section.values {
+            contents.push_str(&format!("{} = {}\n", key, value));
+        }
+    }
+
+    let path = path.as_ref();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    // Write then rename so losing power halfway doesn't leave a half written file
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, contents.trim_start())?;
+    fs::rename(tmp_path, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sections_keep_their_order() {
+        let sections = parse_config(
+            "# top\ntop = 1\n\n[first]\nkey = a b  # not this\n[ second ]\n[first]\nkey=c\n",
+        )
+        .unwrap();
+
+        let names: Vec<&str> = sections.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["", "first", "second", "first"]);
+        assert_eq!(sections[0].get("top"), Some("1"));
+        assert_eq!(sections[1].get("key"), Some("a b"));
+        assert_eq!(sections[2].get("key"), None);
+        assert_eq!(sections[3].get("key"), Some("c"));
+    }
+
+    #[test]
+    fn bad_lines_are_errors() {
+        assert!(parse_config("[unclosed\n").is_err());
+        assert!(parse_config("just words\n").is_err());
+    }
+
+    #[test]
+    fn values_parse_or_default() {
+        let sections =
+            parse_config("[s]\nnumber = 5\nword = five\nnan = nan\ninf = -inf\n").unwrap();
+        let section = &sections[1];
+
+        assert_eq!(section.parse_or("number", 1u8).unwrap(), 5);
+        assert_eq!(section.parse_or("missing", 1u8).unwrap(), 1);
+        assert!(section.parse_or("word", 1u8).is_err());
+        assert!(section.require("missing").is_err());
+
+        assert_eq!(section.parse_finite_or("number", 1.0).unwrap(), 5.0);
+        assert_eq!(section.parse_finite_or("missing", 1.0).unwrap(), 1.0);
+        assert!(section.parse_finite_or("nan", 1.0).is_err());
+        assert!(section.parse_finite_or("inf", 1.0).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+// Bad readings from the keybed's contacts (see Message::Contacts), added up per key since the daemon
+//   started. A key that keeps giving them has a dirty or worn rubber contact, and is better found
+//   from the menu before a gig than by a note that won't play during one.
+// Only kept in memory, a key that's been cleaned shouldn't stay on the list
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+pub type SharedContacts = Arc<RwLock<Contacts>>;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ContactCounts {
+    // A down while B is up
+    pub impossible: u32,
+    // Presses and releases that bounced back
+    pub chatter: u32,
+}
+
+impl ContactCounts {
+    fn total(&self) -> u32 {
+        self.impossible + self.chatter
+    }
+}
+
+pub struct Contacts {
+    // By key, not slot
+    counts: BTreeMap<u8, ContactCounts>,
+}
+
+impl Contacts {
+    pub fn new() -> Self {
+        Self {
+            counts: BTreeMap::new(),
+        }
+    }
+
+    pub fn shared(self) -> SharedContacts {
+        Arc::new(RwLock::new(self))
+    }
+
+    // From the keyboard driver, the simulator's keys can't wear out
+    #[cfg(any(test, not(feature = "simulator")))]
+    pub fn record(&mut self, key: u8, impossible: u8, chatter: u8) {
+        let counts = self.counts.entry(key).or_default();
+        counts.impossible = counts.impossible.saturating_add(impossible as u32);
+        counts.chatter = counts.chatter.saturating_add(chatter as u32);
+    }
+
+    // Every key with bad readings, the worst first
+    pub fn suspects(&self) -> Vec<(u8, ContactCounts)> {
+        let mut suspects: Vec<(u8, ContactCounts)> = self
+            .counts
+            .iter()
+            .filter(|(_, counts)| counts.total() > 0)
+            .map(|(&key, &counts)| (key, counts))
+            .collect();
+        // Stable, so keys with the same total stay in order
+        suspects.sort_by_key(|(_, counts)| Reverse(counts.total()));
+
+        suspects
+    }
+
+    // After cleaning, to see whether it helped
+    pub fn clear(&mut self) {
+        self.counts.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_add_up_per_key() {
+        let mut contacts = Contacts::new();
+        contacts.record(10, 1, 0);
+        contacts.record(3, 0, 1);
+        contacts.record(10, 2, 4);
+        contacts.record(20, 0, 0);
+
+        assert_eq!(
+            contacts.suspects(),
+            vec![
+                (
+                    10,
+                    ContactCounts {
+                        impossible: 3,
+                        chatter: 4
+                    }
+                ),
+                (
+                    3,
+                    ContactCounts {
+                        impossible: 0,
+                        chatter: 1
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn clearing_forgets_everything() {
+        let mut contacts = Contacts::new();
+        contacts.record(10, 1, 0);
+        contacts.clear();
+
+        assert!(contacts.suspects().is_empty());
+    }
+}
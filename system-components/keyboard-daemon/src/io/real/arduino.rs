@@ -1,101 +1,295 @@
-// Both arduinos communicate in the same way
-// Each message starts with just a single character to indicate the type, followed by some payload.
-// The size of the payload is different for each message
-// The only built-in messages are
-//   - 'V': version, payload is a version string prefixed with its size
-//   - 'P': panic, produced by the panic handler
+// Both arduinos communicate in the same way, the messages and commands are all in arduino-protocol.
+// Version, panic, response and telemetry messages are handled here, everything else goes to the
+//   driver.
+// Panics are recorded (see panics.rs) as they happen, and again from the report the firmware sends
+//   after it boots, in case we weren't listening when it happened.
+// Bad frames and messages are counted and skipped, the driver carries on with the next good one
+//
+// The reading thread waits for command responses itself (see `request`), other threads can send
+//   commands with id 0 through a `Commander`, which the firmware doesn't respond to. Settings kept in
+//   the arduinos' EEPROM (see arduino-protocol's stored.rs) are only loaded and stored by the reading
+//   thread, the firmware can't read the serial port while it's writing the EEPROM
 
 use anyhow::{anyhow, Result};
+use arduino_protocol::{Command, Message, PanicReport, Request, Status, Telemetry};
 use rs_tty::TTY;
+use std::collections::VecDeque;
+use std::fmt::Display;
 use std::fs;
-use std::io::{stderr, stdout, BufRead, BufReader, Read, Write};
-use std::process::Command;
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use crate::io::io_impl::elf;
+use crate::io::io_impl::frame::{Frame, FrameReader};
+use crate::io::io_impl::stk500::{self, ATMEGA328P};
+use crate::panics::{Panic, SharedPanics};
+use crate::telemetry::{LoopTiming, SharedTelemetry};
 
 const WATCHDOG_MS: u64 = 500;
 
-pub struct Arduino<M, F: FnMut(&mut [u8; 3], &mut TTY) -> Result<M>> {
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(100);
+// Each byte of EEPROM takes a few ms to write, and a board storing its first setting writes them all
+const STORE_TIMEOUT: Duration = Duration::from_secs(1);
+// Opening the port resets the arduino, and the bootloader waits a while before starting the firmware
+const BOOT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Follows the serial device across reopens, so it stays usable after a panic or reflash
+type SerialWriter = Arc<Mutex<TTY>>;
+
+enum Response {
+    Ok(Vec<u8>),
+    Failed(Status),
+    TimedOut,
+}
+
+// Where an arduino is plugged in and the firmware it should be running. Each driver has its own, the
+//   tests point them at a fake arduino instead (see fake_arduino.rs)
+#[derive(Clone)]
+pub struct ArduinoPaths {
+    pub serial_device: PathBuf,
+    pub firmware_bin: PathBuf,
+    pub firmware_version: PathBuf,
+}
+
+impl ArduinoPaths {
+    pub fn new(
+        serial_device: impl Into<PathBuf>,
+        firmware_bin: impl Into<PathBuf>,
+        firmware_version: impl Into<PathBuf>,
+    ) -> Self {
+        Self {
+            serial_device: serial_device.into(),
+            firmware_bin: firmware_bin.into(),
+            firmware_version: firmware_version.into(),
+        }
+    }
+}
+
+// Sends commands from any thread, without waiting for a response
+#[derive(Clone)]
+pub struct Commander {
+    writer: SerialWriter,
+}
+
+impl Commander {
+    pub fn send(&self, command: Command) -> Result<()> {
+        write_request(&self.writer, Request { id: 0, command })
+    }
+}
+
+fn write_request(writer: &SerialWriter, request: Request) -> Result<()> {
+    let mut frame = vec![];
+    request.encode(&mut |byte| frame.push(byte));
+    writer.lock().unwrap().write_all(&frame)?;
+
+    Ok(())
+}
+
+pub struct Arduino<M, F: FnMut(Message<'_>) -> Result<M>> {
     firmware_header: String,
     expected_firmware_version: String,
-    firmware_bin_path: &'static str,
+    paths: ArduinoPaths,
 
-    serial_device_path: &'static str,
     serial_baud: u32,
     serial_device: TTY,
+    serial_writer: SerialWriter,
 
-    // Most messages are 3 bytes or smaller, the ones that are bigger
-    //      have a size that can be determined from the first 3 bytes
-    read_buffer: [u8; 3],
+    reader: FrameReader,
+    // Messages that arrived while waiting for a response, handled before reading any more
+    backlog: VecDeque<Frame>,
+    // Turns every message that isn't built-in into the driver's own
     read_message_fn: F,
+    errors: u64,
+    last_id: u8,
+
+    // Sent every time the firmware starts, it forgets them when it resets
+    parameters: Vec<(u8, u16)>,
+    needs_parameters: bool,
+    // Stored too, once, so the firmware starts with them before we're around to send them
+    parameters_stored: bool,
+
+    // Called whenever the arduino restarts under us, anything it was in the middle of is gone
+    reset_hook: Option<Box<dyn FnMut() -> Result<()> + Send>>,
+
+    // Which board this is in the panic log
+    panics: Option<(&'static str, SharedPanics)>,
+    // And in the telemetry
+    telemetry: Option<(&'static str, SharedTelemetry)>,
 }
 
-impl<M, F: FnMut(&mut [u8; 3], &mut TTY) -> Result<M>> Arduino<M, F> {
+impl<M, F: FnMut(Message<'_>) -> Result<M>> Arduino<M, F> {
     pub fn new(
-        firmware_bin_path: &'static str,
-        firmware_bin_version_path: &'static str,
+        paths: ArduinoPaths,
         firmware_header: &'static str,
-        serial_device_path: &'static str,
         serial_baud: u32,
         read_message_fn: F,
     ) -> Result<Self> {
-        let firmware_version = fs::read_to_string(firmware_bin_version_path)?;
-        let mut serial_device = TTY::open(serial_device_path, serial_baud)?;
+        let firmware_version = fs::read_to_string(&paths.firmware_version)?;
+        let mut serial_device = TTY::open(&paths.serial_device, serial_baud)?;
         serial_device.flush()?;
+        let serial_writer = Arc::new(Mutex::new(serial_device.try_clone()?));
 
-        Ok(Self {
+        let mut arduino = Self {
             firmware_header: firmware_header.to_string(),
             expected_firmware_version: format!("{}{}", firmware_header, firmware_version),
-            firmware_bin_path,
+            paths,
 
-            serial_device_path,
             serial_baud,
             serial_device,
+            serial_writer,
 
-            read_buffer: [0; 3],
+            reader: FrameReader::new(),
+            backlog: VecDeque::new(),
             read_message_fn,
-        })
+            errors: 0,
+            last_id: 0,
+
+            parameters: vec![],
+            needs_parameters: true,
+            parameters_stored: false,
+
+            reset_hook: None,
+
+            panics: None,
+            telemetry: None,
+        };
+
+        arduino.check_version()?;
+        Ok(arduino)
+    }
+
+    pub fn on_reset(mut self, hook: impl FnMut() -> Result<()> + Send + 'static) -> Self {
+        self.reset_hook = Some(Box::new(hook));
+        self
+    }
+
+    pub fn record_panics(mut self, board: &'static str, panics: SharedPanics) -> Self {
+        self.panics = Some((board, panics));
+        self
+    }
+
+    pub fn record_telemetry(mut self, board: &'static str, telemetry: SharedTelemetry) -> Self {
+        self.telemetry = Some((board, telemetry));
+        self
+    }
+
+    // A firmware parameter (see Command::Set) to set whenever it starts
+    pub fn parameter(mut self, param: u8, value: u16) -> Self {
+        self.parameters.push((param, value));
+        self
+    }
+
+    pub fn commander(&self) -> Commander {
+        Commander {
+            writer: self.serial_writer.clone(),
+        }
+    }
+
+    // A setting from the EEPROM, None if it isn't stored
+    pub fn load(&mut self, setting: u8) -> Result<Option<u16>> {
+        match self.request(Command::Load { setting }, RESPONSE_TIMEOUT)? {
+            Response::Ok(data) => match data[..] {
+                [] => Ok(None),
+                [high, low] => Ok(Some(u16::from_be_bytes([high, low]))),
+                _ => Err(anyhow!("Setting {} loaded as {:?}", setting, data)),
+            },
+            Response::Failed(status) => {
+                Err(anyhow!("Loading setting {} failed: {:?}", setting, status))
+            }
+            Response::TimedOut => Err(anyhow!("Loading setting {} timed out", setting)),
+        }
+    }
+
+    // `len` settings from `start` on, None unless every one of them is stored
+    pub fn load_all(&mut self, start: u8, len: usize) -> Result<Option<Vec<u16>>> {
+        (0..len as u8).map(|i| self.load(start + i)).collect()
+    }
+
+    pub fn store(&mut self, setting: u8, value: u16) -> Result<()> {
+        match self.request(Command::Store { setting, value }, STORE_TIMEOUT)? {
+            Response::Ok(_) => Ok(()),
+            Response::Failed(status) => {
+                Err(anyhow!("Storing setting {} failed: {:?}", setting, status))
+            }
+            Response::TimedOut => Err(anyhow!("Storing setting {} timed out", setting)),
+        }
+    }
+
+    pub fn store_all(&mut self, start: u8, values: &[u16]) -> Result<()> {
+        for (i, &value) in values.iter().enumerate() {
+            self.store(start + i as u8, value)?;
+        }
+
+        Ok(())
     }
 
     pub fn read_next_message(&mut self) -> Result<M> {
-        // Version/panic messages get handled here, and we'll keep reading until there's something to return
+        // Built-in messages get handled here, and we'll keep reading until there's something to return
         loop {
-            self.serial_device.read_exact(&mut self.read_buffer[0..1])?;
-            return match self.read_buffer[0] {
-                b'V' => {
-                    self.handle_version()?;
-                    continue;
-                }
-                b'P' => {
-                    self.handle_panic()?;
-                    continue;
-                }
-                _ => {
-                    let message = (self.read_message_fn)(&mut self.read_buffer, &mut self.serial_device);
-                    if let Err(e) = message {
-                        println!("Error reading message: {}", e);
-
-                        println!("Buffer: {:?}", self.read_buffer);
+            // Again if it restarted while they were being sent
+            while self.needs_parameters {
+                self.needs_parameters = false;
+                self.send_parameters()?;
+            }
 
-                        Err(e)
-                    } else {
-                        message
+            let frame = match self.backlog.pop_front() {
+                Some(frame) => frame,
+                None => match self.reader.read_frame(&mut self.serial_device)? {
+                    Some(frame) => frame,
+                    None => {
+                        self.count_error("Skipped a bad frame");
+                        continue;
                     }
+                },
+            };
+
+            let message = match Message::decode(frame.kind, &frame.payload) {
+                Ok(message) => message,
+                Err(e) => {
+                    self.count_error(format!("Bad message {:?}: {:?}", frame.payload, e));
+                    continue;
                 }
             };
+
+            match message {
+                Message::Version(version) => self.handle_boot(version)?,
+                Message::Panic(report) => self.handle_panic(report)?,
+                Message::LastPanic(report) => self.record_panic(&report),
+                Message::Telemetry(telemetry) => self.handle_telemetry(telemetry),
+                Message::Response { .. } => {
+                    self.count_error(format!("Unexpected response {:?}", message))
+                }
+                message => match (self.read_message_fn)(message) {
+                    Ok(message) => return Ok(message),
+                    Err(e) => self.count_error(format!(
+                        "Error reading message {:?} {:?}: {}",
+                        frame.kind as char, frame.payload, e
+                    )),
+                },
+            }
         }
     }
 
-    fn handle_panic(&mut self) -> Result<()> {
-        // Log the panic message and wait for the Arduino to recover
-
-        let mut panic_message_buf = vec![b'P'];
-
-        let mut buf_serial = BufReader::new(&mut self.serial_device);
-        buf_serial.read_until(b'\x04', &mut panic_message_buf)?;
+    fn count_error(&mut self, error: impl Display) {
+        self.errors += 1;
+        println!(
+            "{}: {} ({} errors so far)",
+            self.firmware_header.trim(),
+            error,
+            self.errors
+        );
+    }
 
-        let panic_message = String::from_utf8(panic_message_buf)?;
-        println!("{} PANIC: {}", self.firmware_header, panic_message);
+    fn handle_panic(&mut self, report: PanicReport) -> Result<()> {
+        // Log the panic message and wait for the Arduino to recover
+        println!(
+            "{} PANIC: {}:{}:{} {}",
+            self.firmware_header, report.file, report.line, report.column, report.message
+        );
+        self.record_panic(&report);
 
         println!("Waiting for watchdog to recover....");
         sleep(Duration::from_millis(WATCHDOG_MS));
@@ -104,68 +298,255 @@ impl<M, F: FnMut(&mut [u8; 3], &mut TTY) -> Result<M>> Arduino<M, F> {
         Ok(())
     }
 
-    fn handle_version(&mut self) -> Result<()> {
-        // If the firmware version isn't what we expect then flash the correct version
-        // Otherwise just print the version and move on
+    // Only panics we hadn't already heard about are recorded
+    fn record_panic(&self, report: &PanicReport) {
+        let Some((board, panics)) = &self.panics else {
+            return;
+        };
 
-        self.serial_device.read_exact(&mut self.read_buffer[1..2])?;
-        let str_len = self.read_buffer[1] as usize;
-
-        let mut str_buf = vec![0u8; str_len];
-        self.serial_device.read_exact(&mut str_buf)?;
+        let panic = Panic {
+            board: board.to_string(),
+            number: report.number,
+            location: format!("{}:{}:{}", report.file, report.line, report.column),
+            message: report.message.to_string(),
+        };
+        let location = panic.location.clone();
+        let mut panics = panics.write().unwrap();
+        match panics.record(panic) {
+            Ok(true) => println!(
+                "Recorded {} panic at {}, {} so far",
+                board,
+                location,
+                panics.count(board)
+            ),
+            Ok(false) => {}
+            Err(e) => println!("Couldn't save {} panic: {}", board, e),
+        }
+    }
 
-        let version = String::from_utf8(str_buf)?;
-        if version != self.expected_firmware_version {
+    // Reports arrive every second, they're only printed when the loop's getting near the watchdog
+    fn handle_telemetry(&self, telemetry: Telemetry) {
+        if telemetry.worst_loop_us > telemetry.watchdog_us / 2 {
             println!(
+                "{}: slow loop, {}us of the watchdog's {}us",
+                self.firmware_header.trim(),
+                telemetry.worst_loop_us,
+                telemetry.watchdog_us
+            );
+        }
+
+        let Some((board, shared)) = &self.telemetry else {
+            return;
+        };
+        shared.write().unwrap().record(
+            board,
+            LoopTiming {
+                scans_per_second: telemetry.scans_per_second(),
+                worst_loop_us: telemetry.worst_loop_us,
+                watchdog_margin_us: telemetry.watchdog_margin_us(),
+                tx_waits: telemetry.tx_waits,
+            },
+        );
+    }
+
+    // The firmware restarted without panicking, the watchdog probably caught it
+    fn handle_boot(&mut self, version: &str) -> Result<()> {
+        println!("{} restarted", version);
+        self.needs_parameters = true;
+
+        if let Some(hook) = &mut self.reset_hook {
+            hook()?;
+        }
+
+        Ok(())
+    }
+
+    // Send a command and wait for its response. Only for the thread reading messages, anything else
+    //   that arrives in the meantime is kept for read_next_message
+    fn request(&mut self, command: Command, timeout: Duration) -> Result<Response> {
+        // 0 is for commands that don't want a response
+        self.last_id = self.last_id.checked_add(1).unwrap_or(1);
+        let id = self.last_id;
+        write_request(&self.serial_writer, Request { id, command })?;
+
+        let deadline = Instant::now() + timeout;
+        let response = loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break Response::TimedOut;
+            }
+
+            self.serial_device.set_read_timeout(Some(remaining));
+            let frame = match self.reader.read_frame(&mut self.serial_device) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    self.count_error("Skipped a bad frame");
+                    continue;
+                }
+                Err(e) if e.kind() == ErrorKind::TimedOut => break Response::TimedOut,
+                Err(e) => {
+                    self.serial_device.set_read_timeout(None);
+                    return Err(e.into());
+                }
+            };
+
+            match Message::decode(frame.kind, &frame.payload) {
+                Ok(Message::Response {
+                    id: response_id,
+                    status,
+                    data,
+                }) if response_id == id => {
+                    break match status {
+                        Status::Ok => Response::Ok(data.to_vec()),
+                        status => Response::Failed(status),
+                    };
+                }
+                // Probably one we gave up waiting for
+                Ok(message @ Message::Response { .. }) => {
+                    self.count_error(format!("Late response {:?}", message))
+                }
+                // Settings are stored while the driver's running too, the firmware could restart
+                //   in the middle of that
+                Ok(Message::Version(version)) => self.handle_boot(version)?,
+                _ => self.backlog.push_back(frame),
+            }
+        };
+
+        self.serial_device.set_read_timeout(None);
+        Ok(response)
+    }
+
+    // Pings until the firmware answers. False if it never does
+    fn wait_for_boot(&mut self) -> Result<bool> {
+        let start = Instant::now();
+        while start.elapsed() < BOOT_TIMEOUT {
+            if !matches!(
+                self.request(Command::Ping, RESPONSE_TIMEOUT)?,
+                Response::TimedOut
+            ) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    // None if the firmware doesn't answer, old firmware won't understand the question
+    fn query_version(&mut self) -> Result<Option<String>> {
+        if !self.wait_for_boot()? {
+            return Ok(None);
+        }
+
+        Ok(match self.request(Command::Version, RESPONSE_TIMEOUT)? {
+            Response::Ok(version) => Some(String::from_utf8_lossy(&version).into_owned()),
+            Response::Failed(_) | Response::TimedOut => None,
+        })
+    }
+
+    fn check_version(&mut self) -> Result<()> {
+        // If the firmware version isn't what we expect then flash the correct version
+        // Otherwise just print the version and move on
+        match self.query_version()? {
+            Some(version) if version == self.expected_firmware_version => {
+                println!("{}", version);
+                return Ok(());
+            }
+            Some(version) => println!(
                 "Firmware version mismatch!\n '{}' != '{}'",
                 version, self.expected_firmware_version
-            );
+            ),
+            None => println!(
+                "{} didn't answer a version query",
+                self.firmware_header.trim()
+            ),
+        }
+
+        self.flash_firmware()?;
 
-            let exit_status = Command::new("avrdude")
-                .args([
-                    "-p",
-                    "atmega328p",
-                    "-c",
-                    "arduino",
-                    "-P",
-                    self.serial_device_path,
-                    "-b",
-                    format!("{}", self.serial_baud).as_ref(),
-                    "-e",
-                    "-D",
-                    "-U",
-                    format!("flash:w:{}:e", self.firmware_bin_path).as_ref(),
-                ])
-                .stdout(stdout())
-                .stderr(stderr())
-                .spawn()?
-                .wait()?;
-
-            if !exit_status.success() {
-                return Err(anyhow!(
-                    "Firmware flash failed. Process returned {}",
-                    exit_status.code().unwrap()
-                ));
+        println!("Firmware flash successful! Waiting for device to restart....");
+        sleep(Duration::from_secs_f32(0.1));
+
+        self.reopen_serial()?;
+
+        match self.query_version()? {
+            Some(version) if version == self.expected_firmware_version => {
+                println!("{}", version);
+                Ok(())
             }
+            version => Err(anyhow!(
+                "Firmware version is still wrong after flashing: {:?}",
+                version
+            )),
+        }
+    }
 
-            println!("Firmware flash successful! Waiting for device to restart....");
-            sleep(Duration::from_secs_f32(0.1));
+    fn flash_firmware(&mut self) -> Result<()> {
+        let image = elf::flash_image(&fs::read(&self.paths.firmware_bin)?)?;
+        println!(
+            "Flashing {} ({} bytes) to {}",
+            self.paths.firmware_bin.display(),
+            image.len(),
+            self.paths.serial_device.display()
+        );
 
-            self.reopen_serial()?;
-        } else {
-            println!("{}", version);
+        // Only every 10%, there's a few hundred pages
+        let mut last_printed = None;
+        stk500::flash(&mut self.serial_device, &ATMEGA328P, &image, |progress| {
+            let percent = progress.done * 100 / progress.total / 10 * 10;
+            if last_printed != Some((progress.stage, percent)) {
+                println!("{:?} {}%", progress.stage, percent);
+                last_printed = Some((progress.stage, percent));
+            }
+        })
+    }
+
+    fn send_parameters(&mut self) -> Result<()> {
+        if self.parameters.is_empty() {
+            return Ok(());
+        }
+
+        if !self.wait_for_boot()? {
+            self.count_error("Firmware didn't answer, parameters weren't set");
+            return Ok(());
+        }
+
+        for (param, value) in self.parameters.clone() {
+            match self.request(Command::Set { param, value }, RESPONSE_TIMEOUT)? {
+                Response::Ok(_) => {}
+                Response::Failed(status) => {
+                    self.count_error(format!("Setting parameter {} failed: {:?}", param, status))
+                }
+                Response::TimedOut => {
+                    self.count_error(format!("Setting parameter {} timed out", param))
+                }
+            }
+        }
+
+        if !self.parameters_stored {
+            self.parameters_stored = true;
+            for (param, value) in self.parameters.clone() {
+                if let Err(e) = self.store(param, value) {
+                    self.count_error(e);
+                }
+            }
         }
 
         Ok(())
     }
 
     fn reopen_serial(&mut self) -> Result<()> {
-        // TODO: Does this leak a file descriptor? It's probably okay if it does
-        std::mem::forget(std::mem::replace(
-            &mut self.serial_device,
-            TTY::open(self.serial_device_path, self.serial_baud)?,
-        ));
+        self.serial_device = TTY::open(&self.paths.serial_device, self.serial_baud)?;
         self.serial_device.flush()?;
+        self.reader.clear();
+        self.backlog.clear();
+        self.needs_parameters = true;
+
+        // Like the old port, the old clone is closed as it's replaced
+        *self.serial_writer.lock().unwrap() = self.serial_device.try_clone()?;
+
+        if let Some(hook) = &mut self.reset_hook {
+            hook()?;
+        }
 
         Ok(())
     }
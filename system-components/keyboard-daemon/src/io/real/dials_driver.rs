@@ -1,13 +1,28 @@
-use std::io::Read;
+use std::path::Path;
 use std::thread;
-use std::thread::JoinHandle;
+use std::thread::{sleep, JoinHandle};
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
+use arduino_protocol::command::{
+    PARAM_DEBOUNCE, PARAM_FADER_INTERVAL, PARAM_HYSTERESIS, PARAM_OVERSAMPLE, PARAM_PEDAL_POLARITY,
+    POLARITY_AUTO, POLARITY_NORMALLY_CLOSED, POLARITY_NORMALLY_OPEN,
+};
+use arduino_protocol::stored::STORED_FADER_CALIBRATION;
+use arduino_protocol::{Command, Message as ArduinoMessage};
 use crossbeam::channel::Sender;
+use midly::num::u7;
+use midly::{MidiMessage, PitchBend};
 
-use crate::io::io_impl::arduino::Arduino;
+use crate::button_actions::{ButtonAction, DIALS_BUTTONS};
+use crate::io::io_impl::arduino::{Arduino, ArduinoPaths, Commander};
+use crate::io::io_impl::faders::{self, FaderCalibrations};
 use crate::midi_sender::MidiEvent;
-use rs_tty::TTY;
+use crate::panics::SharedPanics;
+use crate::settings::{PedalPolarity, SharedSettings};
+use crate::telemetry::SharedTelemetry;
+use crate::user_interface::UIEvent;
+use crate::Threads;
 
 const SERIAL_DEVICE: &str = "/dev/ttyUSBdials";
 const SERIAL_BAUD: u32 = 115_200;
@@ -16,26 +31,30 @@ const FIRMWARE_BIN: &str = "/usr/share/dials.elf";
 const FIRMWARE_VERSION: &str = "/usr/share/dials-version.txt";
 const FIRMWARE_HEADER: &str = "I am dials! :3 ";
 
+const CC_MODULATION: u8 = 1;
+const CC_VOLUME: u8 = 7;
+const CC_SUSTAIN: u8 = 64;
+
+const LED_POLL: Duration = Duration::from_millis(50);
+const LED_REFRESH: Duration = Duration::from_secs(1);
+
 enum Message {
     Volume(u16),
     Pitch(u16),
     Modulation(u16),
+    // The firmware works out the pedal's polarity, this is always true for pressed
+    Pedal(bool),
+    // Button index and whether it's pressed
+    Button(u8, bool),
 }
 
-fn read_next_message(buffer: &mut [u8; 3], serial: &mut TTY) -> Result<Message> {
-    match buffer[0] {
-        b'F' => {
-            serial.read_exact(&mut buffer[1..3])?;
-            Ok(Message::Volume(u16::from_be_bytes([buffer[0], buffer[1]])))
-        }
-        b'G' => {
-            serial.read_exact(&mut buffer[1..3])?;
-            Ok(Message::Pitch(u16::from_be_bytes([buffer[0], buffer[1]])))
-        }
-        b'H' => {
-            serial.read_exact(&mut buffer[1..3])?;
-            Ok(Message::Modulation(u16::from_be_bytes([buffer[0], buffer[1]])))
-        }
+fn read_next_message(message: ArduinoMessage) -> Result<Message> {
+    match message {
+        ArduinoMessage::Volume(value) => Ok(Message::Volume(value)),
+        ArduinoMessage::Pitch(value) => Ok(Message::Pitch(value)),
+        ArduinoMessage::Modulation(value) => Ok(Message::Modulation(value)),
+        ArduinoMessage::Pedal(pressed) => Ok(Message::Pedal(pressed)),
+        ArduinoMessage::Button { button, pressed } => Ok(Message::Button(button, pressed)),
         _ => {
             // Who knows what we read
             Err(anyhow!("Unknown dials message..."))
@@ -43,27 +62,364 @@ fn read_next_message(buffer: &mut [u8; 3], serial: &mut TTY) -> Result<Message>
     }
 }
 
-pub fn start_dials_driver(midi_channel: Sender<MidiEvent>) -> Result<JoinHandle<Result<()>>> {
-    let mut arduino = Arduino::new(
-        FIRMWARE_BIN,
-        FIRMWARE_VERSION,
-        FIRMWARE_HEADER,
-        SERIAL_DEVICE,
-        SERIAL_BAUD,
-        read_next_message,
-    )?;
-
-    Ok(thread::spawn(move || loop {
-        match arduino.read_next_message()? {
-            Message::Volume(val) => {
-                println!("Volume: {}", val);
+pub fn dials_paths() -> ArduinoPaths {
+    ArduinoPaths::new(SERIAL_DEVICE, FIRMWARE_BIN, FIRMWARE_VERSION)
+}
+
+// The buttons' LEDs are driven from their own thread, reading the dials blocks
+pub fn start_dials_driver(
+    paths: ArduinoPaths,
+    midi_channel: Sender<MidiEvent>,
+    ui_channel: Sender<UIEvent>,
+    settings: SharedSettings,
+    panics: SharedPanics,
+    telemetry: SharedTelemetry,
+) -> Result<Threads> {
+    let (debounce, oversample, hysteresis, fader_interval, pedal_polarity) = {
+        let settings = settings.read().unwrap();
+        (
+            settings.dials_debounce_ms(),
+            settings.fader_oversample(),
+            settings.fader_hysteresis(),
+            settings.fader_interval_ms(),
+            match settings.pedal_polarity() {
+                PedalPolarity::Auto => POLARITY_AUTO,
+                PedalPolarity::NormallyOpen => POLARITY_NORMALLY_OPEN,
+                PedalPolarity::NormallyClosed => POLARITY_NORMALLY_CLOSED,
+            },
+        )
+    };
+    let mut arduino = Arduino::new(paths, FIRMWARE_HEADER, SERIAL_BAUD, read_next_message)?
+        .parameter(PARAM_DEBOUNCE, debounce)
+        .parameter(PARAM_OVERSAMPLE, oversample)
+        .parameter(PARAM_HYSTERESIS, hysteresis)
+        .parameter(PARAM_FADER_INTERVAL, fader_interval)
+        .parameter(PARAM_PEDAL_POLARITY, pedal_polarity)
+        .record_panics("dials", panics)
+        .record_telemetry("dials", telemetry)
+        .on_reset({
+            // A pedal held through the reset won't send its release
+            let midi_channel = midi_channel.clone();
+            let settings = settings.clone();
+            move || {
+                for channel in settings.read().unwrap().zone_channels() {
+                    midi_channel.try_send(MidiEvent::new(
+                        channel,
+                        controller(CC_SUSTAIN, on_off(false)),
+                    ))?;
+                }
+                Ok(())
+            }
+        });
+
+    let config_dir = settings.read().unwrap().config_dir().to_path_buf();
+    let calibrations = fader_calibrations(&mut arduino, &config_dir);
+    let commander = arduino.commander();
+
+    let led_thread = start_led_driver(arduino.commander(), settings.clone());
+    let dials_thread = thread::spawn(move || {
+        // ADC readings jitter, only send when the midi value actually changes
+        let mut last_volume = None;
+        let mut last_pitch = None;
+        let mut last_modulation = None;
+
+        loop {
+            let message = match arduino.read_next_message()? {
+                Message::Volume(val) => {
+                    let value = to_u7(calibrations.volume.unipolar(val));
+                    changed(&mut last_volume, value).then(|| controller(CC_VOLUME, value))
+                }
+                Message::Pitch(val) => {
+                    let bend = PitchBend::from_f32(calibrations.pitch.bipolar(val));
+                    changed(&mut last_pitch, bend).then_some(MidiMessage::PitchBend { bend })
+                }
+                Message::Modulation(val) => {
+                    let value = to_u7(calibrations.modulation.unipolar(val));
+                    changed(&mut last_modulation, value).then(|| controller(CC_MODULATION, value))
+                }
+                Message::Pedal(pressed) => Some(controller(CC_SUSTAIN, on_off(pressed))),
+                Message::Button(button, pressed) => {
+                    button_message(button, pressed, &settings, &ui_channel, &commander)?
+                }
+            };
+
+            // Every zone gets the same controllers, so a split still bends and modulates as one instrument
+            if let Some(message) = message {
+                for channel in settings.read().unwrap().zone_channels() {
+                    midi_channel.try_send(MidiEvent::new(channel, message))?;
+                }
+            }
+        }
+    });
+
+    Ok(vec![dials_thread, led_thread])
+}
+
+// Calibration made on this Pi is copied to the dials. A Pi without any, a freshly flashed image say,
+//   uses the dials' copy instead
+fn fader_calibrations(
+    arduino: &mut Arduino<Message, impl FnMut(ArduinoMessage<'_>) -> Result<Message>>,
+    dir: &Path,
+) -> FaderCalibrations {
+    if FaderCalibrations::is_saved(dir) {
+        let calibrations = FaderCalibrations::load(dir);
+        if let Err(e) = arduino.store_all(STORED_FADER_CALIBRATION, &calibrations.to_stored()) {
+            println!("Couldn't store fader calibration on the dials: {}", e);
+        }
+        return calibrations;
+    }
+
+    let stored = arduino
+        .load_all(STORED_FADER_CALIBRATION, faders::STORED_VALUES)
+        .and_then(|values| {
+            values
+                .map(|values| FaderCalibrations::from_stored(&values))
+                .transpose()
+        });
+    match stored {
+        Ok(Some(calibrations)) => {
+            println!("Using the fader calibration stored on the dials");
+            calibrations
+        }
+        Ok(None) => FaderCalibrations::load(dir),
+        Err(e) => {
+            println!("Couldn't load the dials' fader calibration: {}", e);
+            FaderCalibrations::load(dir)
+        }
+    }
+}
+
+// Controllers are returned to be sent like the faders, everything else happens here
+fn button_message(
+    button: u8,
+    pressed: bool,
+    settings: &SharedSettings,
+    ui_channel: &Sender<UIEvent>,
+    commander: &Commander,
+) -> Result<Option<MidiMessage>> {
+    let Some(action) = settings
+        .read()
+        .unwrap()
+        .buttons()
+        .get(button as usize)
+        .copied()
+    else {
+        return Ok(None);
+    };
+
+    if let Some(cc) = action.controller() {
+        set_led(commander, button, pressed)?;
+        return Ok(Some(controller(cc, on_off(pressed))));
+    }
+
+    match action {
+        ButtonAction::Ui(button_event) => {
+            set_led(commander, button, pressed)?;
+            ui_channel.try_send(if pressed {
+                UIEvent::Down(button_event)
+            } else {
+                UIEvent::Up(button_event)
+            })?;
+        }
+        _ if pressed => {
+            let mut settings = settings.write().unwrap();
+            match action {
+                ButtonAction::OctaveUp => settings.step_octave(1),
+                ButtonAction::OctaveDown => settings.step_octave(-1),
+                ButtonAction::TransposeUp => settings.step_semitones(1),
+                ButtonAction::TransposeDown => settings.step_semitones(-1),
+                ButtonAction::Arp => {
+                    let arpeggiator = settings.arpeggiator_mut();
+                    arpeggiator.enabled = !arpeggiator.enabled;
+                }
+                _ => {}
             }
-            Message::Pitch(val) => {
-                println!("Pitch: {}", val);
+
+            if let Err(e) = settings.save() {
+                println!("Couldn't save settings: {}", e);
             }
-            Message::Modulation(val) => {
-                println!("Modulation: {}", val);
+        }
+        _ => {}
+    }
+
+    Ok(None)
+}
+
+// LEDs for toggles and shifts follow the settings, however they were changed.
+// They're all re-sent every so often, in case the arduino reset and forgot them
+fn start_led_driver(commander: Commander, settings: SharedSettings) -> JoinHandle<Result<()>> {
+    thread::spawn(move || {
+        let mut last = [None; DIALS_BUTTONS.len()];
+        let mut last_refresh = Instant::now();
+
+        loop {
+            let lit = {
+                let settings = settings.read().unwrap();
+                settings.buttons().map(|action| action.lit(&settings))
+            };
+
+            let refresh = last_refresh.elapsed() >= LED_REFRESH;
+            if refresh {
+                last_refresh = Instant::now();
             }
+
+            for (button, (lit, last)) in lit.iter().zip(last.iter_mut()).enumerate() {
+                if let Some(on) = lit {
+                    if refresh || last != lit {
+                        set_led(&commander, button as u8, *on)?;
+                    }
+                }
+                *last = *lit;
+            }
+
+            sleep(LED_POLL);
         }
-    }))
+    })
+}
+
+fn set_led(commander: &Commander, led: u8, on: bool) -> Result<()> {
+    commander.send(Command::Led { led, on })
+}
+
+fn on_off(on: bool) -> u7 {
+    if on {
+        u7::max_value()
+    } else {
+        u7::default()
+    }
+}
+
+fn to_u7(position: f32) -> u7 {
+    u7::new((position * u7::max_value().as_int() as f32).round() as u8)
+}
+
+fn controller(controller: u8, value: u7) -> MidiMessage {
+    MidiMessage::Controller {
+        controller: u7::new(controller),
+        value,
+    }
+}
+
+fn changed<T: PartialEq>(last: &mut Option<T>, value: T) -> bool {
+    if last.as_ref() == Some(&value) {
+        return false;
+    }
+
+    *last = Some(value);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::io_impl::fake_arduino::{wait_for, FakeArduino};
+    use crate::panics::Panics;
+    use crate::settings::Settings;
+    use crate::telemetry::Telemetry;
+    use crossbeam::channel::{unbounded, Receiver};
+
+    const CC_BUTTON: u8 = 80;
+    const PARAMETERS: [u8; 5] = [
+        PARAM_DEBOUNCE,
+        PARAM_OVERSAMPLE,
+        PARAM_HYSTERESIS,
+        PARAM_FADER_INTERVAL,
+        PARAM_PEDAL_POLARITY,
+    ];
+
+    fn start() -> (FakeArduino, Receiver<MidiEvent>, SharedSettings) {
+        start_with(FakeArduino::start(FIRMWARE_HEADER, "test"))
+    }
+
+    fn start_with(mut arduino: FakeArduino) -> (FakeArduino, Receiver<MidiEvent>, SharedSettings) {
+        let (midi_sender, midi) = unbounded();
+        let (ui_sender, _) = unbounded();
+        let settings = Settings::load_from(&arduino.dir).shared();
+        // So pressing it doesn't save the settings
+        settings.write().unwrap().buttons_mut()[0] = ButtonAction::Controller(CC_BUTTON);
+
+        arduino.start_driver(&PARAMETERS, |arduino| {
+            start_dials_driver(
+                arduino.paths.clone(),
+                midi_sender,
+                ui_sender,
+                settings.clone(),
+                Panics::load_from(arduino.dir.join("panics.txt")).shared(),
+                Telemetry::new().shared(),
+            )
+        });
+
+        (arduino, midi, settings)
+    }
+
+    fn next_controller(midi: &Receiver<MidiEvent>) -> (u8, u8) {
+        match wait_for(midi, |_| true) {
+            MidiEvent::Channel {
+                message: MidiMessage::Controller { controller, value },
+                ..
+            } => (controller.as_int(), value.as_int()),
+            event => panic!("Expected a controller, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn faders_only_send_changes() {
+        let (arduino, midi, _) = start();
+
+        arduino.send(ArduinoMessage::Volume(0));
+        // Jitter that doesn't change the midi value
+        arduino.send(ArduinoMessage::Volume(1));
+        arduino.send(ArduinoMessage::Volume(1023));
+        arduino.send(ArduinoMessage::Modulation(1023));
+
+        assert_eq!(next_controller(&midi), (CC_VOLUME, 0));
+        assert_eq!(next_controller(&midi), (CC_VOLUME, 127));
+        assert_eq!(next_controller(&midi), (CC_MODULATION, 127));
+    }
+
+    #[test]
+    fn buttons_send_controllers_and_light_up() {
+        let (arduino, midi, _) = start();
+
+        arduino.send(ArduinoMessage::Button {
+            button: 0,
+            pressed: true,
+        });
+
+        assert_eq!(next_controller(&midi), (CC_BUTTON, 127));
+        wait_for(arduino.commands(), |command| {
+            *command == Command::Led { led: 0, on: true }
+        });
+    }
+
+    #[test]
+    fn restarting_releases_the_pedal() {
+        let (arduino, midi, _) = start();
+
+        arduino.send(ArduinoMessage::Pedal(true));
+        assert_eq!(next_controller(&midi), (CC_SUSTAIN, 127));
+
+        // A message cut short by the reset
+        arduino.send_bytes(b"\xA5\x02Sjunk");
+        arduino.boot();
+        assert_eq!(next_controller(&midi), (CC_SUSTAIN, 0));
+        arduino.expect_parameters(&PARAMETERS);
+    }
+
+    #[test]
+    fn the_dials_fader_calibration_is_used_without_one_on_the_pi() {
+        let arduino = FakeArduino::start(FIRMWARE_HEADER, "test");
+        // Volume only reaches 200 to 800, the others are the defaults
+        let values = [200, 800, 500, 4, 0, 1023, 511, 16, 0, 1023, 511, 4];
+        for (i, value) in values.into_iter().enumerate() {
+            arduino.store(STORED_FADER_CALIBRATION + i as u8, value);
+        }
+        let (arduino, midi, _) = start_with(arduino);
+
+        arduino.send(ArduinoMessage::Volume(204));
+        arduino.send(ArduinoMessage::Volume(796));
+
+        assert_eq!(next_controller(&midi), (CC_VOLUME, 0));
+        assert_eq!(next_controller(&midi), (CC_VOLUME, 127));
+    }
 }
@@ -0,0 +1,136 @@
+// Just enough of ELF to get the flash image out of the firmware. Every loadable segment is placed
+//   at its physical address, which is where it lives in flash (.data's too, it's copied to RAM at
+//   startup). AVR data space addresses start at DATA_SPACE, anything there isn't flash.
+
+use anyhow::{anyhow, Result};
+
+const EM_AVR: u16 = 83;
+const PT_LOAD: u32 = 1;
+const DATA_SPACE: u32 = 0x80_0000;
+
+// Unprogrammed flash, so gaps between segments are left alone
+const ERASED: u8 = 0xFF;
+
+pub fn flash_image(elf: &[u8]) -> Result<Vec<u8>> {
+    if elf.get(..4) != Some(b"\x7fELF") {
+        return Err(anyhow!("Not an ELF file"));
+    }
+    // 32 bit, little endian
+    if elf.get(4..6) != Some(&[1, 1]) {
+        return Err(anyhow!("Not a 32 bit little endian ELF file"));
+    }
+    if read_u16(elf, 18)? != EM_AVR {
+        return Err(anyhow!("Not an AVR ELF file"));
+    }
+
+    let ph_offset = read_u32(elf, 28)? as usize;
+    let ph_size = read_u16(elf, 42)? as usize;
+    let ph_count = read_u16(elf, 44)? as usize;
+
+    let mut image = vec![];
+    for i in 0..ph_count {
+        let header = ph_offset + i * ph_size;
+        let kind = read_u32(elf, header)?;
+        let offset = read_u32(elf, header + 4)? as usize;
+        let address = read_u32(elf, header + 12)?;
+        let size = read_u32(elf, header + 16)? as usize;
+
+        if kind != PT_LOAD || size == 0 || address >= DATA_SPACE {
+            continue;
+        }
+
+        let data = elf
+            .get(offset..offset + size)
+            .ok_or_else(|| anyhow!("Segment {} is past the end of the file", i))?;
+        let start = address as usize;
+        if image.len() < start + size {
+            image.resize(start + size, ERASED);
+        }
+        image[start..start + size].copy_from_slice(data);
+    }
+
+    if image.is_empty() {
+        return Err(anyhow!("Nothing to flash"));
+    }
+
+    Ok(image)
+}
+
+fn read_u16(elf: &[u8], at: usize) -> Result<u16> {
+    let bytes = elf
+        .get(at..at + 2)
+        .ok_or_else(|| anyhow!("ELF file is cut short"))?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(elf: &[u8], at: usize) -> Result<u32> {
+    let bytes = elf
+        .get(at..at + 4)
+        .ok_or_else(|| anyhow!("ELF file is cut short"))?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER_SIZE: usize = 52;
+    const PH_SIZE: usize = 32;
+
+    // (physical address, virtual address, contents) for each loadable segment
+    fn build_elf(segments: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let mut elf = vec![0; HEADER_SIZE];
+        elf[..6].copy_from_slice(b"\x7fELF\x01\x01");
+        elf[18..20].copy_from_slice(&EM_AVR.to_le_bytes());
+        elf[28..32].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        elf[42..44].copy_from_slice(&(PH_SIZE as u16).to_le_bytes());
+        elf[44..46].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+        let mut offset = HEADER_SIZE + segments.len() * PH_SIZE;
+        for (paddr, vaddr, data) in segments {
+            let mut header = vec![0; PH_SIZE];
+            header[0..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            header[4..8].copy_from_slice(&(offset as u32).to_le_bytes());
+            header[8..12].copy_from_slice(&vaddr.to_le_bytes());
+            header[12..16].copy_from_slice(&paddr.to_le_bytes());
+            header[16..20].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            header[20..24].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            elf.extend(header);
+            offset += data.len();
+        }
+        for (_, _, data) in segments {
+            elf.extend(*data);
+        }
+
+        elf
+    }
+
+    #[test]
+    fn segments_go_at_their_physical_address() {
+        let elf = build_elf(&[(0, 0, &[1, 2, 3, 4]), (6, 0x80_0100, &[5, 6])]);
+
+        assert_eq!(
+            flash_image(&elf).unwrap(),
+            [1, 2, 3, 4, ERASED, ERASED, 5, 6]
+        );
+    }
+
+    #[test]
+    fn ram_only_segments_are_skipped() {
+        let elf = build_elf(&[(0, 0, &[1, 2]), (0x80_0100, 0x80_0100, &[9, 9])]);
+
+        assert_eq!(flash_image(&elf).unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(flash_image(b"#!/bin/sh").is_err());
+
+        let mut elf = build_elf(&[(0, 0, &[1])]);
+        elf[18] = 40; // ARM
+        assert!(flash_image(&elf).is_err());
+
+        let elf = build_elf(&[(0, 0, &[1, 2, 3])]);
+        assert!(flash_image(&elf[..elf.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+// Every fader's pot is a little different, and none of them reach the full range of the ADC.
+// Calibration for each one is read from a file on the boot partition:
+//
+//   [pitch]
+//   min = 0           # raw reading at the bottom of travel
+//   max = 1023        # raw reading at the top of travel
+//   center = 512      # raw reading where a spring loaded fader rests
+//   deadzone = 16     # readings this close to the center (or ends, for the others) are snapped to it
+//
+// Sections are [volume], [pitch] and [modulation]. Any that are missing use the defaults
+//
+// The dials keep a copy in their EEPROM (see STORED_FADER_CALIBRATION), for a Pi without the file
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+
+use crate::config::{read_config, Section};
+
+const CALIBRATION_FILE: &str = "faders.conf";
+
+// Min, max, center and deadzone for each fader, the way the dials store them
+pub const STORED_VALUES: usize = 12;
+
+// The arduino's ADC is 10 bits
+const ADC_MAX: u16 = 1023;
+
+pub struct FaderCalibration {
+    min: u16,
+    max: u16,
+    center: u16,
+    deadzone: u16,
+}
+
+impl FaderCalibration {
+    fn default(deadzone: u16) -> Self {
+        Self {
+            min: 0,
+            max: ADC_MAX,
+            center: ADC_MAX / 2,
+            deadzone,
+        }
+    }
+
+    fn from_section(section: &Section, default: Self) -> Result<Self> {
+        let calibration = Self {
+            min: section.parse_or("min", default.min)?,
+            max: section.parse_or("max", default.max)?,
+            center: section.parse_or("center", default.center)?,
+            deadzone: section.parse_or("deadzone", default.deadzone)?,
+        };
+
+        calibration.check(&section.name)?;
+        Ok(calibration)
+    }
+
+    fn from_stored(name: &str, values: &[u16]) -> Result<Self> {
+        let &[min, max, center, deadzone] = values else {
+            bail!("[{}] needs 4 values, not {}", name, values.len());
+        };
+
+        let calibration = Self {
+            min,
+            max,
+            center,
+            deadzone,
+        };
+        calibration.check(name)?;
+        Ok(calibration)
+    }
+
+    fn check(&self, name: &str) -> Result<()> {
+        if self.min >= self.max || self.max > ADC_MAX {
+            bail!("[{}] must have min < max <= {}", name, ADC_MAX);
+        }
+        if self.deadzone >= (self.max - self.min) / 4 {
+            bail!("[{}] deadzone is too large", name);
+        }
+        if self.center <= self.min + self.deadzone || self.center + self.deadzone >= self.max {
+            bail!("[{}] center must be between min and max", name);
+        }
+
+        Ok(())
+    }
+
+    // Fader position in [0-1]. The deadzone at either end makes sure it can actually reach 0 and 1
+    pub fn unipolar(&self, raw: u16) -> f32 {
+        let low = self.min + self.deadzone;
+        let high = self.max - self.deadzone;
+
+        (raw.clamp(low, high) - low) as f32 / (high - low) as f32
+    }
+
+    // Fader position in [-1, 1]. Anything inside the deadzone is exactly 0, so a spring loaded fader
+    //   always comes back to rest in the same place
+    pub fn bipolar(&self, raw: u16) -> f32 {
+        let raw = raw.clamp(self.min, self.max);
+
+        if raw > self.center + self.deadzone {
+            let low = self.center + self.deadzone;
+            (raw - low) as f32 / (self.max - low) as f32
+        } else if raw + self.deadzone < self.center {
+            let high = self.center - self.deadzone;
+            -((high - raw) as f32 / (high - self.min) as f32)
+        } else {
+            0.0
+        }
+    }
+}
+
+pub struct FaderCalibrations {
+    pub volume: FaderCalibration,
+    pub pitch: FaderCalibration,
+    pub modulation: FaderCalibration,
+}
+
+impl FaderCalibrations {
+    fn default() -> Self {
+        Self {
+            volume: FaderCalibration::default(4),
+            pitch: FaderCalibration::default(16),
+            modulation: FaderCalibration::default(4),
+        }
+    }
+
+    // Only the file on the Pi, the dials' copy is left to the driver
+    pub fn is_saved(dir: &Path) -> bool {
+        calibration_path(dir).exists()
+    }
+
+    pub fn load(dir: &Path) -> Self {
+        let path = calibration_path(dir);
+        if !path.exists() {
+            return Self::default();
+        }
+
+        match Self::read(&path) {
+            Ok(calibrations) => calibrations,
+            Err(e) => {
+                println!("Couldn't load fader calibration, using defaults: {}", e);
+                Self::default()
+            }
+        }
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        let mut calibrations = Self::default();
+
+        for section in read_config(path)? {
+            match section.name.as_str() {
+                "" => {}
+                "volume" => {
+                    calibrations.volume =
+                        FaderCalibration::from_section(&section, calibrations.volume)?
+                }
+                "pitch" => {
+                    calibrations.pitch =
+                        FaderCalibration::from_section(&section, calibrations.pitch)?
+                }
+                "modulation" => {
+                    calibrations.modulation =
+                        FaderCalibration::from_section(&section, calibrations.modulation)?
+                }
+                other => bail!("unknown fader [{}]", other),
+            }
+        }
+
+        Ok(calibrations)
+    }
+
+    // In the order they're stored, volume, pitch then modulation
+    pub fn from_stored(values: &[u16]) -> Result<Self> {
+        if values.len() != STORED_VALUES {
+            bail!(
+                "{} fader calibration values, not {}",
+                values.len(),
+                STORED_VALUES
+            );
+        }
+
+        Ok(Self {
+            volume: FaderCalibration::from_stored("volume", &values[0..4])?,
+            pitch: FaderCalibration::from_stored("pitch", &values[4..8])?,
+            modulation: FaderCalibration::from_stored("modulation", &values[8..12])?,
+        })
+    }
+
+    pub fn to_stored(&self) -> Vec<u16> {
+        [&self.volume, &self.pitch, &self.modulation]
+            .into_iter()
+            .flat_map(|fader| [fader.min, fader.max, fader.center, fader.deadzone])
+            .collect()
+    }
+}
+
+fn calibration_path(dir: &Path) -> PathBuf {
+    dir.join(CALIBRATION_FILE)
+}
@@ -0,0 +1,307 @@
+// A scripted arduino on a pseudo-terminal, so the drivers can be tested against real serial IO
+//   without a board. It answers commands the way the firmware does, and sends whatever the test tells
+//   it to. Commands besides pings and version queries are passed on to the test to check.
+// Stored settings are kept in a map instead of an EEPROM, the layout's tested in arduino-protocol.
+// Dropping it hangs up the port, which stops any driver threads it's been given
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use arduino_protocol::frame::FrameDecoder;
+use arduino_protocol::stored::UNSET;
+use arduino_protocol::{Command, DecodeError, Message, Request, Status};
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use nix::pty::openpty;
+
+use crate::io::io_impl::arduino::ArduinoPaths;
+use crate::Threads;
+
+// Long enough for anything the daemon sends
+const MAX_COMMAND_LEN: usize = 8;
+
+// Long enough to get through a panic, the daemon waits for the watchdog
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+// Taken when the fake hangs up, the port only closes once every copy of it has
+type Writer = Arc<Mutex<Option<File>>>;
+type Stored = Arc<Mutex<HashMap<u8, u16>>>;
+
+pub struct FakeArduino {
+    pub paths: ArduinoPaths,
+    // Holds the firmware files, and anything else the test wants on disk. Removed when it's dropped
+    pub dir: PathBuf,
+    firmware_version: String,
+    writer: Writer,
+    commands: Receiver<Command>,
+    stored: Stored,
+    boot_on_store: Arc<AtomicBool>,
+    hung_up: Arc<AtomicBool>,
+    answer_thread: Option<JoinHandle<()>>,
+    driver_threads: Threads,
+    // Kept open so the daemon can reopen the port, like it does after a panic
+    slave: OwnedFd,
+}
+
+impl FakeArduino {
+    // Runs firmware `version`, which is the one the daemon expects too
+    pub fn start(header: &str, version: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "fake-arduino-{}-{}",
+            process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("version.txt"), version).unwrap();
+
+        let pty = openpty(None, None).unwrap();
+        let paths = ArduinoPaths::new(
+            format!("/proc/self/fd/{}", pty.slave.as_raw_fd()),
+            dir.join("firmware.elf"),
+            dir.join("version.txt"),
+        );
+
+        let firmware_version = format!("{}{}", header, version);
+        let port = File::from(pty.master);
+        let writer = Arc::new(Mutex::new(Some(port.try_clone().unwrap())));
+        let (command_sender, commands) = unbounded();
+        let stored = Stored::default();
+        let boot_on_store = Arc::new(AtomicBool::new(false));
+        let hung_up = Arc::new(AtomicBool::new(false));
+        let answer_thread = thread::spawn({
+            let writer = writer.clone();
+            let firmware_version = firmware_version.clone();
+            let stored = stored.clone();
+            let boot_on_store = boot_on_store.clone();
+            let hung_up = hung_up.clone();
+            move || {
+                answer_commands(
+                    port,
+                    writer,
+                    firmware_version,
+                    stored,
+                    boot_on_store,
+                    hung_up,
+                    command_sender,
+                )
+            }
+        });
+
+        Self {
+            paths,
+            dir,
+            firmware_version,
+            writer,
+            commands,
+            stored,
+            boot_on_store,
+            hung_up,
+            answer_thread: Some(answer_thread),
+            driver_threads: vec![],
+            slave: pty.slave,
+        }
+    }
+
+    pub fn send(&self, message: Message) {
+        let mut frame = vec![];
+        message.encode(&mut |byte| frame.push(byte));
+        self.send_bytes(&frame);
+    }
+
+    // Anything at all, garbage and half frames included
+    pub fn send_bytes(&self, bytes: &[u8]) {
+        let mut writer = self.writer.lock().unwrap();
+        writer.as_mut().unwrap().write_all(bytes).unwrap();
+    }
+
+    // What the firmware says when it starts, after the watchdog resets it say
+    pub fn boot(&self) {
+        self.send(Message::Version(&self.firmware_version));
+    }
+
+    // As if it had been stored before the daemon started
+    pub fn store(&self, setting: u8, value: u16) {
+        self.stored.lock().unwrap().insert(setting, value);
+    }
+
+    // Restarts before answering the next store, like a reset while the EEPROM's being written
+    pub fn boot_on_next_store(&self) {
+        self.boot_on_store.store(true, Ordering::Relaxed);
+    }
+
+    pub fn stored(&self, setting: u8) -> Option<u16> {
+        self.stored.lock().unwrap().get(&setting).copied()
+    }
+
+    // Every command besides pings and version queries, in the order they arrived
+    pub fn commands(&self) -> &Receiver<Command> {
+        &self.commands
+    }
+
+    // Starts a driver on the fake's port and waits for it to set `params`. Its threads are joined
+    //   once the port's hung up, so a test doesn't leave them running
+    pub fn start_driver(&mut self, params: &[u8], start: impl FnOnce(&Self) -> Result<Threads>) {
+        let threads = start(self).unwrap();
+        self.driver_threads.extend(threads);
+        self.expect_parameters(params);
+    }
+
+    // Set before anything else is read, and again whenever the firmware restarts
+    pub fn expect_parameters(&self, params: &[u8]) {
+        for &param in params {
+            wait_for(
+                &self.commands,
+                |command| matches!(command, Command::Set { param: set, .. } if *set == param),
+            );
+        }
+    }
+
+    fn hang_up(&mut self) {
+        self.hung_up.store(true, Ordering::Relaxed);
+        self.writer.lock().unwrap().take();
+        // Wakes the answering thread so it sees it's time to close its copy
+        let _ = nix::unistd::write(&self.slave, &[0]);
+        if let Some(thread) = self.answer_thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for FakeArduino {
+    fn drop(&mut self) {
+        self.hang_up();
+        let _ = fs::remove_dir_all(&self.dir);
+
+        // A test that failed has said why already, waiting on its threads could only hide that
+        if thread::panicking() {
+            return;
+        }
+
+        // They stop with an error once they next read or write the port
+        let deadline = Instant::now() + TIMEOUT;
+        for thread in self.driver_threads.drain(..) {
+            while !thread.is_finished() {
+                assert!(Instant::now() < deadline, "A driver thread didn't stop");
+                sleep(Duration::from_millis(10));
+            }
+            let _ = thread.join().expect("A driver thread panicked");
+        }
+    }
+}
+
+// Stops once the fake hangs up, or there's nobody left to pass commands on to
+fn answer_commands(
+    mut port: File,
+    writer: Writer,
+    firmware_version: String,
+    stored: Stored,
+    boot_on_store: Arc<AtomicBool>,
+    hung_up: Arc<AtomicBool>,
+    commands: Sender<Command>,
+) {
+    let mut decoder = FrameDecoder::<MAX_COMMAND_LEN>::new();
+    let mut byte = [0];
+
+    while port.read_exact(&mut byte).is_ok() {
+        if hung_up.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let Some((kind, payload)) = decoder.push(byte[0]) else {
+            continue;
+        };
+
+        let (id, command) = Request::decode(kind, payload);
+        let (status, data) = match command {
+            Ok(Command::Ping) => (Status::Ok, vec![]),
+            Ok(Command::Version) => (Status::Ok, firmware_version.as_bytes().to_vec()),
+            Ok(command) => {
+                if commands.send(command).is_err() {
+                    return;
+                }
+
+                if matches!(command, Command::Store { .. })
+                    && boot_on_store.swap(false, Ordering::Relaxed)
+                {
+                    let mut frame = vec![];
+                    Message::Version(&firmware_version).encode(&mut |byte| frame.push(byte));
+                    if !write_frame(&writer, &frame) {
+                        return;
+                    }
+                }
+
+                let mut stored = stored.lock().unwrap();
+                match command {
+                    Command::Load { setting } => {
+                        let value = stored.get(&setting).map(|value| value.to_be_bytes());
+                        (Status::Ok, value.map_or(vec![], |value| value.to_vec()))
+                    }
+                    Command::Store {
+                        setting,
+                        value: UNSET,
+                    } => {
+                        stored.remove(&setting);
+                        (Status::Ok, vec![])
+                    }
+                    Command::Store { setting, value } => {
+                        stored.insert(setting, value);
+                        (Status::Ok, vec![])
+                    }
+                    _ => (Status::Ok, vec![]),
+                }
+            }
+            Err(DecodeError::UnknownKind(_)) => (Status::UnknownCommand, vec![]),
+            Err(DecodeError::BadPayload(_)) => (Status::BadArgument, vec![]),
+        };
+
+        // Like the firmware, nothing's sent back for commands that don't want a response
+        if id == 0 {
+            continue;
+        }
+
+        let mut frame = vec![];
+        Message::Response {
+            id,
+            status,
+            data: &data,
+        }
+        .encode(&mut |byte| frame.push(byte));
+        if !write_frame(&writer, &frame) {
+            return;
+        }
+    }
+}
+
+// False once the fake's hung up
+fn write_frame(writer: &Writer, frame: &[u8]) -> bool {
+    match writer.lock().unwrap().as_mut() {
+        Some(port) => port.write_all(frame).is_ok(),
+        None => false,
+    }
+}
+
+// The first thing from `receiver` that matches, skipping anything else that arrives before it
+pub fn wait_for<T: Debug>(receiver: &Receiver<T>, matches: impl Fn(&T) -> bool) -> T {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut skipped = vec![];
+
+    loop {
+        match receiver.recv_deadline(deadline) {
+            Ok(item) if matches(&item) => return item,
+            Ok(item) => skipped.push(item),
+            Err(RecvTimeoutError::Timeout) => panic!("Timed out, skipped {:?}", skipped),
+            Err(RecvTimeoutError::Disconnected) => panic!("Disconnected, skipped {:?}", skipped),
+        }
+    }
+}
@@ -0,0 +1,183 @@
+// Frames (see arduino-protocol's frame.rs) read off a serial port. The firmware decodes a byte at a
+//   time into a fixed buffer, here there's room to keep everything read so far instead
+
+use arduino_protocol::frame::{crc8, OVERHEAD, SYNC};
+use arduino_protocol::message::KEY_CHANGE_LEN;
+use arduino_protocol::panic_report::MAX_REPORT_LEN;
+use keybed_logic::SLOTS;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Result};
+
+// The longest payload either firmware sends, a scan where every slot changed (or a panic report, if
+//   those ever get longer). A sync byte in some noise claims any length, and waiting for that many
+//   bytes could hold up everything behind it until the board happens to send enough
+const MAX_LEN: usize = max(2 + SLOTS * KEY_CHANGE_LEN, MAX_REPORT_LEN);
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+pub struct Frame {
+    pub kind: u8,
+    pub payload: Vec<u8>,
+}
+
+// Bytes read but not yet part of a good frame. After a bad frame the search for the next one
+//   starts just after its sync byte, so a real frame hiding in the bad one's payload isn't lost
+pub struct FrameReader {
+    pending: VecDeque<u8>,
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        Self {
+            pending: VecDeque::new(),
+        }
+    }
+
+    // Forget anything half read, the other end has started over
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    // None means a bad frame (or some junk) was skipped
+    pub fn read_frame(&mut self, source: &mut impl Read) -> Result<Option<Frame>> {
+        // Junk before the sync byte
+        self.fill(source, 1)?;
+        let junk = self
+            .pending
+            .iter()
+            .position(|&byte| byte == SYNC)
+            .unwrap_or(self.pending.len());
+        if junk > 0 {
+            self.pending.drain(..junk);
+            return Ok(None);
+        }
+
+        self.fill(source, 2)?;
+        let len = self.pending[1] as usize;
+        if len > MAX_LEN {
+            self.pending.pop_front();
+            return Ok(None);
+        }
+        self.fill(source, len + OVERHEAD)?;
+
+        let frame: Vec<u8> = self.pending.range(..len + OVERHEAD).copied().collect();
+        let (crc, checked) = (frame[len + 3], &frame[1..len + 3]);
+        if crc8(checked) != crc {
+            self.pending.pop_front();
+            return Ok(None);
+        }
+
+        self.pending.drain(..len + OVERHEAD);
+        Ok(Some(Frame {
+            kind: frame[2],
+            payload: frame[3..len + 3].to_vec(),
+        }))
+    }
+
+    fn fill(&mut self, source: &mut impl Read, len: usize) -> Result<()> {
+        let mut buffer = [0u8; 64];
+        while self.pending.len() < len {
+            let read = source.read(&mut buffer)?;
+            if read == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            self.pending.extend(&buffer[..read]);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arduino_protocol::Message;
+
+    fn encode(message: Message) -> Vec<u8> {
+        let mut frame = vec![];
+        message.encode(&mut |byte| frame.push(byte));
+        frame
+    }
+
+    // Every frame in `bytes`, skipping the bad ones, until they run out
+    fn read_all(bytes: &[u8]) -> Vec<Frame> {
+        let mut reader = FrameReader::new();
+        let mut source = bytes;
+        let mut frames = vec![];
+        loop {
+            match reader.read_frame(&mut source) {
+                Ok(Some(frame)) => frames.push(frame),
+                Ok(None) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return frames,
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    fn assert_pedal(frames: &[Frame], pressed: &[bool]) {
+        let frames: Vec<Message> = frames
+            .iter()
+            .map(|frame| Message::decode(frame.kind, &frame.payload).unwrap())
+            .collect();
+        let expected: Vec<Message> = pressed.iter().map(|&p| Message::Pedal(p)).collect();
+        assert_eq!(frames, expected);
+    }
+
+    #[test]
+    fn junk_between_frames_is_skipped() {
+        let mut bytes = vec![0, 1, 2];
+        bytes.extend(encode(Message::Pedal(true)));
+        bytes.extend([7, 8]);
+        bytes.extend(encode(Message::Pedal(false)));
+
+        assert_pedal(&read_all(&bytes), &[true, false]);
+    }
+
+    #[test]
+    fn reads_resync_after_a_corrupt_frame() {
+        let mut corrupt = encode(Message::Volume(0x1234));
+        corrupt[3] ^= 0xFF;
+
+        let mut bytes = corrupt;
+        bytes.extend(encode(Message::Pedal(true)));
+
+        assert_pedal(&read_all(&bytes), &[true]);
+    }
+
+    #[test]
+    fn frames_inside_a_corrupt_frame_are_found() {
+        // A sync and a length that swallow the next frame, with a CRC that can't match
+        let mut bytes = vec![SYNC, 4];
+        bytes.extend(encode(Message::Pedal(true)));
+        bytes.push(0);
+
+        assert_pedal(&read_all(&bytes), &[true]);
+    }
+
+    #[test]
+    fn oversized_lengths_are_skipped_without_waiting() {
+        // Fewer bytes follow than the bogus length, reading them all would hit the end first
+        let mut bytes = vec![SYNC, u8::MAX];
+        bytes.extend(encode(Message::Pedal(true)));
+
+        assert_pedal(&read_all(&bytes), &[true]);
+    }
+
+    #[test]
+    fn the_longest_messages_fit() {
+        let mut frame = vec![SYNC, MAX_LEN as u8, b'K'];
+        frame.extend(vec![0; MAX_LEN]);
+        let crc = crc8(&frame[1..]);
+        frame.push(crc);
+
+        let frames = read_all(&frame);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].payload.len(), MAX_LEN);
+    }
+}
@@ -20,7 +20,7 @@ const B_GPIO_PIN: u8 = 13;
 
 // Start a thread to poll for gpio interrupts and translate them to events
 pub fn start_gpio_driver(
-    midi_channel: Sender<MidiEvent>,
+    _midi_channel: Sender<MidiEvent>,
     ui_channel: Sender<UIEvent>,
 ) -> Result<JoinHandle<Result<()>>> {
     let gpio = Gpio::new()?;
@@ -1,15 +1,24 @@
-use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
 
 use anyhow::{anyhow, Result};
+use arduino_protocol::command::{PARAM_RAW_KEYS, PARAM_SCAN_INTERVAL};
+use arduino_protocol::stored::STORED_KEY_MAP;
+use arduino_protocol::{ContactFault, KeyChange, KeyEvent, Message};
 use crossbeam::channel::Sender;
-use midly::num::u7;
-use midly::MidiMessage::{NoteOff, NoteOn};
+use keybed_logic::SLOTS;
 
-use crate::io::io_impl::arduino::Arduino;
+use crate::calibration::KEYS;
+use crate::contacts::SharedContacts;
+use crate::io::io_impl::arduino::{Arduino, ArduinoPaths};
+use crate::key_map::KeyMap;
+use crate::keyboard::Keyboard;
 use crate::midi_sender::MidiEvent;
-use rs_tty::TTY;
+use crate::panics::SharedPanics;
+use crate::settings::{Settings, SharedSettings};
+use crate::telemetry::SharedTelemetry;
 
 const SERIAL_DEVICE: &str = "/dev/ttyUSBkeyboard";
 const SERIAL_BAUD: u32 = 115_200;
@@ -18,20 +27,38 @@ const FIRMWARE_BIN: &str = "/usr/share/keyboard.elf";
 const FIRMWARE_VERSION: &str = "/usr/share/keyboard-version.txt";
 const FIRMWARE_HEADER: &str = "I am a keyboard! :3 ";
 
-enum Message {
-    KeyDown(u8, u8),
-    KeyUp(u8),
+// Every slot of the matrix that changed in one scan of the keybed, numbered so missed scans can be
+//   spotted. The key map says which key each slot is
+struct Scan {
+    sequence: u8,
+    changes: Vec<KeyChange>,
 }
 
-fn read_next_message(buffer: &mut [u8; 3], serial: &mut TTY) -> Result<Message> {
-    match buffer[0] {
-        b'D' => {
-            serial.read_exact(&mut buffer[1..3])?;
-            Ok(Message::KeyDown(buffer[1], buffer[2]))
+enum KeyboardMessage {
+    Scan(Scan),
+    // Keys with bad contact readings, numbered like a scan's changes
+    Contacts(Vec<ContactFault>),
+}
+
+fn read_next_message(message: Message) -> Result<KeyboardMessage> {
+    match message {
+        Message::Keys { sequence, changes } => {
+            // A scan can't change more slots than there are
+            if changes.len() > SLOTS {
+                return Err(anyhow!("Keyboard scan with {} changes", changes.len()));
+            }
+
+            Ok(KeyboardMessage::Scan(Scan {
+                sequence,
+                changes: changes.iter().collect(),
+            }))
         }
-        b'U' => {
-            serial.read_exact(&mut buffer[1..2])?;
-            Ok(Message::KeyUp(buffer[1]))
+        Message::Contacts(faults) => {
+            if faults.len() > SLOTS {
+                return Err(anyhow!("Contact report for {} keys", faults.len()));
+            }
+
+            Ok(KeyboardMessage::Contacts(faults.iter().collect()))
         }
         _ => {
             // Who knows what we read
@@ -40,66 +67,455 @@ fn read_next_message(buffer: &mut [u8; 3], serial: &mut TTY) -> Result<Message>
     }
 }
 
-pub fn start_keyboard_driver(midi_channel: Sender<MidiEvent>) -> Result<JoinHandle<Result<()>>> {
-    let mut arduino = Arduino::new(
-        FIRMWARE_BIN,
-        FIRMWARE_VERSION,
-        FIRMWARE_HEADER,
-        SERIAL_DEVICE,
-        SERIAL_BAUD,
-        read_next_message,
-    )?;
-
-    Ok(thread::spawn(move || {
-        let vel_curve = pow_curve(2.0);
-
-        loop {
-            midi_channel.try_send(match arduino.read_next_message()? {
-                Message::KeyDown(key, travel_time) => NoteOn {
-                    key: u7::new(note(key)),
-                    vel: u7::new(calc_velocity(travel_time, &vel_curve)),
-                },
-                Message::KeyUp(key) => NoteOff {
-                    key: u7::new(note(key)),
-                    vel: Default::default(),
-                },
-            })?;
+pub fn keyboard_paths() -> ArduinoPaths {
+    ArduinoPaths::new(SERIAL_DEVICE, FIRMWARE_BIN, FIRMWARE_VERSION)
+}
+
+pub fn start_keyboard_driver(
+    paths: ArduinoPaths,
+    midi_channel: Sender<MidiEvent>,
+    settings: SharedSettings,
+    panics: SharedPanics,
+    contacts: SharedContacts,
+    telemetry: SharedTelemetry,
+) -> Result<JoinHandle<Result<()>>> {
+    let reset = Arc::new(AtomicBool::new(false));
+    let scan_interval = settings.read().unwrap().keyboard_scan_interval_us();
+    let mut arduino = Arduino::new(paths, FIRMWARE_HEADER, SERIAL_BAUD, read_next_message)?
+        .parameter(PARAM_SCAN_INTERVAL, scan_interval)
+        .parameter(PARAM_RAW_KEYS, 1)
+        .record_panics("keyboard", panics)
+        .record_telemetry("keyboard", telemetry)
+        .on_reset({
+            let midi_channel = midi_channel.clone();
+            let reset = reset.clone();
+            move || {
+                reset.store(true, Ordering::Relaxed);
+                midi_channel.try_send(MidiEvent::AllNotesOff)?;
+                Ok(())
+            }
+        });
+
+    // What the keyboard has stored, or the map it'd use if it hasn't
+    let mut stored_key_map = sync_key_map(&mut arduino, &settings);
+    let mut keyboard = Keyboard::new(settings.clone());
+    let mut last_sequence: Option<u8> = None;
+
+    Ok(thread::spawn(move || loop {
+        let scan = match arduino.read_next_message()? {
+            KeyboardMessage::Scan(scan) => scan,
+            KeyboardMessage::Contacts(faults) => {
+                let settings = settings.read().unwrap();
+                let mut contacts = contacts.write().unwrap();
+                // Slots with nothing mapped to them don't matter
+                for fault in faults {
+                    if let Some(key) = settings.key_map().key(fault.key) {
+                        contacts.record(key, fault.impossible, fault.chatter);
+                    }
+                }
+                continue;
+            }
+        };
+        // Any keys that were down before the reset will never send a key up
+        if reset.swap(false, Ordering::Relaxed) {
+            keyboard.reset();
+            // The firmware starts counting again
+            last_sequence = None;
+        }
+
+        let Scan { sequence, changes } = scan;
+
+        if let Some(last) = last_sequence {
+            let missed = sequence.wrapping_sub(last).wrapping_sub(1);
+            if missed > 0 {
+                println!("Missed {} keyboard scans, notes may be stuck", missed);
+            }
+        }
+        last_sequence = Some(sequence);
+
+        let (changes, new_key_map) = {
+            let mut settings = settings.write().unwrap();
+            let changes: Vec<KeyChange> = changes
+                .into_iter()
+                .filter_map(|change| to_key(&mut settings, change))
+                .collect();
+            // Mapping finishes with a key press, so this is as soon as it can have changed
+            let key_map = settings.key_map();
+            (
+                changes,
+                (*key_map != stored_key_map).then(|| key_map.clone()),
+            )
+        };
+
+        // A chord is handled as one group, after the whole scan has arrived
+        let events: Vec<MidiEvent> = changes
+            .into_iter()
+            .flat_map(|change| match change.event {
+                KeyEvent::Down(travel_time) => keyboard.key_down(change.key, travel_time),
+                KeyEvent::Up(release_time) => keyboard.key_up(change.key, release_time),
+            })
+            .collect();
+
+        for event in events {
+            midi_channel.try_send(event)?;
+        }
+
+        if let Some(key_map) = new_key_map {
+            store_key_map(&mut arduino, &key_map);
+            stored_key_map = key_map;
         }
     }))
 }
 
-// TODO: Support microtonal tunings
-fn note(key: u8) -> u8 {
-    // midi middle c = 60
-    // keyboard middle c = 24
-    let midi = 60 + (key - 24);
-    println!("{} {}", key, midi);
+// A map made on this Pi is copied to the keyboard. A Pi without one, a freshly flashed image say,
+//   uses the keyboard's copy instead
+fn sync_key_map(
+    arduino: &mut Arduino<KeyboardMessage, impl FnMut(Message<'_>) -> Result<KeyboardMessage>>,
+    settings: &SharedSettings,
+) -> KeyMap {
+    let (key_map, saved) = {
+        let settings = settings.read().unwrap();
+        let saved = KeyMap::is_saved(settings.config_dir());
+        (settings.key_map().clone(), saved)
+    };
+    if saved {
+        store_key_map(arduino, &key_map);
+        return key_map;
+    }
 
-    midi
+    let stored = arduino
+        .load_all(STORED_KEY_MAP, KEYS)
+        .and_then(|slots| slots.map(|slots| KeyMap::from_stored(&slots)).transpose());
+    match stored {
+        Ok(Some(stored)) => {
+            println!("Using the key map stored on the keyboard");
+            settings.write().unwrap().use_key_map(stored.clone());
+            stored
+        }
+        // The original wiring doesn't need storing
+        Ok(None) => key_map,
+        Err(e) => {
+            println!("Couldn't load the keyboard's key map: {}", e);
+            key_map
+        }
+    }
 }
 
-// fn linear_curve(t: f32) -> f32 {
-//     return t;
-// }
+fn store_key_map(
+    arduino: &mut Arduino<KeyboardMessage, impl FnMut(Message<'_>) -> Result<KeyboardMessage>>,
+    key_map: &KeyMap,
+) {
+    if let Err(e) = arduino.store_all(STORED_KEY_MAP, &key_map.to_stored()) {
+        println!("Couldn't store the key map on the keyboard: {}", e);
+    }
+}
 
-fn pow_curve(pow: f32) -> impl Fn(f32) -> f32 {
-    move |t: f32| t.powf(pow.clamp(0.0, 10.0))
+// Slots with nothing mapped to them are dropped. While mapping, presses are recorded instead of
+//   played, but releases still go through so nothing held from before is left stuck
+fn to_key(settings: &mut Settings, change: KeyChange) -> Option<KeyChange> {
+    if settings.mapping_keys() && matches!(change.event, KeyEvent::Down(_)) {
+        settings.record_key_slot(change.key);
+        return None;
+    }
+
+    let key = settings.key_map().key(change.key)?;
+    Some(KeyChange { key, ..change })
 }
 
-fn calc_velocity(travel_time: u8, curve: impl FnOnce(f32) -> f32) -> u8 {
-    // The firmware reports the time between each contact being pressed in whole milliseconds
-    // Midi expects some number in [0-127]
-    let min_travel_time = 1.0f32;
-    let max_travel_time = 80.0f32;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contacts::{ContactCounts, Contacts};
+    use crate::io::io_impl::fake_arduino::{wait_for, FakeArduino};
+    use crate::panics::Panics;
+    use crate::settings::Settings;
+    use crate::telemetry::{LoopTiming, Telemetry};
+    use arduino_protocol::{Command, ContactFaults, KeyChanges, PanicReport};
+    use crossbeam::channel::{unbounded, Receiver};
+    use midly::num::u7;
+    use midly::MidiMessage::{NoteOff, NoteOn};
+
+    // Middle C and the E above it, untransposed
+    const C: u8 = 24;
+    const E: u8 = 28;
+
+    const PARAMETERS: [u8; 2] = [PARAM_SCAN_INTERVAL, PARAM_RAW_KEYS];
+
+    struct Started {
+        arduino: FakeArduino,
+        midi: Receiver<MidiEvent>,
+        panics: SharedPanics,
+        contacts: SharedContacts,
+        telemetry: SharedTelemetry,
+    }
+
+    fn start() -> Started {
+        start_with(FakeArduino::start(FIRMWARE_HEADER, "test"))
+    }
+
+    // For a fake that's had settings stored first
+    fn start_with(mut arduino: FakeArduino) -> Started {
+        let (midi_sender, midi) = unbounded();
+        let panics = Panics::load_from(arduino.dir.join("panics.txt")).shared();
+        let contacts = Contacts::new().shared();
+        let telemetry = Telemetry::new().shared();
+
+        arduino.start_driver(&PARAMETERS, |arduino| {
+            Ok(vec![start_keyboard_driver(
+                arduino.paths.clone(),
+                midi_sender,
+                Settings::load_from(&arduino.dir).shared(),
+                panics.clone(),
+                contacts.clone(),
+                telemetry.clone(),
+            )?])
+        });
+
+        Started {
+            arduino,
+            midi,
+            panics,
+            contacts,
+            telemetry,
+        }
+    }
 
-    let clamped_travel_time = (travel_time as f32).clamp(min_travel_time, max_travel_time);
+    fn send_scan(arduino: &FakeArduino, sequence: u8, changes: &[KeyChange]) {
+        arduino.send(Message::Keys {
+            sequence,
+            changes: KeyChanges::List(changes),
+        });
+    }
 
-    let norm_travel_time =
-        (clamped_travel_time - min_travel_time) / (max_travel_time - min_travel_time);
+    // The firmware sends slots, these are where the original wiring puts the keys
+    fn slot_of(key: u8) -> u8 {
+        let map = KeyMap::factory();
+        (0..SLOTS as u8)
+            .find(|&slot| map.key(slot) == Some(key))
+            .unwrap()
+    }
 
-    let velocity = 127.0 - (curve(norm_travel_time) * 126.0);
-    assert!(velocity <= 127.0);
-    assert!(velocity > 0.0);
+    fn down(key: u8) -> KeyChange {
+        KeyChange {
+            key: slot_of(key),
+            event: KeyEvent::Down(100),
+        }
+    }
 
-    velocity as u8
+    fn up(key: u8) -> KeyChange {
+        KeyChange {
+            key: slot_of(key),
+            event: KeyEvent::Up(100),
+        }
+    }
+
+    // The tuning reset sent before the first note is skipped
+    fn expect_note_on(midi: &Receiver<MidiEvent>, note: u8) {
+        wait_for(midi, |event| match event {
+            MidiEvent::Channel {
+                message: NoteOn { key, .. },
+                ..
+            } => *key == u7::new(note),
+            _ => false,
+        });
+    }
+
+    fn expect_note_off(midi: &Receiver<MidiEvent>, note: u8) {
+        wait_for(midi, |event| match event {
+            MidiEvent::Channel {
+                message: NoteOff { key, .. },
+                ..
+            } => *key == u7::new(note),
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn chords_play_and_release() {
+        let Started { arduino, midi, .. } = start();
+
+        send_scan(&arduino, 0, &[down(C), down(E)]);
+        expect_note_on(&midi, 60);
+        expect_note_on(&midi, 64);
+
+        send_scan(&arduino, 1, &[up(C), up(E)]);
+        expect_note_off(&midi, 60);
+        expect_note_off(&midi, 64);
+    }
+
+    #[test]
+    fn garbage_and_other_messages_are_skipped() {
+        let Started { arduino, midi, .. } = start();
+
+        // A frame with a bad CRC, then junk
+        arduino.send_bytes(b"\xA5\x03Kjunk\x00\xFF");
+        // A dials message
+        arduino.send(Message::Volume(100));
+        send_scan(&arduino, 0, &[down(C)]);
+
+        expect_note_on(&midi, 60);
+    }
+
+    #[test]
+    fn panics_are_recorded_once_and_release_everything() {
+        let Started {
+            arduino,
+            midi,
+            panics,
+            ..
+        } = start();
+        send_scan(&arduino, 0, &[down(C)]);
+        expect_note_on(&midi, 60);
+
+        let report = PanicReport::new(3, "src/keybed.rs", 90, 13, "oops");
+        arduino.send(Message::Panic(report));
+        wait_for(&midi, |event| *event == MidiEvent::AllNotesOff);
+        arduino.expect_parameters(&PARAMETERS);
+
+        // The firmware sends the same report again once it's back up
+        arduino.boot();
+        arduino.send(Message::LastPanic(report));
+        // The key was released by the reset, the firmware's counting from 0 again
+        send_scan(&arduino, 0, &[down(C)]);
+        expect_note_on(&midi, 60);
+
+        let panics = panics.read().unwrap();
+        assert_eq!(panics.count("keyboard"), 1);
+        assert_eq!(panics.last().unwrap().location, "src/keybed.rs:90:13");
+    }
+
+    #[test]
+    fn parameters_are_only_stored_once() {
+        let Started { arduino, midi, .. } = start();
+        wait_for(arduino.commands(), |command| {
+            matches!(
+                command,
+                Command::Store {
+                    setting: PARAM_RAW_KEYS,
+                    ..
+                }
+            )
+        });
+        assert_eq!(arduino.stored(PARAM_RAW_KEYS), Some(1));
+
+        arduino.boot();
+        arduino.expect_parameters(&PARAMETERS);
+        send_scan(&arduino, 0, &[down(C)]);
+        expect_note_on(&midi, 60);
+
+        let commands: Vec<Command> = arduino.commands().try_iter().collect();
+        assert!(!commands
+            .iter()
+            .any(|command| matches!(command, Command::Store { .. })));
+    }
+
+    #[test]
+    fn restarts_while_storing_release_everything() {
+        let arduino = FakeArduino::start(FIRMWARE_HEADER, "test");
+        // The parameters are stored once they've been set
+        arduino.boot_on_next_store();
+        let Started { arduino, midi, .. } = start_with(arduino);
+
+        wait_for(&midi, |event| *event == MidiEvent::AllNotesOff);
+        arduino.expect_parameters(&PARAMETERS);
+    }
+
+    #[test]
+    fn the_keyboards_key_map_is_used_without_one_on_the_pi() {
+        let arduino = FakeArduino::start(FIRMWARE_HEADER, "test");
+        // C and E wired the other way round
+        let mut slots = KeyMap::factory().to_stored();
+        slots.swap(C as usize, E as usize);
+        for (key, slot) in slots.into_iter().enumerate() {
+            arduino.store(STORED_KEY_MAP + key as u8, slot);
+        }
+        let Started { arduino, midi, .. } = start_with(arduino);
+
+        send_scan(&arduino, 0, &[down(C)]);
+        expect_note_on(&midi, 64);
+    }
+
+    #[test]
+    fn bad_contacts_are_counted_by_key() {
+        let Started {
+            arduino,
+            midi,
+            contacts,
+            ..
+        } = start();
+
+        let fault = |key, impossible, chatter| ContactFault {
+            key,
+            impossible,
+            chatter,
+        };
+        // Nothing's wired to slot 6
+        let faults = [
+            fault(slot_of(C), 2, 1),
+            fault(slot_of(E), 0, 3),
+            fault(6, 1, 1),
+        ];
+        arduino.send(Message::Contacts(ContactFaults::List(&faults)));
+        arduino.send(Message::Contacts(ContactFaults::List(&faults[..1])));
+        // Messages are handled in order, so they've been counted once this plays
+        send_scan(&arduino, 0, &[down(C)]);
+        expect_note_on(&midi, 60);
+
+        let counts = |impossible, chatter| ContactCounts {
+            impossible,
+            chatter,
+        };
+        assert_eq!(
+            contacts.read().unwrap().suspects(),
+            vec![(C, counts(4, 2)), (E, counts(0, 3))]
+        );
+    }
+
+    #[test]
+    fn timing_reports_are_recorded() {
+        let Started {
+            arduino,
+            midi,
+            telemetry,
+            ..
+        } = start();
+
+        arduino.send(Message::Telemetry(arduino_protocol::Telemetry {
+            period_ms: 1000,
+            scans: 5000,
+            worst_loop_us: 400,
+            watchdog_us: 500_000,
+            tx_waits: 2,
+        }));
+        send_scan(&arduino, 0, &[down(C)]);
+        expect_note_on(&midi, 60);
+
+        assert_eq!(
+            telemetry.read().unwrap().get("keyboard"),
+            Some(&LoopTiming {
+                scans_per_second: 5000,
+                worst_loop_us: 400,
+                watchdog_margin_us: 499_600,
+                tx_waits: 2,
+            })
+        );
+        assert_eq!(telemetry.read().unwrap().get("dials"), None);
+    }
+
+    #[test]
+    fn presses_are_recorded_while_mapping() {
+        let arduino = FakeArduino::start(FIRMWARE_HEADER, "test");
+        let mut settings = Settings::load_from(arduino.dir.clone());
+        settings.step_key_mapping(1);
+
+        assert_eq!(to_key(&mut settings, down(C)), None);
+        assert_eq!(
+            to_key(&mut settings, up(C)),
+            Some(KeyChange {
+                key: C,
+                event: KeyEvent::Up(100)
+            })
+        );
+        assert_eq!(settings.key_mapping_status(), "press C#2");
+    }
 }
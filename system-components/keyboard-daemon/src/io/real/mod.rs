@@ -1,8 +1,12 @@
-use crate::io::io_impl::dials_driver::start_dials_driver;
+use crate::contacts::SharedContacts;
+use crate::io::io_impl::dials_driver::{dials_paths, start_dials_driver};
 use crate::io::io_impl::display::DisplayImpl;
 use crate::io::io_impl::gpio_driver::start_gpio_driver;
-use crate::io::io_impl::keyboard_driver::start_keyboard_driver;
+use crate::io::io_impl::keyboard_driver::{keyboard_paths, start_keyboard_driver};
 use crate::midi_sender::MidiEvent;
+use crate::panics::SharedPanics;
+use crate::settings::SharedSettings;
+use crate::telemetry::SharedTelemetry;
 use crate::user_interface::UIEvent;
 use crate::Threads;
 use anyhow::Result;
@@ -11,17 +15,42 @@ use crossbeam::channel::Sender;
 mod arduino;
 mod dials_driver;
 pub(crate) mod display;
+mod elf;
+mod faders;
+mod frame;
 mod gpio_driver;
 mod keyboard_driver;
+mod stk500;
+
+#[cfg(test)]
+mod fake_arduino;
 
 pub fn init_io(
     threads: &mut Threads,
+    settings: SharedSettings,
+    panics: SharedPanics,
+    contacts: SharedContacts,
+    telemetry: SharedTelemetry,
     midi_channel: Sender<MidiEvent>,
     ui_channel: Sender<UIEvent>,
 ) -> Result<impl crate::io::IO<DisplayImpl>> {
     threads.push(start_gpio_driver(midi_channel.clone(), ui_channel.clone())?);
-    threads.push(start_dials_driver(midi_channel.clone())?);
-    threads.push(start_keyboard_driver(midi_channel.clone())?);
+    threads.extend(start_dials_driver(
+        dials_paths(),
+        midi_channel.clone(),
+        ui_channel.clone(),
+        settings.clone(),
+        panics.clone(),
+        telemetry.clone(),
+    )?);
+    threads.push(start_keyboard_driver(
+        keyboard_paths(),
+        midi_channel.clone(),
+        settings,
+        panics,
+        contacts,
+        telemetry,
+    )?);
 
     Ok(IO {
         display: DisplayImpl::new(),
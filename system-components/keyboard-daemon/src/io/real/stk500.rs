@@ -0,0 +1,348 @@
+// Flashes firmware through the arduino's Optiboot bootloader, which speaks just enough of STK500
+//   version 1. Every command ends with CRC_EOP, and every answer is STK_INSYNC <data> STK_OK.
+//
+// Resetting the arduino starts the bootloader, which waits a moment for a programmer before starting
+//   the firmware. Pages are written through it and then read back to check they took.
+
+use std::io::{ErrorKind, Read, Write};
+use std::thread::sleep;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use nix::errno::Errno;
+use rs_tty::TTY;
+
+const STK_OK: u8 = 0x10;
+const STK_INSYNC: u8 = 0x14;
+const CRC_EOP: u8 = 0x20;
+
+const STK_GET_SYNC: u8 = 0x30;
+const STK_ENTER_PROGMODE: u8 = 0x50;
+const STK_LEAVE_PROGMODE: u8 = 0x51;
+const STK_LOAD_ADDRESS: u8 = 0x55;
+const STK_PROG_PAGE: u8 = 0x64;
+const STK_READ_PAGE: u8 = 0x74;
+const STK_READ_SIGN: u8 = 0x75;
+
+const MEMORY_FLASH: u8 = b'F';
+
+const RESET_PULSE: Duration = Duration::from_millis(250);
+const BOOTLOADER_START: Duration = Duration::from_millis(50);
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(200);
+// Optiboot gives up on the programmer after a second, this is about as many tries as fit
+const SYNC_ATTEMPTS: usize = 5;
+const RESYNC_WAIT: Duration = Duration::from_millis(50);
+
+pub struct Device {
+    pub name: &'static str,
+    pub signature: [u8; 3],
+    pub page_size: usize,
+    // Not counting the bootloader at the top
+    pub flash_size: usize,
+}
+
+pub const ATMEGA328P: Device = Device {
+    name: "ATmega328P",
+    signature: [0x1E, 0x95, 0x0F],
+    page_size: 128,
+    flash_size: 32 * 1024 - 512,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Stage {
+    Writing,
+    Verifying,
+}
+
+// Bytes done out of the whole image
+#[derive(Copy, Clone, Debug)]
+pub struct Progress {
+    pub stage: Stage,
+    pub done: usize,
+    pub total: usize,
+}
+
+pub fn flash(
+    port: &mut TTY,
+    device: &Device,
+    image: &[u8],
+    progress: impl FnMut(Progress),
+) -> Result<()> {
+    if image.len() > device.flash_size {
+        return Err(anyhow!(
+            "Firmware is {} bytes, the {} only has room for {}",
+            image.len(),
+            device.name,
+            device.flash_size
+        ));
+    }
+
+    port.set_read_timeout(Some(RESPONSE_TIMEOUT));
+    let result = program(port, device, image, progress);
+    port.set_read_timeout(None);
+    result
+}
+
+fn program(
+    port: &mut TTY,
+    device: &Device,
+    image: &[u8],
+    mut progress: impl FnMut(Progress),
+) -> Result<()> {
+    reset(port)?;
+    sync(port)?;
+
+    let signature = command(port, &[STK_READ_SIGN], 3)?;
+    if signature != device.signature {
+        return Err(anyhow!(
+            "Expected an {} (signature {:02X?}), found {:02X?}",
+            device.name,
+            device.signature,
+            signature
+        ));
+    }
+
+    command(port, &[STK_ENTER_PROGMODE], 0)?;
+
+    for stage in [Stage::Writing, Stage::Verifying] {
+        for (i, page) in image.chunks(device.page_size).enumerate() {
+            let address = i * device.page_size;
+            load_address(port, address)?;
+
+            let [size_high, size_low] = (page.len() as u16).to_be_bytes();
+            match stage {
+                Stage::Writing => {
+                    let mut prog_page = vec![STK_PROG_PAGE, size_high, size_low, MEMORY_FLASH];
+                    prog_page.extend(page);
+                    command(port, &prog_page, 0)?;
+                }
+                Stage::Verifying => {
+                    let read = command(
+                        port,
+                        &[STK_READ_PAGE, size_high, size_low, MEMORY_FLASH],
+                        page.len(),
+                    )?;
+                    if read != page {
+                        return Err(anyhow!("Flash doesn't match at page 0x{:04X}", address));
+                    }
+                }
+            }
+
+            progress(Progress {
+                stage,
+                done: address + page.len(),
+                total: image.len(),
+            });
+        }
+    }
+
+    // The bootloader starts the new firmware once it's out of programming mode
+    command(port, &[STK_LEAVE_PROGMODE], 0)?;
+    Ok(())
+}
+
+// Ports without modem control lines (a pty, say) can't reset the arduino, it had better be in the
+//   bootloader already
+fn reset(port: &mut TTY) -> Result<()> {
+    match port.set_dtr(false) {
+        Err(e) if e.raw_os_error() == Some(Errno::ENOTTY as i32) => return Ok(()),
+        result => result?,
+    }
+    sleep(RESET_PULSE);
+    port.set_dtr(true)?;
+    sleep(BOOTLOADER_START);
+
+    // Whatever the old firmware was saying when it was cut off
+    port.discard_input()?;
+    Ok(())
+}
+
+fn sync(port: &mut TTY) -> Result<()> {
+    for _ in 0..SYNC_ATTEMPTS {
+        match command(port, &[STK_GET_SYNC], 0) {
+            Ok(_) => return Ok(()),
+            // Let the answer to this attempt come in too, so it's not taken for the next one's
+            Err(_) => {
+                sleep(RESYNC_WAIT);
+                port.discard_input()?;
+            }
+        }
+    }
+
+    Err(anyhow!("Bootloader didn't answer"))
+}
+
+// Addresses are in words
+fn load_address(port: &mut TTY, address: usize) -> Result<()> {
+    let [low, high] = ((address / 2) as u16).to_le_bytes();
+    command(port, &[STK_LOAD_ADDRESS, low, high], 0)?;
+    Ok(())
+}
+
+fn command(port: &mut TTY, command: &[u8], response_len: usize) -> Result<Vec<u8>> {
+    let mut bytes = command.to_vec();
+    bytes.push(CRC_EOP);
+    port.write_all(&bytes)?;
+
+    let mut response = vec![0; response_len + 2];
+    port.read_exact(&mut response).map_err(|e| match e.kind() {
+        ErrorKind::TimedOut => anyhow!("Bootloader didn't answer command 0x{:02X}", command[0]),
+        _ => e.into(),
+    })?;
+
+    if response[0] != STK_INSYNC || response[response_len + 1] != STK_OK {
+        return Err(anyhow!(
+            "Bootloader lost sync on command 0x{:02X}: {:02X?}",
+            command[0],
+            response
+        ));
+    }
+
+    Ok(response[1..response_len + 1].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::pty::openpty;
+    use std::fs::File;
+    use std::os::fd::AsRawFd;
+    use std::thread;
+
+    // None once the flasher has closed its end
+    fn read_bytes(port: &mut File, len: usize) -> Option<Vec<u8>> {
+        let mut bytes = vec![0; len];
+        port.read_exact(&mut bytes).ok()?;
+        Some(bytes)
+    }
+
+    // Answers like Optiboot until it's told to leave programming mode, then hands back the flash.
+    //   `corrupt` flips a bit in whatever's read back from that address
+    fn fake_bootloader(
+        mut port: File,
+        signature: [u8; 3],
+        corrupt: Option<usize>,
+    ) -> Option<Vec<u8>> {
+        let mut flash = vec![0xFF; ATMEGA328P.flash_size];
+        let mut address = 0;
+
+        // Left over from the firmware that was running
+        port.write_all(b"\xA5junk").ok()?;
+
+        loop {
+            let command = read_bytes(&mut port, 1)?[0];
+            let (reply, done) = match command {
+                STK_GET_SYNC | STK_ENTER_PROGMODE => (vec![], false),
+                STK_LEAVE_PROGMODE => (vec![], true),
+                STK_READ_SIGN => (signature.to_vec(), false),
+                STK_LOAD_ADDRESS => {
+                    let args = read_bytes(&mut port, 2)?;
+                    address = u16::from_le_bytes([args[0], args[1]]) as usize * 2;
+                    (vec![], false)
+                }
+                STK_PROG_PAGE => {
+                    let args = read_bytes(&mut port, 3)?;
+                    let len = u16::from_be_bytes([args[0], args[1]]) as usize;
+                    let data = read_bytes(&mut port, len)?;
+                    flash[address..address + len].copy_from_slice(&data);
+                    (vec![], false)
+                }
+                STK_READ_PAGE => {
+                    let args = read_bytes(&mut port, 3)?;
+                    let len = u16::from_be_bytes([args[0], args[1]]) as usize;
+                    let mut data = flash[address..address + len].to_vec();
+                    if let Some(at) = corrupt.filter(|at| (address..address + len).contains(at)) {
+                        data[at - address] ^= 1;
+                    }
+                    (data, false)
+                }
+                // Junk, the programmer will try to sync again
+                _ => continue,
+            };
+
+            assert_eq!(read_bytes(&mut port, 1)?, [CRC_EOP]);
+            let mut response = vec![STK_INSYNC];
+            response.extend(reply);
+            response.push(STK_OK);
+            port.write_all(&response).ok()?;
+
+            if done {
+                // Closing the master hangs up the pty, which throws away the answer if it hasn't
+                //   been read yet, so wait for the flasher to close its end first
+                let _ = port.read(&mut [0]);
+                return Some(flash);
+            }
+        }
+    }
+
+    // The flasher's end and the fake arduino's end. The flasher's is opened separately so the pty
+    //   hangs up once it's closed
+    fn open_pty() -> (TTY, File) {
+        let pty = openpty(None, None).unwrap();
+        let port = TTY::open(format!("/proc/self/fd/{}", pty.slave.as_raw_fd()), 115_200).unwrap();
+        (port, File::from(pty.master))
+    }
+
+    fn run(
+        image: &[u8],
+        signature: [u8; 3],
+        corrupt: Option<usize>,
+    ) -> (Result<()>, Vec<Progress>, Option<Vec<u8>>) {
+        let (mut port, bootloader) = open_pty();
+        let bootloader = thread::spawn(move || fake_bootloader(bootloader, signature, corrupt));
+
+        let mut progress = vec![];
+        let result = flash(&mut port, &ATMEGA328P, image, |p| progress.push(p));
+
+        // The bootloader only finishes if it was told to leave programming mode
+        drop(port);
+        let flash = result.is_ok().then(|| bootloader.join().unwrap().unwrap());
+        (result, progress, flash)
+    }
+
+    fn test_image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7) as u8).collect()
+    }
+
+    #[test]
+    fn flashes_and_verifies() {
+        // A partial page at the end
+        let image = test_image(1000);
+        let (result, progress, flash) = run(&image, ATMEGA328P.signature, None);
+
+        result.unwrap();
+        let flash = flash.unwrap();
+        assert_eq!(&flash[..image.len()], image);
+        assert!(flash[image.len()..].iter().all(|&byte| byte == 0xFF));
+
+        let last = |stage| progress.iter().filter(|p| p.stage == stage).last().unwrap();
+        assert_eq!(last(Stage::Writing).done, image.len());
+        assert_eq!(last(Stage::Verifying).done, image.len());
+        assert!(progress.iter().all(|p| p.total == image.len()));
+    }
+
+    #[test]
+    fn verify_catches_bad_flash() {
+        let (result, _, _) = run(&test_image(600), ATMEGA328P.signature, Some(300));
+
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("0x0100"), "{}", error);
+    }
+
+    #[test]
+    fn refuses_the_wrong_chip() {
+        // ATmega168
+        let (result, progress, _) = run(&test_image(100), [0x1E, 0x94, 0x06], None);
+
+        assert!(result.unwrap_err().to_string().contains("signature"));
+        assert!(progress.is_empty());
+    }
+
+    #[test]
+    fn refuses_firmware_that_wont_fit() {
+        let (mut port, _bootloader) = open_pty();
+
+        let image = test_image(ATMEGA328P.flash_size + 1);
+        assert!(flash(&mut port, &ATMEGA328P, &image, |_| {}).is_err());
+    }
+}
@@ -12,21 +12,32 @@ use embedded_graphics_simulator::{
     BinaryColorTheme, OutputSettingsBuilder, SimulatorDisplay, SimulatorEvent, Window,
 };
 use midly::num::u7;
+use midly::MidiMessage;
 
 use crate::io::Display;
+use crate::keyboard::Keyboard;
 use crate::midi_sender::MidiEvent;
+use crate::settings::SharedSettings;
 use crate::user_interface::{Button, UIEvent};
 
+// There's no way to strike a computer key softly, pretend every press is a medium one
+// Tenths of a millisecond, like the firmware sends
+const SIMULATED_TRAVEL_TIME: u16 = 200;
+
 pub struct DisplayImpl {
     display: SimulatorDisplay<BinaryColor>,
     window: Window,
 
     midi_channel: Sender<MidiEvent>,
     ui_channel: Sender<UIEvent>,
+
+    settings: SharedSettings,
+    keyboard: Keyboard,
 }
 
 impl DisplayImpl {
     pub fn new(
+        settings: SharedSettings,
         midi_channel: Sender<MidiEvent>,
         ui_channel: Sender<UIEvent>,
     ) -> Self {
@@ -42,6 +53,8 @@ impl DisplayImpl {
             window,
             midi_channel,
             ui_channel,
+            keyboard: Keyboard::new(settings.clone()),
+            settings,
         }
     }
 }
@@ -77,6 +90,8 @@ impl Display for DisplayImpl {
         // TODO: Goddam fucking borrow checker ruining my life
         let midi_channel = self.midi_channel.clone();
         let ui_channel = self.ui_channel.clone();
+        let settings = self.settings.clone();
+        let keyboard = &mut self.keyboard;
 
         for event in self.window.events() {
             match event {
@@ -88,7 +103,7 @@ impl Display for DisplayImpl {
                         continue;
                     }
 
-                    send_input_event(&midi_channel, &ui_channel, keycode, false)?
+                    send_input_event(&midi_channel, &ui_channel, &settings, keyboard, keycode, false)?
                 }
                 SimulatorEvent::KeyDown {
                     keycode, repeat, ..
@@ -97,7 +112,7 @@ impl Display for DisplayImpl {
                         continue;
                     }
 
-                    send_input_event(&midi_channel, &ui_channel, keycode, true)?
+                    send_input_event(&midi_channel, &ui_channel, &settings, keyboard, keycode, true)?
                 }
                 _ => {}
             }
@@ -110,6 +125,8 @@ impl Display for DisplayImpl {
 fn send_input_event(
     midi_channel: &Sender<MidiEvent>,
     ui_channel: &Sender<UIEvent>,
+    settings: &SharedSettings,
+    keyboard: &mut Keyboard,
     keycode: Keycode,
     down: bool,
 ) -> anyhow::Result<()> {
@@ -124,18 +141,21 @@ fn send_input_event(
         Keycode::LeftBracket => ui_event(ui_channel, down, Button::B),
 
         // Keyboard keys
-        Keycode::A => midi_key_event(midi_channel, down, 48),
-        Keycode::W => midi_key_event(midi_channel, down, 49),
-        Keycode::S => midi_key_event(midi_channel, down, 50),
-        Keycode::E => midi_key_event(midi_channel, down, 51),
-        Keycode::D => midi_key_event(midi_channel, down, 52),
-        Keycode::F => midi_key_event(midi_channel, down, 53),
-        Keycode::T => midi_key_event(midi_channel, down, 54),
-        Keycode::G => midi_key_event(midi_channel, down, 55),
-        Keycode::Y => midi_key_event(midi_channel, down, 56),
-        Keycode::H => midi_key_event(midi_channel, down, 57),
-
-        Keycode::Space => midi_sustain_event(midi_channel, down),
+        Keycode::A => midi_key_event(midi_channel, keyboard, down, 12),
+        Keycode::W => midi_key_event(midi_channel, keyboard, down, 13),
+        Keycode::S => midi_key_event(midi_channel, keyboard, down, 14),
+        Keycode::E => midi_key_event(midi_channel, keyboard, down, 15),
+        Keycode::D => midi_key_event(midi_channel, keyboard, down, 16),
+        Keycode::F => midi_key_event(midi_channel, keyboard, down, 17),
+        Keycode::T => midi_key_event(midi_channel, keyboard, down, 18),
+        Keycode::G => midi_key_event(midi_channel, keyboard, down, 19),
+        Keycode::Y => midi_key_event(midi_channel, keyboard, down, 20),
+        Keycode::H => midi_key_event(midi_channel, keyboard, down, 21),
+
+        Keycode::Space => midi_sustain_event(midi_channel, settings, down),
+
+        // Like the keyboard's firmware resetting, everything held is released
+        Keycode::Backspace if down => simulated_reset(midi_channel, keyboard),
 
         _ => Ok(()),
     }
@@ -151,34 +171,46 @@ fn ui_event(ui_channel: &Sender<UIEvent>, down: bool, button: Button) -> anyhow:
     Ok(())
 }
 
-fn midi_key_event(midi_channel: &Sender<MidiEvent>, down: bool, pitch: u8) -> anyhow::Result<()> {
-    midi_channel.send(if down {
-        MidiEvent::NoteOn {
-            key: u7::new(pitch),
-            vel: u7::new(127 / 2),
-        }
+// Keys are indexes on the keybed, the same as the real keyboard sends
+fn midi_key_event(
+    midi_channel: &Sender<MidiEvent>,
+    keyboard: &mut Keyboard,
+    down: bool,
+    key: u8,
+) -> anyhow::Result<()> {
+    let events = if down {
+        keyboard.key_down(key, SIMULATED_TRAVEL_TIME)
     } else {
-        MidiEvent::NoteOff {
-            key: u7::new(pitch),
-            vel: Default::default(),
-        }
-    })?;
+        keyboard.key_up(key, SIMULATED_TRAVEL_TIME)
+    };
+
+    for event in events {
+        midi_channel.send(event)?;
+    }
 
     Ok(())
 }
 
-fn midi_sustain_event(midi_channel: &Sender<MidiEvent>, down: bool) -> anyhow::Result<()> {
-    midi_channel.send(if down {
-        MidiEvent::Controller {
-            controller: u7::new(0x40),
-            value: u7::max_value(),
-        }
-    } else {
-        MidiEvent::Controller {
-            controller: u7::new(0x40),
-            value: u7::default(),
-        }
-    })?;
+fn simulated_reset(midi_channel: &Sender<MidiEvent>, keyboard: &mut Keyboard) -> anyhow::Result<()> {
+    keyboard.reset();
+    midi_channel.send(MidiEvent::AllNotesOff)?;
+
+    Ok(())
+}
+
+fn midi_sustain_event(
+    midi_channel: &Sender<MidiEvent>,
+    settings: &SharedSettings,
+    down: bool,
+) -> anyhow::Result<()> {
+    let message = MidiMessage::Controller {
+        controller: u7::new(0x40),
+        value: if down { u7::max_value() } else { u7::default() },
+    };
+
+    for channel in settings.read().unwrap().zone_channels() {
+        midi_channel.send(MidiEvent::new(channel, message))?;
+    }
 
     Ok(())
 }
@@ -1,5 +1,9 @@
+use crate::contacts::SharedContacts;
 use crate::io::io_impl::display::DisplayImpl;
 use crate::midi_sender::MidiEvent;
+use crate::panics::SharedPanics;
+use crate::settings::SharedSettings;
+use crate::telemetry::SharedTelemetry;
 use crate::user_interface::UIEvent;
 use crate::Threads;
 use anyhow::Result;
@@ -9,11 +13,18 @@ mod display;
 
 pub fn init_io(
     threads: &mut Threads,
+    settings: SharedSettings,
+    // Simulated arduinos don't panic
+    _panics: SharedPanics,
+    // Or have contacts to wear out
+    _contacts: SharedContacts,
+    // Or firmware to time
+    _telemetry: SharedTelemetry,
     midi_channel: Sender<MidiEvent>,
     ui_channel: Sender<UIEvent>,
 ) -> Result<impl crate::io::IO<DisplayImpl>> {
     Ok(IO {
-        display: DisplayImpl::new(midi_channel, ui_channel),
+        display: DisplayImpl::new(settings, midi_channel, ui_channel),
     })
 }
 
@@ -0,0 +1,252 @@
+// Which key is wired to each slot of the keybed's matrix. The firmware sends slots (see
+//   PARAM_RAW_KEYS) and they're turned into keys here, so a keybed wired some other way only needs a
+//   new map instead of a new formula in the firmware.
+// A map is made by pressing every key in turn, left to right, while mapping from the menu. Until
+//   then it's the original keybed's wiring.
+// The keyboard keeps a copy in its EEPROM too (see STORED_KEY_MAP), for a Pi that's lost its own.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use keybed_logic::{key_index, slot, KEYS_PER_SECTION, SECTIONS, SLOTS};
+
+use crate::calibration::KEYS;
+use crate::config::{read_config, write_config, Section};
+
+const KEY_MAP_FILE: &str = "key-map.conf";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyMap {
+    // The key in each slot, None for slots with nothing wired to them
+    keys: [Option<u8>; SLOTS],
+}
+
+impl KeyMap {
+    pub fn factory() -> Self {
+        let mut keys = [None; SLOTS];
+        for section in 0..SECTIONS {
+            for input in 0..KEYS_PER_SECTION {
+                keys[slot(section, input)] = key_index(section, input).map(|key| key as u8);
+            }
+        }
+
+        Self { keys }
+    }
+
+    pub fn load(dir: &Path) -> Self {
+        let path = key_map_path(dir);
+        if !path.exists() {
+            return Self::factory();
+        }
+
+        match read_config(&path).and_then(|sections| Self::from_section(&sections[0])) {
+            Ok(map) => map,
+            Err(e) => {
+                println!("Couldn't load key map, using the original wiring: {}", e);
+                Self::factory()
+            }
+        }
+    }
+
+    // Only the file on the Pi, the keyboard's copy is left to the driver
+    #[cfg(not(feature = "simulator"))]
+    pub fn is_saved(dir: &Path) -> bool {
+        key_map_path(dir).exists()
+    }
+
+    // key_<key> = <slot>, for every key
+    fn from_section(section: &Section) -> Result<Self> {
+        let slots = (0..KEYS)
+            .map(|key| {
+                let name = format!("key_{}", key);
+                section
+                    .require(&name)?
+                    .parse()
+                    .map_err(|e| anyhow!("bad slot for '{}': {}", name, e))
+            })
+            .collect::<Result<Vec<usize>>>()?;
+
+        Self::from_slots(&slots)
+    }
+
+    // The slot of every key, the way the keyboard stores them
+    #[cfg(any(test, not(feature = "simulator")))]
+    pub fn from_stored(slots: &[u16]) -> Result<Self> {
+        let slots: Vec<usize> = slots.iter().map(|&slot| slot as usize).collect();
+        Self::from_slots(&slots)
+    }
+
+    // Every map has every key in it, there's no way to make one that doesn't
+    #[cfg(any(test, not(feature = "simulator")))]
+    pub fn to_stored(&self) -> Vec<u16> {
+        (0..KEYS as u8)
+            .map(|key| self.slot(key).unwrap_or(SLOTS) as u16)
+            .collect()
+    }
+
+    fn from_slots(slots: &[usize]) -> Result<Self> {
+        if slots.len() != KEYS {
+            return Err(anyhow!("{} keys mapped, not {}", slots.len(), KEYS));
+        }
+
+        let mut keys = [None; SLOTS];
+        for (key, &slot) in slots.iter().enumerate() {
+            match keys.get_mut(slot) {
+                Some(None) => keys[slot] = Some(key as u8),
+                Some(Some(other)) => {
+                    return Err(anyhow!(
+                        "key_{} and key_{} are both slot {}",
+                        other,
+                        key,
+                        slot
+                    ))
+                }
+                None => return Err(anyhow!("key_{} needs a slot below {}", key, SLOTS)),
+            }
+        }
+
+        Ok(Self { keys })
+    }
+
+    fn to_section(&self) -> Section {
+        let mut section = Section::new("");
+        for key in 0..KEYS {
+            if let Some(slot) = self.slot(key as u8) {
+                section.set(format!("key_{}", key), slot);
+            }
+        }
+
+        section
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        write_config(key_map_path(dir), &[self.to_section()])
+    }
+
+    #[cfg(any(test, not(feature = "simulator")))]
+    pub fn key(&self, slot: u8) -> Option<u8> {
+        *self.keys.get(slot as usize)?
+    }
+
+    fn slot(&self, key: u8) -> Option<usize> {
+        self.keys.iter().position(|&k| k == Some(key))
+    }
+}
+
+// Slots in the order their keys were pressed while mapping, so the first one is key 0
+pub struct KeyMapping {
+    slots: Vec<u8>,
+}
+
+impl KeyMapping {
+    pub fn new() -> Self {
+        Self { slots: vec![] }
+    }
+
+    // A key pressed again doesn't move on to the next one
+    #[cfg(any(test, not(feature = "simulator")))]
+    pub fn record(&mut self, slot: u8) {
+        if (slot as usize) < SLOTS && !self.slots.contains(&slot) && !self.is_done() {
+            self.slots.push(slot);
+        }
+    }
+
+    // The key to press next, None once they've all been pressed
+    pub fn next_key(&self) -> Option<u8> {
+        (!self.is_done()).then_some(self.slots.len() as u8)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.slots.len() == KEYS
+    }
+
+    // Only once every key has been pressed, a map with keys missing would leave them silent
+    pub fn finish(&self) -> Option<KeyMap> {
+        if !self.is_done() {
+            return None;
+        }
+
+        let mut keys = [None; SLOTS];
+        for (key, &slot) in self.slots.iter().enumerate() {
+            keys[slot as usize] = Some(key as u8);
+        }
+
+        Some(KeyMap { keys })
+    }
+}
+
+fn key_map_path(dir: &Path) -> PathBuf {
+    dir.join(KEY_MAP_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+
+    #[test]
+    fn factory_map_is_the_original_wiring() {
+        let map = KeyMap::factory();
+
+        assert_eq!(map.key(slot(1, 0) as u8), Some(0));
+        assert_eq!(map.key(slot(0, 0) as u8), Some(7));
+        assert_eq!(map.key(slot(1, 6) as u8), Some(48));
+        assert_eq!(map.key(slot(0, 6) as u8), None);
+        assert_eq!(map.key(SLOTS as u8), None);
+        assert!((0..KEYS as u8).all(|key| map.slot(key).is_some()));
+    }
+
+    #[test]
+    fn saved_maps_load_back() {
+        let map = KeyMap::factory();
+
+        assert_eq!(KeyMap::from_section(&map.to_section()).unwrap(), map);
+    }
+
+    #[test]
+    fn stored_maps_load_back() {
+        let map = KeyMap::factory();
+
+        assert_eq!(KeyMap::from_stored(&map.to_stored()).unwrap(), map);
+        assert!(KeyMap::from_stored(&map.to_stored()[1..]).is_err());
+    }
+
+    #[test]
+    fn bad_maps_are_rejected() {
+        let map = |text: &str| KeyMap::from_section(&parse_config(text).unwrap()[0]);
+        let all_keys = (0..KEYS).map(|key| format!("key_{} = {}\n", key, key));
+        let text: String = all_keys.collect();
+
+        assert!(map(&text).is_ok());
+        assert!(map(&text.replace("key_48 = 48", "")).is_err());
+        assert!(map(&text.replace("key_48 = 48", "key_48 = 0")).is_err());
+        assert!(map(&text.replace("key_48 = 48", "key_48 = 56")).is_err());
+    }
+
+    #[test]
+    fn mapping_takes_keys_in_order() {
+        let mut mapping = KeyMapping::new();
+        // Wired right to left
+        for slot in (0..KEYS as u8).rev() {
+            assert_eq!(mapping.next_key(), Some(KEYS as u8 - 1 - slot));
+            mapping.record(slot);
+            // Bounced, or pressed twice
+            mapping.record(slot);
+        }
+        assert_eq!(mapping.next_key(), None);
+        mapping.record(55);
+
+        let map = mapping.finish().unwrap();
+        assert_eq!(map.key(48), Some(0));
+        assert_eq!(map.key(0), Some(48));
+        assert_eq!(map.key(55), None);
+    }
+
+    #[test]
+    fn unfinished_mappings_are_dropped() {
+        let mut mapping = KeyMapping::new();
+        mapping.record(3);
+
+        assert!(mapping.finish().is_none());
+    }
+}
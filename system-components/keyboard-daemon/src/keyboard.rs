@@ -0,0 +1,170 @@
+// Turns key presses into midi notes, through whichever zones the key falls in.
+// Shared between the real keyboard driver and the simulator.
+
+use midly::num::{u4, u7};
+use midly::MidiMessage::{NoteOff, NoteOn, PitchBend};
+
+use crate::calibration::KEYS;
+use crate::midi_sender::MidiEvent;
+use crate::settings::{Settings, SharedSettings};
+use crate::tuning::{mpe_configuration, mpe_note, mts_sysex, TuningMode, MPE_MEMBER_CHANNELS};
+
+pub struct Keyboard {
+    settings: SharedSettings,
+
+    // Notes each key started, so a key is released on the same channel and pitch it was pressed with,
+    //   even if the zones or transpose change while it's held
+    held: Vec<Vec<(u4, u7)>>,
+
+    // The last tuning sent to the synth. None until the first note, so a synth left retuned by the
+    //   last session gets reset
+    tuning_version: Option<u32>,
+    mpe_active: bool,
+    // Whether the synth's MTS table might be retuned. Assumed so until it's been reset
+    mts_active: bool,
+    last_mpe_channel: u8,
+}
+
+impl Keyboard {
+    pub fn new(settings: SharedSettings) -> Self {
+        Self {
+            settings,
+            held: vec![vec![]; KEYS],
+            tuning_version: None,
+            mpe_active: false,
+            mts_active: true,
+            last_mpe_channel: 0,
+        }
+    }
+
+    pub fn key_down(&mut self, key: u8, travel_time: u16) -> Vec<MidiEvent> {
+        if key as usize >= KEYS {
+            println!("Ignoring unknown key {}", key);
+            return vec![];
+        }
+
+        // We missed a key up somewhere, don't leave the old notes hanging
+        let mut events = self.release(key, u7::default());
+
+        let settings = self.settings.clone();
+        let mut settings = settings.write().unwrap();
+        settings.record_travel_time(key, travel_time);
+
+        // Tuning changes are sent just before the first note that needs them
+        if self.tuning_version != Some(settings.tuning_version()) {
+            events.extend(self.send_tuning(&settings));
+        }
+
+        for zone in settings.zones().iter().filter(|zone| zone.contains(key)) {
+            let Some(note) = note(key, settings.transpose() + zone.transpose as i16) else {
+                continue;
+            };
+            let vel = u7::new(settings.velocity(key, travel_time, zone.curve.as_deref()));
+
+            let (channel, note) = match settings.tuning() {
+                Some(tuning) if settings.mpe_active() => {
+                    let Some((note, bend)) = mpe_note(tuning, note.as_int()) else {
+                        continue;
+                    };
+                    let channel = self.next_mpe_channel();
+                    events.push(MidiEvent::new(channel, PitchBend { bend }));
+                    (channel, note)
+                }
+                // Notes the tuning leaves out are silent
+                Some(tuning) if tuning.pitch(note.as_int()).is_none() => continue,
+                _ => (zone.channel, note),
+            };
+
+            self.held[key as usize].push((channel, note));
+            events.push(MidiEvent::new(channel, NoteOn { key: note, vel }));
+        }
+
+        events
+    }
+
+    // The keyboard reset, so none of its keys are held any more. The sink releases the actual notes
+    pub fn reset(&mut self) {
+        self.held.iter_mut().for_each(Vec::clear);
+    }
+
+    fn send_tuning(&mut self, settings: &Settings) -> Vec<MidiEvent> {
+        self.tuning_version = Some(settings.tuning_version());
+
+        let mut events = vec![];
+        if settings.mpe_active() != self.mpe_active {
+            self.mpe_active = settings.mpe_active();
+            events.extend(mpe_configuration(if self.mpe_active {
+                MPE_MEMBER_CHANNELS
+            } else {
+                0
+            }));
+        }
+        let mts_active = settings.tuning_mode() == TuningMode::Mts;
+        if mts_active {
+            events.extend(mts_sysex(settings.tuning()));
+        } else if self.mts_active {
+            // Left retuned, every note bent into tune by mpe would be tuned twice
+            events.extend(mts_sysex(None));
+        }
+        self.mts_active = mts_active;
+
+        events
+    }
+
+    // Round robin over the member channels, skipping any still holding a note if we can
+    fn next_mpe_channel(&mut self) -> u4 {
+        let busy = |channel: u8| {
+            self.held
+                .iter()
+                .flatten()
+                .any(|(held, _)| held.as_int() == channel)
+        };
+
+        let channel = (1..=MPE_MEMBER_CHANNELS)
+            .map(|i| (self.last_mpe_channel + i) % MPE_MEMBER_CHANNELS)
+            .find(|&i| !busy(i + 1))
+            .unwrap_or((self.last_mpe_channel + 1) % MPE_MEMBER_CHANNELS);
+
+        self.last_mpe_channel = channel;
+        u4::new(channel + 1)
+    }
+
+    pub fn key_up(&mut self, key: u8, release_time: u16) -> Vec<MidiEvent> {
+        let vel = u7::new(self.settings.read().unwrap().release_velocity(release_time));
+        self.release(key, vel)
+    }
+
+    fn release(&mut self, key: u8, vel: u7) -> Vec<MidiEvent> {
+        let Some(held) = self.held.get_mut(key as usize) else {
+            return vec![];
+        };
+
+        held.drain(..)
+            .map(|(channel, note)| MidiEvent::new(channel, NoteOff { key: note, vel }))
+            .collect()
+    }
+}
+
+// The midi note a key plays. Microtonal tunings are applied on top of this, see tuning.rs
+fn note(key: u8, transpose: i16) -> Option<u7> {
+    // midi middle c = 60
+    // keyboard middle c = 24
+    let midi = 60 + (key as i16 - 24) + transpose;
+
+    u8::try_from(midi).ok().and_then(u7::try_from)
+}
+
+// Name of the note a key plays untransposed, for showing zone ranges
+pub fn key_name(key: u8) -> String {
+    const NAMES: [&str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+
+    match note(key, 0) {
+        Some(note) => {
+            let note = note.as_int();
+            format!("{}{}", NAMES[note as usize % 12], note as i32 / 12 - 1)
+        }
+        None => key.to_string(),
+    }
+}
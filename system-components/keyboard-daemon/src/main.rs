@@ -1,54 +1,93 @@
+use crate::arpeggiator::start_arpeggiator;
 use crate::boot_animation::do_logo_scroll;
+use crate::contacts::Contacts;
 use crate::io::{init_io, IO};
-use crate::midi_sender::start_midi_sink;
+use crate::midi_sender::{start_midi_sink, MidiEvent};
+use crate::panics::Panics;
+use crate::settings::Settings;
+use crate::shutdown::{block_shutdown_signals, shutdown, start_signal_handler};
+use crate::telemetry::Telemetry;
 use crate::user_interface::do_ui;
 use anyhow::Result;
-use crossbeam::channel::unbounded;
-use std::panic;
+use crossbeam::channel::{unbounded, Sender};
 use std::thread::JoinHandle;
 
+mod arpeggiator;
 mod boot_animation;
+mod button_actions;
+mod calibration;
+mod config;
+mod contacts;
+mod key_map;
+mod keyboard;
 mod midi_sender;
+mod panics;
+mod settings;
+mod shutdown;
+mod telemetry;
+mod tuning;
 mod user_interface;
+mod velocity;
+mod zones;
 mod io;
 
 pub type Threads = Vec<JoinHandle<Result<()>>>;
 
 fn main() -> Result<()> {
+    block_shutdown_signals()?;
+
     let mut threads = vec![];
-    let (midi_sender, midi_receiver) = unbounded();
+    let (midi_sender, arp_receiver) = unbounded();
+    let (arp_sender, midi_receiver) = unbounded();
     let (ui_sender, ui_receiver) = unbounded();
+    let settings = Settings::load().shared();
+    let panics = Panics::load().shared();
+    let contacts = Contacts::new().shared();
+    let telemetry = Telemetry::new().shared();
 
     let mut io = init_io(
         &mut threads,
+        settings.clone(),
+        panics.clone(),
+        contacts.clone(),
+        telemetry.clone(),
         midi_sender,
         ui_sender,
     )?;
-    threads.push(start_midi_sink(midi_receiver));
+    // Shutting down skips the arpeggiator and goes straight to the sink
+    let midi_sink = arp_sender.clone();
+    threads.push(start_arpeggiator(settings.clone(), arp_receiver, arp_sender));
+    threads.push(start_midi_sink(
+        midi_receiver,
+        settings.read().unwrap().stuck_note_timeout(),
+    ));
+    threads.push(start_signal_handler(midi_sink.clone()));
 
     println!("IO initialized");
 
     do_logo_scroll(io.get_display());
 
-    do_ui(io, ui_receiver, || {
-        join_finished_threads(&mut threads)
+    do_ui(io, settings, panics, contacts, telemetry, ui_receiver, || {
+        join_finished_threads(&mut threads, &midi_sink)
     });
 }
 
-fn join_finished_threads(threads: &mut Threads) {
-    let finished_threads = threads.iter().enumerate().filter_map(|(i, thread)| {
-        if thread.is_finished() {
-            return Some(i);
-        } else {
-            None
-        }
-    });
+// Any thread stopping is fatal. The panic message has already been printed by the time we join it
+fn join_finished_threads(threads: &mut Threads, midi_sink: &Sender<MidiEvent>) {
+    let finished_threads: Vec<usize> = threads
+        .iter()
+        .enumerate()
+        .filter_map(|(i, thread)| if thread.is_finished() { Some(i) } else { None })
+        .collect();
 
-    for i in finished_threads.rev() {
+    for i in finished_threads.into_iter().rev() {
         match threads.remove(i).join() {
-            Err(p) => panic::resume_unwind(p),
-            Ok(Err(e)) => panic!("{}", e),
-            Ok(Ok(_)) => return,
+            Err(_) => shutdown(midi_sink, 101),
+            Ok(Err(e)) => {
+                println!("{}", e);
+                shutdown(midi_sink, 1)
+            }
+            Ok(Ok(_)) => {}
         }
     }
 }
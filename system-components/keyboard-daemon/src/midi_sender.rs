@@ -1,39 +1,197 @@
+use std::collections::HashMap;
+use std::process;
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use crossbeam::channel::Receiver;
+use crossbeam::channel::{Receiver, RecvTimeoutError};
 use midir::os::unix::VirtualOutput;
-use midir::MidiOutput;
-use midly::live::LiveEvent;
-use midly::num::u4;
+use midir::{MidiOutput, MidiOutputConnection};
+use midly::live::{LiveEvent, SystemCommon};
+use midly::num::{u4, u7};
 use midly::MidiMessage;
 
 const MIDI_CLIENT_NAME: &str = "keystation";
 const MIDI_PORT_NAME: &str = "midi_out";
 
-pub type MidiEvent = MidiMessage;
+const CC_ALL_NOTES_OFF: u8 = 123;
 
-// Start a new thread to send midi events to the OS
-pub fn start_midi_sink(midi_channel: Receiver<MidiEvent>) -> JoinHandle<Result<()>> {
+// How often held notes are checked against the stuck note timeout
+const STUCK_NOTE_CHECK: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MidiEvent {
+    Channel { channel: u4, message: MidiMessage },
+    // Without the F0/F7 framing, the sink adds it
+    SysEx(Vec<u7>),
+
+    // Something lost track of its notes (a driver reset, say). Release everything that's still held
+    AllNotesOff,
+    // Release everything, then exit the daemon with this code
+    Shutdown(i32),
+}
+
+impl MidiEvent {
+    pub fn new(channel: u4, message: MidiMessage) -> Self {
+        Self::Channel { channel, message }
+    }
+}
+
+// Every note that's been started and not stopped, so nothing is left stuck on when things go wrong
+struct HeldNotes {
+    notes: HashMap<(u4, u7), Instant>,
+    // Channels anything has been sent on, they all get a CC123 when everything's released
+    channels: Vec<u4>,
+}
+
+impl HeldNotes {
+    fn new() -> Self {
+        Self {
+            notes: HashMap::new(),
+            channels: vec![],
+        }
+    }
+
+    fn track(&mut self, channel: u4, message: &MidiMessage) {
+        if !self.channels.contains(&channel) {
+            self.channels.push(channel);
+        }
+
+        match *message {
+            MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                self.notes.insert((channel, key), Instant::now());
+            }
+            MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                self.notes.remove(&(channel, key));
+            }
+            _ => {}
+        }
+    }
+
+    fn note_off((channel, key): (u4, u7)) -> MidiEvent {
+        MidiEvent::new(
+            channel,
+            MidiMessage::NoteOff {
+                key,
+                vel: Default::default(),
+            },
+        )
+    }
+
+    // Note offs first, for synths that ignore CC123
+    fn release_all(&mut self) -> Vec<MidiEvent> {
+        let mut events: Vec<MidiEvent> = self
+            .notes
+            .drain()
+            .map(|(note, _)| Self::note_off(note))
+            .collect();
+
+        events.extend(self.channels.iter().map(|channel| {
+            MidiEvent::new(
+                *channel,
+                MidiMessage::Controller {
+                    controller: u7::new(CC_ALL_NOTES_OFF),
+                    value: u7::default(),
+                },
+            )
+        }));
+
+        events
+    }
+
+    fn release_stuck(&mut self, timeout: Duration) -> Vec<MidiEvent> {
+        let stuck: Vec<(u4, u7)> = self
+            .notes
+            .iter()
+            .filter(|(_, started)| started.elapsed() >= timeout)
+            .map(|(note, _)| *note)
+            .collect();
+
+        stuck
+            .into_iter()
+            .map(|note| {
+                println!(
+                    "Releasing stuck note {} on channel {}",
+                    note.1,
+                    note.0.as_int() + 1
+                );
+                self.notes.remove(&note);
+                Self::note_off(note)
+            })
+            .collect()
+    }
+}
+
+// Start a new thread to send midi events to the OS.
+// Notes held longer than the stuck note timeout are released, unless it's None
+pub fn start_midi_sink(
+    midi_channel: Receiver<MidiEvent>,
+    stuck_note_timeout: Option<Duration>,
+) -> JoinHandle<Result<()>> {
     thread::spawn(move || -> Result<()> {
         let mut midi_out = MidiOutput::new(MIDI_CLIENT_NAME)?
             .create_virtual(MIDI_PORT_NAME)
             .expect("couldn't create virtual midi port");
 
-        let mut buf = [0u8; 3];
+        let mut held = HeldNotes::new();
+        let mut last_stuck_check = Instant::now();
 
-        for e in midi_channel {
-            let live_event = LiveEvent::Midi {
-                channel: u4::new(0),
-                message: e,
-            };
+        loop {
+            // Checked on a timer rather than when things are quiet, the faders can keep us busy forever
+            if let Some(timeout) = stuck_note_timeout {
+                if last_stuck_check.elapsed() >= STUCK_NOTE_CHECK {
+                    last_stuck_check = Instant::now();
+                    for e in held.release_stuck(timeout) {
+                        send(&mut midi_out, &e);
+                    }
+                }
+            }
 
-            live_event.write_std(&mut buf[..]).unwrap();
+            let e = match midi_channel.recv_timeout(STUCK_NOTE_CHECK) {
+                Ok(e) => e,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
 
-            midi_out.send(&buf).unwrap()
+            match &e {
+                MidiEvent::Channel { channel, message } => {
+                    held.track(*channel, message);
+                    send(&mut midi_out, &e);
+                }
+                MidiEvent::SysEx(_) => send(&mut midi_out, &e),
+                MidiEvent::AllNotesOff => {
+                    for e in held.release_all() {
+                        send(&mut midi_out, &e);
+                    }
+                }
+                MidiEvent::Shutdown(code) => {
+                    for e in held.release_all() {
+                        send(&mut midi_out, &e);
+                    }
+                    println!("Shutting down");
+                    process::exit(*code);
+                }
+            }
         }
 
         Ok(())
     })
 }
+
+fn send(midi_out: &mut MidiOutputConnection, e: &MidiEvent) {
+    let live_event = match e {
+        MidiEvent::Channel { channel, message } => LiveEvent::Midi {
+            channel: *channel,
+            message: *message,
+        },
+        MidiEvent::SysEx(data) => LiveEvent::Common(SystemCommon::SysEx(data)),
+        MidiEvent::AllNotesOff | MidiEvent::Shutdown(_) => return,
+    };
+
+    // Sysex can be any length
+    let mut buf = Vec::with_capacity(3);
+    live_event.write_std(&mut buf).unwrap();
+
+    midi_out.send(&buf).unwrap()
+}
@@ -0,0 +1,180 @@
+// Firmware panics from both arduinos, kept on the boot partition so they're still around to look at
+//   after the keyboard is turned off. One line per panic, oldest first:
+//
+//   <board> #<number> <file>:<line>:<column> <message>
+//
+// Line breaks in the message are escaped as \n, and backslashes as \\, so each panic stays on one line
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::config::CONFIG_DIR;
+
+const PANICS_FILE: &str = "panics.txt";
+
+// How long a new panic stays on the status line
+const SHOW_FOR: Duration = Duration::from_secs(10);
+
+pub type SharedPanics = Arc<RwLock<Panics>>;
+
+#[derive(Clone, PartialEq)]
+pub struct Panic {
+    pub board: String,
+    // Counted by the firmware, so a report sent again after the reset isn't counted twice
+    pub number: u16,
+    pub location: String,
+    pub message: String,
+}
+
+impl Panic {
+    fn parse(line: &str) -> Option<Self> {
+        let (board, rest) = line.split_once(' ')?;
+        let (number, rest) = rest.strip_prefix('#')?.split_once(' ')?;
+        let (location, message) = rest.split_once(' ').unwrap_or((rest, ""));
+
+        Some(Self {
+            board: board.to_string(),
+            number: number.parse().ok()?,
+            location: location.to_string(),
+            message: unescape(message),
+        })
+    }
+
+    #[cfg(any(test, not(feature = "simulator")))]
+    fn line(&self) -> String {
+        format!(
+            "{} #{} {} {}",
+            self.board,
+            self.number,
+            self.location,
+            escape(&self.message)
+        )
+    }
+}
+
+#[cfg(any(test, not(feature = "simulator")))]
+fn escape(message: &str) -> String {
+    message
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(message: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        // A lone backslash at the end was written by hand, keep it
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+
+    unescaped
+}
+
+// Only the drivers record panics, the simulator's arduinos don't have any
+pub struct Panics {
+    #[cfg(not(feature = "simulator"))]
+    path: PathBuf,
+    panics: Vec<Panic>,
+    last_recorded: Option<Instant>,
+}
+
+impl Panics {
+    pub fn load() -> Self {
+        Self::load_from(Path::new(CONFIG_DIR).join(PANICS_FILE))
+    }
+
+    pub fn load_from(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let panics = fs::read_to_string(path.as_path())
+            .map(|text| text.lines().filter_map(Panic::parse).collect())
+            .unwrap_or_default();
+
+        Self {
+            #[cfg(not(feature = "simulator"))]
+            path,
+            panics,
+            last_recorded: None,
+        }
+    }
+
+    pub fn shared(self) -> SharedPanics {
+        Arc::new(RwLock::new(self))
+    }
+
+    // The firmware sends its last panic again every time it boots, that's only recorded once
+    #[cfg(not(feature = "simulator"))]
+    pub fn record(&mut self, panic: Panic) -> anyhow::Result<bool> {
+        use std::io::Write;
+
+        let last = self.panics.iter().rev().find(|p| p.board == panic.board);
+        if last == Some(&panic) {
+            return Ok(false);
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", panic.line())?;
+
+        self.panics.push(panic);
+        self.last_recorded = Some(Instant::now());
+        Ok(true)
+    }
+
+    pub fn count(&self, board: &str) -> usize {
+        self.panics.iter().filter(|p| p.board == board).count()
+    }
+
+    pub fn last(&self) -> Option<&Panic> {
+        self.panics.last()
+    }
+
+    // The panic that just happened, if there's been one in the last few seconds
+    pub fn recent(&self) -> Option<&Panic> {
+        self.last_recorded
+            .filter(|at| at.elapsed() < SHOW_FOR)
+            .and(self.last())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panics_survive_a_round_trip() {
+        let panic = Panic {
+            board: "keyboard".to_string(),
+            number: 3,
+            location: "src/keybed.rs:90:13".to_string(),
+            message: "two\nlines\r\nand a \\n that isn't one".to_string(),
+        };
+
+        let line = panic.line();
+        assert!(!line.contains('\n') && !line.contains('\r'));
+        assert!(Panic::parse(&line) == Some(panic));
+    }
+
+    #[test]
+    fn messages_can_be_empty() {
+        let panic = Panic::parse("dials #1 src/main.rs:1:2").unwrap();
+
+        assert_eq!(panic.location, "src/main.rs:1:2");
+        assert_eq!(panic.message, "");
+        assert!(Panic::parse("dials 1 src/main.rs:1:2").is_none());
+    }
+}
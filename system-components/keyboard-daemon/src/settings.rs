@@ -0,0 +1,621 @@
+// Settings that can be changed from the UI while the daemon is running.
+// They're shared between the UI thread and the drivers, and saved to the boot partition whenever they change.
+
+#[cfg(not(feature = "simulator"))]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use midly::num::u4;
+
+use crate::arpeggiator::ArpSettings;
+use crate::button_actions::ButtonAction;
+use crate::calibration::{Calibration, VelocityProfile, KEYS};
+use crate::config::{read_config, write_config, Section, CONFIG_DIR};
+use crate::key_map::{KeyMap, KeyMapping};
+use crate::keyboard::key_name;
+use crate::tuning::{load_tunings, Tuning, TuningMode, MPE_MANAGER_CHANNEL};
+use crate::velocity::{calc_velocity, load_curves, VelocityCurve};
+use crate::zones::Zone;
+
+const SETTINGS_FILE: &str = "settings.conf";
+
+// Long enough for any sensibly held note, 0 turns it off
+const DEFAULT_STUCK_NOTE_TIMEOUT_S: u64 = 300;
+
+// Sent to the firmwares whenever they start. 0 scans the keybed as fast as it can
+const DEFAULT_KEYBOARD_SCAN_INTERVAL_US: u16 = 0;
+const DEFAULT_DIALS_DEBOUNCE_MS: u16 = 5;
+// Fader readings averaged, ADC steps a fader has to move, and ms between messages for each fader
+const DEFAULT_FADER_OVERSAMPLE: u16 = 4;
+const DEFAULT_FADER_HYSTERESIS: u16 = 2;
+const DEFAULT_FADER_INTERVAL_MS: u16 = 10;
+
+const MAX_OCTAVE: i8 = 4;
+const MAX_SEMITONES: i8 = 11;
+
+pub type SharedSettings = Arc<RwLock<Settings>>;
+
+pub struct Settings {
+    // Everything's loaded from and saved here, the boot partition outside of tests
+    dir: PathBuf,
+
+    // Loaded from the curve library, not saved with the rest of the settings
+    curves: Vec<VelocityCurve>,
+    velocity_curve: usize,
+    // Note off velocity comes from how fast the key is let go, through a curve of its own
+    release_curve: usize,
+
+    // Kept in its own file, it's only written by calibration
+    velocity_profile: VelocityProfile,
+    calibration: Option<Calibration>,
+    // Also in its own file, it's only written by mapping the keys
+    key_map: KeyMap,
+    key_mapping: Option<KeyMapping>,
+
+    // There's always at least one zone
+    zones: Vec<Zone>,
+
+    // Shifts the whole keyboard, on top of each zone's own transpose
+    octave: i8,
+    semitones: i8,
+
+    // Like the curves, the tunings come from their own files. None is plain 12-TET
+    tunings: Vec<Tuning>,
+    tuning: Option<usize>,
+    tuning_mode: TuningMode,
+    // Bumped on every tuning change, so the keyboard knows to send the synth the new one
+    tuning_version: u32,
+
+    arpeggiator: ArpSettings,
+
+    // What the dials board's buttons do
+    buttons: [ButtonAction; 3],
+
+    // Only read at startup, and only settable in the file
+    stuck_note_timeout_s: u64,
+    keyboard_scan_interval_us: u16,
+    dials_debounce_ms: u16,
+    fader_oversample: u16,
+    fader_hysteresis: u16,
+    fader_interval_ms: u16,
+    pedal_polarity: PedalPolarity,
+}
+
+impl Settings {
+    pub fn load() -> Self {
+        Self::load_from(CONFIG_DIR)
+    }
+
+    pub fn load_from(dir: impl Into<PathBuf>) -> Self {
+        let dir = dir.into();
+        let mut settings = Self {
+            curves: load_curves(&dir),
+            velocity_curve: 0,
+            release_curve: 0,
+            velocity_profile: VelocityProfile::load(&dir),
+            calibration: None,
+            key_map: KeyMap::load(&dir),
+            key_mapping: None,
+            zones: vec![Zone::full(u4::new(0))],
+            octave: 0,
+            semitones: 0,
+            tunings: load_tunings(&dir),
+            tuning: None,
+            tuning_mode: TuningMode::Mts,
+            tuning_version: 0,
+            arpeggiator: ArpSettings::default(),
+            buttons: ButtonAction::defaults(),
+            stuck_note_timeout_s: DEFAULT_STUCK_NOTE_TIMEOUT_S,
+            keyboard_scan_interval_us: DEFAULT_KEYBOARD_SCAN_INTERVAL_US,
+            dials_debounce_ms: DEFAULT_DIALS_DEBOUNCE_MS,
+            fader_oversample: DEFAULT_FADER_OVERSAMPLE,
+            fader_hysteresis: DEFAULT_FADER_HYSTERESIS,
+            fader_interval_ms: DEFAULT_FADER_INTERVAL_MS,
+            pedal_polarity: PedalPolarity::Auto,
+            dir,
+        };
+
+        let path = settings.dir.join(SETTINGS_FILE);
+        if path.exists() {
+            match read_config(&path) {
+                Ok(sections) => settings.apply(&sections),
+                Err(e) => println!("Couldn't load settings, using defaults: {}", e),
+            }
+        }
+
+        settings
+    }
+
+    pub fn shared(self) -> SharedSettings {
+        Arc::new(RwLock::new(self))
+    }
+
+    fn apply(&mut self, sections: &[Section]) {
+        if let Some(name) = sections[0].get("velocity_curve") {
+            self.select_velocity_curve(name);
+        }
+        if let Some(name) = sections[0].get("release_curve") {
+            self.select_release_curve(name);
+        }
+
+        match (
+            sections[0].parse_or("octave", 0i8),
+            sections[0].parse_or("semitones", 0i8),
+        ) {
+            (Ok(octave), Ok(semitones)) => {
+                self.octave = octave.clamp(-MAX_OCTAVE, MAX_OCTAVE);
+                self.semitones = semitones.clamp(-MAX_SEMITONES, MAX_SEMITONES);
+            }
+            (Err(e), _) | (_, Err(e)) => println!("Couldn't load transpose, using defaults: {}", e),
+        }
+
+        match sections[0].parse_or("stuck_note_timeout_s", DEFAULT_STUCK_NOTE_TIMEOUT_S) {
+            Ok(timeout) => self.stuck_note_timeout_s = timeout,
+            Err(e) => println!("{}", e),
+        }
+        match sections[0].parse_or(
+            "keyboard_scan_interval_us",
+            DEFAULT_KEYBOARD_SCAN_INTERVAL_US,
+        ) {
+            Ok(interval) => self.keyboard_scan_interval_us = interval,
+            Err(e) => println!("{}", e),
+        }
+        match sections[0].parse_or("dials_debounce_ms", DEFAULT_DIALS_DEBOUNCE_MS) {
+            Ok(debounce) => self.dials_debounce_ms = debounce,
+            Err(e) => println!("{}", e),
+        }
+        match sections[0].parse_or("fader_oversample", DEFAULT_FADER_OVERSAMPLE) {
+            Ok(oversample) => self.fader_oversample = oversample,
+            Err(e) => println!("{}", e),
+        }
+        match sections[0].parse_or("fader_hysteresis", DEFAULT_FADER_HYSTERESIS) {
+            Ok(hysteresis) => self.fader_hysteresis = hysteresis,
+            Err(e) => println!("{}", e),
+        }
+        match sections[0].parse_or("fader_interval_ms", DEFAULT_FADER_INTERVAL_MS) {
+            Ok(interval) => self.fader_interval_ms = interval,
+            Err(e) => println!("{}", e),
+        }
+        match sections[0]
+            .get("pedal_polarity")
+            .map(PedalPolarity::from_name)
+        {
+            Some(Ok(polarity)) => self.pedal_polarity = polarity,
+            Some(Err(e)) => println!("{}", e),
+            None => {}
+        }
+
+        if let Some(name) = sections[0].get("tuning") {
+            self.select_tuning(name);
+        }
+        match sections[0].get("tuning_mode").map(TuningMode::from_name) {
+            Some(Ok(mode)) => self.tuning_mode = mode,
+            Some(Err(e)) => println!("{}", e),
+            None => {}
+        }
+
+        if let Some(section) = sections
+            .iter()
+            .find(|section| section.name == "arpeggiator")
+        {
+            match ArpSettings::from_section(section) {
+                Ok(arpeggiator) => self.arpeggiator = arpeggiator,
+                Err(e) => println!("Couldn't load arpeggiator settings, using defaults: {}", e),
+            }
+        }
+
+        if let Some(section) = sections.iter().find(|section| section.name == "buttons") {
+            match ButtonAction::from_section(section) {
+                Ok(buttons) => self.buttons = buttons,
+                Err(e) => println!("Couldn't load button settings, using defaults: {}", e),
+            }
+        }
+
+        let zones = sections
+            .iter()
+            .filter(|section| section.name == "zone")
+            .map(Zone::from_section)
+            .collect::<Result<Vec<_>>>();
+
+        match zones {
+            Ok(zones) if !zones.is_empty() => self.zones = zones,
+            Ok(_) => {}
+            Err(e) => println!("Couldn't load zones, using defaults: {}", e),
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let mut section = Section::new("");
+        section.set("velocity_curve", &self.velocity_curve().name);
+        section.set("release_curve", &self.release_curve().name);
+        section.set("octave", self.octave);
+        section.set("semitones", self.semitones);
+        if let Some(tuning) = self.tuning() {
+            section.set("tuning", &tuning.name);
+        }
+        section.set("tuning_mode", self.tuning_mode.name());
+        section.set("stuck_note_timeout_s", self.stuck_note_timeout_s);
+        section.set("keyboard_scan_interval_us", self.keyboard_scan_interval_us);
+        section.set("dials_debounce_ms", self.dials_debounce_ms);
+        section.set("fader_oversample", self.fader_oversample);
+        section.set("fader_hysteresis", self.fader_hysteresis);
+        section.set("fader_interval_ms", self.fader_interval_ms);
+        section.set("pedal_polarity", self.pedal_polarity.name());
+
+        let mut sections = vec![section];
+        sections.push(self.arpeggiator.to_section());
+        sections.push(ButtonAction::to_section(&self.buttons));
+        sections.extend(self.zones.iter().map(Zone::to_section));
+
+        write_config(self.dir.join(SETTINGS_FILE), &sections)
+    }
+
+    // For the drivers' own files, like the fader calibration
+    #[cfg(not(feature = "simulator"))]
+    pub fn config_dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn velocity_curve(&self) -> &VelocityCurve {
+        &self.curves[self.velocity_curve]
+    }
+
+    pub fn release_curve(&self) -> &VelocityCurve {
+        &self.curves[self.release_curve]
+    }
+
+    fn find_curve(&self, name: &str) -> Option<usize> {
+        let found = self.curves.iter().position(|curve| curve.name == name);
+        if found.is_none() {
+            println!("Unknown velocity curve '{}'", name);
+        }
+        found
+    }
+
+    fn select_velocity_curve(&mut self, name: &str) {
+        if let Some(i) = self.find_curve(name) {
+            self.velocity_curve = i;
+        }
+    }
+
+    fn select_release_curve(&mut self, name: &str) {
+        if let Some(i) = self.find_curve(name) {
+            self.release_curve = i;
+        }
+    }
+
+    // The library is re-read before stepping through it so edits show up without a restart.
+    //   Both selections are kept by name, the indexes may have moved
+    fn reload_curves(&mut self) {
+        let velocity = self.velocity_curve().name.clone();
+        let release = self.release_curve().name.clone();
+        self.curves = load_curves(&self.dir);
+        self.velocity_curve = 0;
+        self.release_curve = 0;
+        self.select_velocity_curve(&velocity);
+        self.select_release_curve(&release);
+    }
+
+    pub fn cycle_velocity_curve(&mut self, step: i32) {
+        self.reload_curves();
+        self.velocity_curve = cycle(self.velocity_curve, self.curves.len(), step);
+    }
+
+    pub fn cycle_release_curve(&mut self, step: i32) {
+        self.reload_curves();
+        self.release_curve = cycle(self.release_curve, self.curves.len(), step);
+    }
+
+    // Zones can pick a curve by name, anything not in the library falls back to the global curve
+    pub fn velocity(&self, key: u8, travel_time: u16, curve: Option<&str>) -> u8 {
+        let curve = curve
+            .and_then(|name| self.curves.iter().find(|curve| curve.name == name))
+            .unwrap_or(self.velocity_curve());
+
+        calc_velocity(
+            travel_time,
+            curve,
+            self.velocity_profile.key_window(key as usize),
+        )
+    }
+
+    // The per key windows are measured on the way down, so releases use the curve's own window
+    pub fn release_velocity(&self, release_time: u16) -> u8 {
+        calc_velocity(release_time, self.release_curve(), None)
+    }
+
+    pub fn record_travel_time(&mut self, key: u8, travel_time: u16) {
+        if let Some(calibration) = &mut self.calibration {
+            calibration.record(key as usize, travel_time);
+        }
+    }
+
+    pub fn calibration_status(&self) -> String {
+        match &self.calibration {
+            None => "off".to_string(),
+            Some(calibration) => format!("{}/{} keys", calibration.keys_done(), KEYS),
+        }
+    }
+
+    // Right starts calibrating, then right again keeps the results. Left throws them away
+    pub fn step_calibration(&mut self, step: i32) {
+        match self.calibration.take() {
+            None if step > 0 => self.calibration = Some(Calibration::new()),
+            Some(calibration) if step > 0 => {
+                self.velocity_profile = calibration.finish(&self.velocity_profile);
+                if let Err(e) = self.velocity_profile.save(&self.dir) {
+                    println!("Couldn't save velocity profile: {}", e);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Only the keyboard driver maps keys, the simulator plays them as they are
+    #[cfg(not(feature = "simulator"))]
+    pub fn key_map(&self) -> &KeyMap {
+        &self.key_map
+    }
+
+    // The keyboard's own copy, when the Pi doesn't have one. Not saved, the keyboard still has it
+    #[cfg(not(feature = "simulator"))]
+    pub fn use_key_map(&mut self, key_map: KeyMap) {
+        self.key_map = key_map;
+    }
+
+    // While mapping, the keyboard's slots are recorded instead of played
+    #[cfg(not(feature = "simulator"))]
+    pub fn mapping_keys(&self) -> bool {
+        self.key_mapping.is_some()
+    }
+
+    #[cfg(not(feature = "simulator"))]
+    pub fn record_key_slot(&mut self, slot: u8) {
+        if let Some(mapping) = &mut self.key_mapping {
+            mapping.record(slot);
+        }
+    }
+
+    pub fn key_mapping_status(&self) -> String {
+        match &self.key_mapping {
+            None => "off".to_string(),
+            Some(mapping) => match mapping.next_key() {
+                Some(key) => format!("press {}", key_name(key)),
+                None => "done".to_string(),
+            },
+        }
+    }
+
+    // Right starts mapping, then right again keeps the map once every key's been pressed. Left
+    //   throws it away
+    pub fn step_key_mapping(&mut self, step: i32) {
+        match self.key_mapping.take() {
+            None if step > 0 => self.key_mapping = Some(KeyMapping::new()),
+            Some(mapping) if step > 0 => match mapping.finish() {
+                Some(key_map) => {
+                    if let Err(e) = key_map.save(&self.dir) {
+                        println!("Couldn't save key map: {}", e);
+                    }
+                    self.key_map = key_map;
+                }
+                None => self.key_mapping = Some(mapping),
+            },
+            _ => {}
+        }
+    }
+
+    pub fn octave(&self) -> i8 {
+        self.octave
+    }
+
+    pub fn semitones(&self) -> i8 {
+        self.semitones
+    }
+
+    // Total shift applied to every key, in semitones
+    pub fn transpose(&self) -> i16 {
+        self.octave as i16 * 12 + self.semitones as i16
+    }
+
+    pub fn step_octave(&mut self, step: i32) {
+        self.octave =
+            (self.octave as i32 + step).clamp(-MAX_OCTAVE as i32, MAX_OCTAVE as i32) as i8;
+    }
+
+    pub fn step_semitones(&mut self, step: i32) {
+        self.semitones =
+            (self.semitones as i32 + step).clamp(-MAX_SEMITONES as i32, MAX_SEMITONES as i32) as i8;
+    }
+
+    pub fn tuning(&self) -> Option<&Tuning> {
+        self.tunings.get(self.tuning?)
+    }
+
+    pub fn tuning_mode(&self) -> TuningMode {
+        self.tuning_mode
+    }
+
+    pub fn tuning_version(&self) -> u32 {
+        self.tuning_version
+    }
+
+    // Notes are spread over channels and bent into tune, instead of going out on their zone's channel
+    pub fn mpe_active(&self) -> bool {
+        self.tuning.is_some() && self.tuning_mode == TuningMode::Mpe
+    }
+
+    fn select_tuning(&mut self, name: &str) {
+        match self.tunings.iter().position(|tuning| tuning.name == name) {
+            Some(i) => self.tuning = Some(i),
+            None => println!("Unknown tuning '{}'", name),
+        }
+    }
+
+    // Steps through 12-TET followed by every tuning. The tunings are re-read first, like the curve library
+    pub fn cycle_tuning(&mut self, step: i32) {
+        let current = self.tuning().map(|tuning| tuning.name.clone());
+        self.tunings = load_tunings(&self.dir);
+        self.tuning = None;
+        if let Some(name) = current {
+            self.select_tuning(&name);
+        }
+
+        let index = self.tuning.map_or(0, |i| i + 1);
+        self.tuning = match cycle(index, self.tunings.len() + 1, step) {
+            0 => None,
+            i => Some(i - 1),
+        };
+        self.tuning_version += 1;
+    }
+
+    pub fn toggle_tuning_mode(&mut self, _step: i32) {
+        self.tuning_mode = match self.tuning_mode {
+            TuningMode::Mts => TuningMode::Mpe,
+            TuningMode::Mpe => TuningMode::Mts,
+        };
+        self.tuning_version += 1;
+    }
+
+    pub fn arpeggiator(&self) -> ArpSettings {
+        self.arpeggiator
+    }
+
+    pub fn arpeggiator_mut(&mut self) -> &mut ArpSettings {
+        &mut self.arpeggiator
+    }
+
+    pub fn stuck_note_timeout(&self) -> Option<Duration> {
+        match self.stuck_note_timeout_s {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    // Firmware parameters, the simulator has no firmware to send them to
+    #[cfg(not(feature = "simulator"))]
+    pub fn keyboard_scan_interval_us(&self) -> u16 {
+        self.keyboard_scan_interval_us
+    }
+
+    #[cfg(not(feature = "simulator"))]
+    pub fn dials_debounce_ms(&self) -> u16 {
+        self.dials_debounce_ms
+    }
+
+    #[cfg(not(feature = "simulator"))]
+    pub fn fader_oversample(&self) -> u16 {
+        self.fader_oversample
+    }
+
+    #[cfg(not(feature = "simulator"))]
+    pub fn fader_hysteresis(&self) -> u16 {
+        self.fader_hysteresis
+    }
+
+    #[cfg(not(feature = "simulator"))]
+    pub fn fader_interval_ms(&self) -> u16 {
+        self.fader_interval_ms
+    }
+
+    #[cfg(not(feature = "simulator"))]
+    pub fn pedal_polarity(&self) -> PedalPolarity {
+        self.pedal_polarity
+    }
+
+    pub fn buttons(&self) -> &[ButtonAction; 3] {
+        &self.buttons
+    }
+
+    pub fn buttons_mut(&mut self) -> &mut [ButtonAction; 3] {
+        &mut self.buttons
+    }
+
+    pub fn zones(&self) -> &[Zone] {
+        &self.zones
+    }
+
+    pub fn zone_mut(&mut self, zone: usize) -> Option<&mut Zone> {
+        self.zones.get_mut(zone)
+    }
+
+    // New zones start on the next free channel, so they don't layer onto an existing zone's sound
+    pub fn add_zone(&mut self) {
+        let channel = (0..16)
+            .map(u4::new)
+            .find(|channel| self.zones.iter().all(|zone| zone.channel != *channel))
+            .unwrap_or_default();
+
+        self.zones.push(Zone::full(channel));
+    }
+
+    pub fn remove_zone(&mut self, zone: usize) {
+        if self.zones.len() > 1 && zone < self.zones.len() {
+            self.zones.remove(zone);
+        }
+    }
+
+    // Steps through "global" followed by every curve in the library
+    pub fn cycle_zone_curve(&mut self, zone: usize, step: i32) {
+        let names: Vec<Option<String>> = [None]
+            .into_iter()
+            .chain(self.curves.iter().map(|curve| Some(curve.name.clone())))
+            .collect();
+
+        if let Some(zone) = self.zones.get_mut(zone) {
+            let current = names
+                .iter()
+                .position(|name| *name == zone.curve)
+                .unwrap_or(0);
+            zone.curve = names[cycle(current, names.len(), step)].clone();
+        }
+    }
+
+    // Every channel some zone plays on. Controllers like the faders are sent to all of them
+    pub fn zone_channels(&self) -> Vec<u4> {
+        // With mpe, the manager channel's controllers apply to every note
+        if self.mpe_active() {
+            return vec![MPE_MANAGER_CHANNEL];
+        }
+
+        let mut channels: Vec<u4> = self.zones.iter().map(|zone| zone.channel).collect();
+        channels.sort();
+        channels.dedup();
+
+        channels
+    }
+}
+
+fn cycle(index: usize, len: usize, step: i32) -> usize {
+    (index as i64 + step as i64).rem_euclid(len as i64) as usize
+}
+
+// Which way round the sustain pedal's switch is. Only settable in the file
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PedalPolarity {
+    // Whatever the pedal reads as the dials start is released
+    Auto,
+    NormallyOpen,
+    NormallyClosed,
+}
+
+impl PedalPolarity {
+    pub fn name(self) -> &'static str {
+        match self {
+            PedalPolarity::Auto => "auto",
+            PedalPolarity::NormallyOpen => "normally_open",
+            PedalPolarity::NormallyClosed => "normally_closed",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "auto" => Ok(PedalPolarity::Auto),
+            "normally_open" => Ok(PedalPolarity::NormallyOpen),
+            "normally_closed" => Ok(PedalPolarity::NormallyClosed),
+            other => bail!("unknown pedal polarity '{}'", other),
+        }
+    }
+}
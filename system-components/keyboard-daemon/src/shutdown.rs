@@ -0,0 +1,48 @@
+// Getting out without leaving notes stuck on. Every way out goes through the midi sink, which releases
+//   whatever is still held before exiting the process.
+
+use std::process;
+use std::thread;
+use std::thread::{sleep, JoinHandle};
+use std::time::Duration;
+
+use anyhow::Result;
+use crossbeam::channel::Sender;
+use nix::sys::signal::{SigSet, Signal};
+
+use crate::midi_sender::MidiEvent;
+
+// How long the sink gets to release everything before we give up on it
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn shutdown_signals() -> SigSet {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGINT);
+
+    signals
+}
+
+// Must be called before any other threads are started, they inherit the mask.
+// Otherwise the signal could land on any thread and kill the process the normal way
+pub fn block_shutdown_signals() -> Result<()> {
+    shutdown_signals().thread_block()?;
+    Ok(())
+}
+
+pub fn start_signal_handler(midi_sink: Sender<MidiEvent>) -> JoinHandle<Result<()>> {
+    thread::spawn(move || {
+        let signal = shutdown_signals().wait()?;
+        println!("Received {}", signal);
+
+        shutdown(&midi_sink, 0)
+    })
+}
+
+pub fn shutdown(midi_sink: &Sender<MidiEvent>, code: i32) -> ! {
+    // The sink may be what failed, in which case there's nobody to release the notes anyway
+    _ = midi_sink.send(MidiEvent::Shutdown(code));
+    sleep(SHUTDOWN_TIMEOUT);
+
+    process::exit(code)
+}
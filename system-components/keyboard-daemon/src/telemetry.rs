@@ -0,0 +1,70 @@
+// The latest timing report from each arduino's main loop (see Message::Telemetry), for the menu and
+//   anyone measuring a firmware change. Only kept in memory, a fresh one arrives every second
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+pub type SharedTelemetry = Arc<RwLock<Telemetry>>;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LoopTiming {
+    pub scans_per_second: u32,
+    pub worst_loop_us: u32,
+    pub watchdog_margin_us: u32,
+    pub tx_waits: u16,
+}
+
+pub struct Telemetry {
+    boards: BTreeMap<&'static str, LoopTiming>,
+}
+
+impl Telemetry {
+    pub fn new() -> Self {
+        Self {
+            boards: BTreeMap::new(),
+        }
+    }
+
+    pub fn shared(self) -> SharedTelemetry {
+        Arc::new(RwLock::new(self))
+    }
+
+    // The simulator has no firmware to time
+    #[cfg(any(test, not(feature = "simulator")))]
+    pub fn record(&mut self, board: &'static str, timing: LoopTiming) {
+        self.boards.insert(board, timing);
+    }
+
+    // None until the board's sent its first report
+    pub fn get(&self, board: &str) -> Option<&LoopTiming> {
+        self.boards.get(board)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing(scans_per_second: u32) -> LoopTiming {
+        LoopTiming {
+            scans_per_second,
+            worst_loop_us: 400,
+            watchdog_margin_us: 499_600,
+            tx_waits: 0,
+        }
+    }
+
+    #[test]
+    fn boards_keep_their_latest_report() {
+        let mut telemetry = Telemetry::new();
+        assert_eq!(telemetry.get("keyboard"), None);
+
+        telemetry.record("keyboard", timing(5000));
+        telemetry.record("dials", timing(900));
+        telemetry.record("keyboard", timing(4000));
+
+        assert_eq!(telemetry.get("keyboard"), Some(&timing(4000)));
+        assert_eq!(telemetry.get("dials"), Some(&timing(900)));
+        assert_eq!(telemetry.get("neither"), None);
+    }
+}
@@ -0,0 +1,512 @@
+// Microtonal tunings, loaded from Scala files in the tunings directory on the boot partition.
+// Every `name.scl` is a tuning. A `name.kbm` next to it maps the scale onto midi notes, otherwise the
+//   scale is laid out from middle c, with A4 at 440hz.
+//
+// Scala's formats are documented at https://www.huygens-fokker.org/scala/scl_format.html
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use midly::num::{u4, u7};
+use midly::{MidiMessage, PitchBend};
+
+use crate::midi_sender::MidiEvent;
+
+const TUNINGS_DIR: &str = "tunings";
+
+// Both file formats use '!' for comment lines
+fn data_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim_end_matches('\r')))
+        .filter(|(_, line)| !line.trim_start().starts_with('!'))
+}
+
+// Numeric fields are the first word on their line, anything after it is a comment
+fn first_word(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or("")
+}
+
+pub struct Scale {
+    // Every degree above the root, in cents. The last one is the period the scale repeats at
+    pitches: Vec<f64>,
+}
+
+impl Scale {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut lines = data_lines(text);
+
+        // The description can legitimately be an empty line, it's only there for people
+        lines.next().ok_or_else(|| anyhow!("missing description"))?;
+
+        let (line, count) = lines
+            .next()
+            .ok_or_else(|| anyhow!("missing number of notes"))?;
+        let count: usize = first_word(count)
+            .parse()
+            .map_err(|_| anyhow!("line {}: '{}' isn't a number of notes", line, count.trim()))?;
+        if count == 0 {
+            bail!("line {}: a scale needs at least one note", line);
+        }
+
+        let pitches = lines
+            .filter(|(_, line)| !line.trim().is_empty())
+            .take(count)
+            .map(|(line, pitch)| {
+                parse_pitch(first_word(pitch)).map_err(|e| anyhow!("line {}: {}", line, e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if pitches.len() != count {
+            bail!("expected {} notes but only found {}", count, pitches.len());
+        }
+        if pitches[count - 1] <= 0.0 {
+            bail!("the last note is the period of the scale, it must be above the root");
+        }
+
+        Ok(Self { pitches })
+    }
+
+    fn len(&self) -> i32 {
+        self.pitches.len() as i32
+    }
+
+    // Cents above the root for any degree, including ones outside the first period
+    fn cents(&self, degree: i32) -> f64 {
+        let period = degree.div_euclid(self.len());
+        let step = degree.rem_euclid(self.len());
+
+        let within_period = match step {
+            0 => 0.0,
+            step => self.pitches[step as usize - 1],
+        };
+
+        period as f64 * self.pitches[self.pitches.len() - 1] + within_period
+    }
+}
+
+// Pitches with a '.' are in cents, anything else is a ratio like '3/2' or just '2'
+fn parse_pitch(pitch: &str) -> Result<f64> {
+    if pitch.is_empty() {
+        bail!("missing pitch");
+    }
+
+    if pitch.contains('.') {
+        return pitch
+            .parse()
+            .map_err(|_| anyhow!("'{}' isn't a pitch in cents", pitch));
+    }
+
+    let (numerator, denominator) = pitch.split_once('/').unwrap_or((pitch, "1"));
+    let parse = |n: &str| {
+        n.parse::<u64>()
+            .map_err(|_| anyhow!("'{}' isn't a ratio", pitch))
+    };
+    let (numerator, denominator) = (parse(numerator)?, parse(denominator)?);
+
+    if numerator == 0 || denominator == 0 {
+        bail!("ratio '{}' must be above 0", pitch);
+    }
+
+    Ok(1200.0 * (numerator as f64 / denominator as f64).log2())
+}
+
+pub struct KeyboardMapping {
+    first_note: u8,
+    last_note: u8,
+    // The note the scale's root is on
+    middle_note: u8,
+    reference_note: u8,
+    reference_frequency: f64,
+    // How many scale degrees one repeat of the mapping moves by
+    octave_degree: i32,
+    // Scale degree for each note in one repeat. Empty means every note is the next degree
+    map: Vec<Option<i32>>,
+}
+
+impl KeyboardMapping {
+    // Middle c is the root, and A4 is 440hz like normal
+    pub fn standard() -> Self {
+        Self {
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            reference_note: 69,
+            reference_frequency: 440.0,
+            octave_degree: 0,
+            map: vec![],
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut lines = data_lines(text).filter(|(_, line)| !line.trim().is_empty());
+
+        let mut field = |name: &str| {
+            lines
+                .next()
+                .map(|(line, value)| (line, first_word(value).to_string()))
+                .ok_or_else(|| anyhow!("missing {}", name))
+        };
+        fn number<T: std::str::FromStr>(name: &str, (line, value): (usize, String)) -> Result<T> {
+            value
+                .parse()
+                .map_err(|_| anyhow!("line {}: '{}' isn't a valid {}", line, value, name))
+        }
+
+        let map_size: usize = number("map size", field("map size")?)?;
+        let mut mapping = Self {
+            first_note: number("note", field("first note")?)?,
+            last_note: number("note", field("last note")?)?,
+            middle_note: number("note", field("middle note")?)?,
+            reference_note: number("note", field("reference note")?)?,
+            reference_frequency: number("frequency", field("reference frequency")?)?,
+            octave_degree: number("degree", field("octave degree")?)?,
+            map: vec![],
+        };
+
+        for _ in 0..map_size {
+            let (line, value) = field("mapping entry")?;
+            mapping.map.push(match value.as_str() {
+                "x" => None,
+                _ => Some(number("degree", (line, value))?),
+            });
+        }
+
+        for note in [
+            mapping.first_note,
+            mapping.last_note,
+            mapping.middle_note,
+            mapping.reference_note,
+        ] {
+            if note > 127 {
+                bail!("note {} is outside the midi range", note);
+            }
+        }
+        if !mapping.reference_frequency.is_finite() || mapping.reference_frequency <= 0.0 {
+            bail!("reference frequency must be above 0");
+        }
+
+        Ok(mapping)
+    }
+
+    // Which scale degree a note plays, if any
+    fn degree(&self, note: u8, scale: &Scale) -> Option<i32> {
+        if !(self.first_note..=self.last_note).contains(&note) {
+            return None;
+        }
+
+        let offset = note as i32 - self.middle_note as i32;
+        if self.map.is_empty() {
+            return Some(offset);
+        }
+
+        // An octave degree of 0 means one repeat of the map is one period of the scale
+        let octave_degree = match self.octave_degree {
+            0 => scale.len(),
+            degree => degree,
+        };
+
+        let size = self.map.len() as i32;
+        let degree = self.map[offset.rem_euclid(size) as usize]?;
+        // A huge octave degree from the file shouldn't take the driver down with it
+        offset
+            .div_euclid(size)
+            .checked_mul(octave_degree)?
+            .checked_add(degree)
+    }
+}
+
+pub struct Tuning {
+    pub name: String,
+    scale: Scale,
+    mapping: KeyboardMapping,
+}
+
+impl Tuning {
+    fn load(scl: &Path) -> Result<Self> {
+        let name = scl
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| anyhow!("bad tuning file name"))?
+            .to_string();
+
+        let scale = Scale::parse(&fs::read_to_string(scl)?)
+            .with_context(|| format!("{}", scl.display()))?;
+
+        let kbm = scl.with_extension("kbm");
+        let mapping = if kbm.exists() {
+            KeyboardMapping::parse(&fs::read_to_string(&kbm)?)
+                .with_context(|| format!("{}", kbm.display()))?
+        } else {
+            KeyboardMapping::standard()
+        };
+
+        // Everything is tuned relative to the reference, so it has to actually be in the scale
+        if mapping.degree(mapping.reference_note, &scale).is_none() {
+            bail!("{}: the reference note isn't mapped", kbm.display());
+        }
+
+        Ok(Self {
+            name,
+            scale,
+            mapping,
+        })
+    }
+
+    // Frequency of a midi note in hz. Notes the mapping leaves out don't play
+    pub fn frequency(&self, note: u8) -> Option<f64> {
+        let degree = self.mapping.degree(note, &self.scale)?;
+        let reference = self
+            .mapping
+            .degree(self.mapping.reference_note, &self.scale)?;
+
+        let cents = self.scale.cents(degree) - self.scale.cents(reference);
+        Some(self.mapping.reference_frequency * (cents / 1200.0).exp2())
+    }
+
+    // Same as frequency, but as a fractional midi note number where 69.0 is A4 at 440hz
+    pub fn pitch(&self, note: u8) -> Option<f64> {
+        self.frequency(note)
+            .map(|frequency| 69.0 + 12.0 * (frequency / 440.0).log2())
+    }
+}
+
+// Broken files are skipped with an error, so one bad tuning doesn't take the rest with it
+pub fn load_tunings(dir: &Path) -> Vec<Tuning> {
+    let Ok(entries) = fs::read_dir(dir.join(TUNINGS_DIR)) else {
+        return vec![];
+    };
+
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "scl"))
+        .collect();
+    paths.sort();
+
+    paths
+        .iter()
+        .filter_map(|path| match Tuning::load(path) {
+            Ok(tuning) => Some(tuning),
+            Err(e) => {
+                println!("Couldn't load tuning: {:#}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+// How a tuning reaches the synth
+#[derive(Copy, Clone, PartialEq)]
+pub enum TuningMode {
+    // Retune the synth's notes with midi tuning standard sysex, notes are sent as normal
+    Mts,
+    // Every note gets its own channel and is bent into tune, for synths that don't understand mts
+    Mpe,
+}
+
+impl TuningMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            TuningMode::Mts => "mts",
+            TuningMode::Mpe => "mpe",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "mts" => Ok(TuningMode::Mts),
+            "mpe" => Ok(TuningMode::Mpe),
+            other => bail!("unknown tuning mode '{}'", other),
+        }
+    }
+}
+
+// Mpe lower zone: channel 1 is the manager, the rest play one note each
+pub const MPE_MANAGER_CHANNEL: u4 = u4::new(0);
+pub const MPE_MEMBER_CHANNELS: u8 = 15;
+
+// The mpe default, wide enough to bend any note to any other
+const MPE_BEND_RANGE: u8 = 48;
+
+const CC_RPN_MSB: u8 = 101;
+const CC_RPN_LSB: u8 = 100;
+const CC_DATA_ENTRY: u8 = 6;
+const RPN_BEND_RANGE: u8 = 0;
+const RPN_MPE_CONFIGURATION: u8 = 6;
+
+// Real time single note tuning change, for every midi note.
+// The note count is only 7 bits, so it takes two messages
+pub fn mts_sysex(tuning: Option<&Tuning>) -> Vec<MidiEvent> {
+    let notes: Vec<u8> = (0..=127).collect();
+
+    notes
+        .chunks(64)
+        .map(|notes| {
+            // Universal real time, all devices, tuning standard, single note change, program 0
+            let mut data: Vec<u8> = vec![0x7F, 0x7F, 0x08, 0x02, 0x00, notes.len() as u8];
+
+            for &note in notes {
+                let pitch = match tuning {
+                    Some(tuning) => tuning.pitch(note),
+                    None => Some(note as f64),
+                };
+                data.push(note);
+                data.extend(mts_pitch(pitch));
+            }
+
+            MidiEvent::SysEx(data.into_iter().map(u7::new).collect())
+        })
+        .collect()
+}
+
+// Semitone, then a 14 bit fraction of a semitone
+fn mts_pitch(pitch: Option<f64>) -> [u8; 3] {
+    let Some(pitch) = pitch else {
+        // Reserved to mean "leave this note alone"
+        return [0x7F, 0x7F, 0x7F];
+    };
+
+    // Capping at exactly 127 keeps clear of the reserved value
+    let fraction = (pitch.clamp(0.0, 127.0) * 16384.0).round() as u32;
+
+    [
+        (fraction >> 14) as u8,
+        (fraction >> 7) as u8 & 0x7F,
+        fraction as u8 & 0x7F,
+    ]
+}
+
+// Tell the synth how many member channels there are (0 turns mpe off) and how far they bend
+pub fn mpe_configuration(members: u8) -> Vec<MidiEvent> {
+    let mut events = rpn(MPE_MANAGER_CHANNEL, RPN_MPE_CONFIGURATION, members);
+    for channel in 1..=members {
+        events.extend(rpn(u4::new(channel), RPN_BEND_RANGE, MPE_BEND_RANGE));
+    }
+
+    events
+}
+
+fn rpn(channel: u4, parameter: u8, value: u8) -> Vec<MidiEvent> {
+    [
+        (CC_RPN_MSB, 0),
+        (CC_RPN_LSB, parameter),
+        (CC_DATA_ENTRY, value),
+    ]
+    .into_iter()
+    .map(|(controller, value)| {
+        MidiEvent::new(
+            channel,
+            MidiMessage::Controller {
+                controller: u7::new(controller),
+                value: u7::new(value),
+            },
+        )
+    })
+    .collect()
+}
+
+// The closest midi note to a tuned note, and how far to bend it the rest of the way
+pub fn mpe_note(tuning: &Tuning, note: u8) -> Option<(u7, PitchBend)> {
+    let pitch = tuning.pitch(note)?;
+    let nearest = pitch.round().clamp(0.0, 127.0);
+    let bend = ((pitch - nearest) / MPE_BEND_RANGE as f64).clamp(-1.0, 1.0);
+
+    Some((u7::new(nearest as u8), PitchBend::from_f64(bend)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TWELVE_TET: &str = "! 12tet.scl\n\
+        Twelve equal steps\n\
+        12\n\
+        100.0\n200.0\n300.0\n400.0\n500.0\n600.0\n700.0\n800.0\n900.0\n1000.0\n1100.0\n\
+        2/1\n";
+
+    fn tuning(scl: &str, kbm: Option<&str>) -> Tuning {
+        Tuning {
+            name: "test".to_string(),
+            scale: Scale::parse(scl).unwrap(),
+            mapping: kbm.map_or_else(KeyboardMapping::standard, |kbm| {
+                KeyboardMapping::parse(kbm).unwrap()
+            }),
+        }
+    }
+
+    #[test]
+    fn pitches_are_cents_or_ratios() {
+        let scale = Scale::parse("just fifth\n 2 ! notes\n3/2 the fifth\n1200.0\n").unwrap();
+
+        assert!((scale.pitches[0] - 701.955).abs() < 0.001);
+        assert_eq!(scale.pitches[1], 1200.0);
+        assert_eq!(scale.cents(-1), -1200.0 + scale.pitches[0]);
+        assert_eq!(scale.cents(4), 2400.0);
+    }
+
+    #[test]
+    fn bad_scales_are_rejected() {
+        for text in [
+            "",
+            "no count\n",
+            "bad count\nlots\n",
+            "empty\n0\n",
+            "too few\n3\n100.0\n2/1\n",
+            "zero ratio\n1\n0/1\n",
+            "bad ratio\n1\n3/x\n",
+            "no period\n1\n-100.0\n",
+        ] {
+            assert!(Scale::parse(text).is_err(), "{:?} parsed", text);
+        }
+    }
+
+    #[test]
+    fn twelve_tet_is_normal_tuning() {
+        let tuning = tuning(TWELVE_TET, None);
+
+        assert!((tuning.frequency(69).unwrap() - 440.0).abs() < 1e-9);
+        assert!((tuning.pitch(60).unwrap() - 60.0).abs() < 1e-9);
+        assert!((tuning.pitch(127).unwrap() - 127.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn mappings_place_and_skip_notes() {
+        // White keys only, a whole tone apart, C4 at 256hz
+        let kbm = "! white.kbm\n\
+            12\n0\n127\n60\n60\n256.0\n7\n\
+            0\nx\n1\nx\n2\n3\nx\n4\nx\n5\nx\n6\n";
+        let scale = "whole tones\n7\n200.0\n400.0\n600.0\n800.0\n1000.0\n1100.0\n2/1\n";
+        let tuning = tuning(scale, Some(kbm));
+
+        assert_eq!(tuning.frequency(60), Some(256.0));
+        assert_eq!(tuning.frequency(61), None);
+        assert!((tuning.frequency(62).unwrap() - 256.0 * (200.0f64 / 1200.0).exp2()).abs() < 1e-9);
+        assert_eq!(tuning.frequency(72), Some(512.0));
+        assert_eq!(tuning.frequency(48), Some(128.0));
+    }
+
+    #[test]
+    fn bad_mappings_are_rejected() {
+        let kbm = |fields: &str| KeyboardMapping::parse(fields);
+
+        assert!(kbm("0\n0\n127\n60\n69\n440.0\n0\n").is_ok());
+        assert!(kbm("0\n0\n127\n60\n69\n").is_err());
+        assert!(kbm("0\n0\n128\n60\n69\n440.0\n0\n").is_err());
+        assert!(kbm("0\n0\n127\n60\n69\n0.0\n0\n").is_err());
+        assert!(kbm("0\n0\n127\n60\n69\nnan\n0\n").is_err());
+        assert!(kbm("0\n0\n127\n60\n69\ninf\n0\n").is_err());
+        assert!(kbm("2\n0\n127\n60\n69\n440.0\n0\n0\n").is_err());
+        assert!(kbm("1\n0\n127\n60\n69\n440.0\n0\ny\n").is_err());
+    }
+
+    #[test]
+    fn huge_octave_degrees_leave_notes_silent() {
+        let kbm = "1\n0\n127\n60\n60\n440.0\n2147483647\n0\n";
+        let tuning = tuning(TWELVE_TET, Some(kbm));
+
+        assert_eq!(tuning.frequency(60), Some(440.0));
+        assert_eq!(tuning.frequency(62), None);
+        assert_eq!(tuning.frequency(0), None);
+    }
+}
@@ -1,13 +1,20 @@
 use std::time::Duration;
 
+use crate::button_actions::DIALS_BUTTONS;
+use crate::contacts::{Contacts, SharedContacts};
 use crate::io::{Display, IO};
+use crate::keyboard::key_name;
+use crate::panics::{Panics, SharedPanics};
+use crate::settings::{Settings, SharedSettings};
+use crate::telemetry::{SharedTelemetry, Telemetry};
 use crossbeam::channel::{select_biased, tick, Receiver};
+use embedded_graphics::mono_font::ascii::FONT_6X10;
+use embedded_graphics::mono_font::MonoTextStyle;
 use embedded_graphics::pixelcolor::BinaryColor;
 use embedded_graphics::prelude::*;
-use embedded_graphics::primitives::{
-    Circle, PrimitiveStyle, PrimitiveStyleBuilder, Rectangle, StyledDrawable, Triangle,
-};
+use embedded_graphics::text::{Baseline, Text};
 
+#[derive(Copy, Clone, PartialEq)]
 pub enum Button {
     DpadUp,
     DpadDown,
@@ -23,61 +30,308 @@ pub enum UIEvent {
     Up(Button),
 }
 
+// Rows of the settings menu. Up/down picks a row, left/right changes its value.
+// Zones add their own rows, so the menu is rebuilt from the settings every time it's used
+#[derive(Copy, Clone)]
+enum Row {
+    Octave,
+    Semitones,
+    VelocityCurve,
+    ReleaseCurve,
+    Calibration,
+    KeyMap,
+    Tuning,
+    TuningMode,
+    Arp,
+    ArpOrder,
+    ArpOctaves,
+    ArpGate,
+    ArpLatch,
+    ArpTempo,
+    DialsButton(usize),
+    ZoneLow(usize),
+    ZoneHigh(usize),
+    ZoneChannel(usize),
+    ZoneTranspose(usize),
+    ZoneCurve(usize),
+    RemoveZone(usize),
+    AddZone,
+    Panics,
+    LastPanic,
+    // One of TIMED_BOARDS
+    Timing(usize),
+    Contacts,
+    // The nth worst key for bad contact readings
+    ContactKey(usize),
+}
+
+impl Row {
+    fn all(settings: &Settings, contacts: &Contacts) -> Vec<Row> {
+        let mut rows = vec![
+            Row::Octave,
+            Row::Semitones,
+            Row::VelocityCurve,
+            Row::ReleaseCurve,
+            Row::Calibration,
+            Row::KeyMap,
+            Row::Tuning,
+            Row::TuningMode,
+            Row::Arp,
+        ];
+        // The rest of the arp's settings only show while it's on
+        if settings.arpeggiator().enabled {
+            rows.extend([
+                Row::ArpOrder,
+                Row::ArpOctaves,
+                Row::ArpGate,
+                Row::ArpLatch,
+                Row::ArpTempo,
+            ]);
+        }
+        rows.extend((0..DIALS_BUTTONS.len()).map(Row::DialsButton));
+        for zone in 0..settings.zones().len() {
+            rows.extend([
+                Row::ZoneLow(zone),
+                Row::ZoneHigh(zone),
+                Row::ZoneChannel(zone),
+                Row::ZoneTranspose(zone),
+                Row::ZoneCurve(zone),
+            ]);
+            if settings.zones().len() > 1 {
+                rows.push(Row::RemoveZone(zone));
+            }
+        }
+        rows.push(Row::AddZone);
+        rows.extend([Row::Panics, Row::LastPanic]);
+        rows.extend((0..TIMED_BOARDS.len()).map(Row::Timing));
+        rows.push(Row::Contacts);
+        rows.extend((0..contacts.suspects().len()).map(Row::ContactKey));
+
+        rows
+    }
+
+    fn text(
+        self,
+        settings: &Settings,
+        panics: &Panics,
+        contacts: &Contacts,
+        telemetry: &Telemetry,
+    ) -> String {
+        let zone = |i: usize| &settings.zones()[i];
+        let arp = settings.arpeggiator();
+
+        match self {
+            Row::Octave => format!("Octave: {:+}", settings.octave()),
+            Row::Semitones => format!("Transpose: {:+}", settings.semitones()),
+            Row::VelocityCurve => format!("Velocity: {}", settings.velocity_curve().name),
+            Row::ReleaseCurve => format!("Release vel: {}", settings.release_curve().name),
+            Row::Calibration => format!("Calibrate: {}", settings.calibration_status()),
+            Row::KeyMap => format!("Map keys: {}", settings.key_mapping_status()),
+            Row::Tuning => format!(
+                "Tuning: {}",
+                settings.tuning().map_or("12-TET", |tuning| &tuning.name)
+            ),
+            Row::TuningMode => format!("Tuning out: {}", settings.tuning_mode().name()),
+            Row::Arp => format!("Arp: {}", on_off(arp.enabled)),
+            Row::ArpOrder => format!("Arp order: {}", arp.order.name()),
+            Row::ArpOctaves => format!("Arp octaves: {}", arp.octaves),
+            Row::ArpGate => format!("Arp gate: {}%", arp.gate),
+            Row::ArpLatch => format!("Arp latch: {}", on_off(arp.latch)),
+            Row::ArpTempo => format!("Arp tempo: {}", arp.bpm),
+            Row::DialsButton(i) => {
+                format!("{} btn: {}", DIALS_BUTTONS[i], settings.buttons()[i].name())
+            }
+            Row::ZoneLow(i) => format!("Z{} low: {}", i + 1, key_name(zone(i).low)),
+            Row::ZoneHigh(i) => format!("Z{} high: {}", i + 1, key_name(zone(i).high)),
+            Row::ZoneChannel(i) => format!("Z{} chan: {}", i + 1, zone(i).channel.as_int() + 1),
+            Row::ZoneTranspose(i) => format!("Z{} transp: {:+}", i + 1, zone(i).transpose),
+            Row::ZoneCurve(i) => format!(
+                "Z{} vel: {}",
+                i + 1,
+                zone(i).curve.as_deref().unwrap_or("global")
+            ),
+            Row::RemoveZone(i) => format!("Remove zone {}", i + 1),
+            Row::AddZone => "Add zone".to_string(),
+            Row::Panics => format!(
+                "Panics: kbd {} dials {}",
+                panics.count("keyboard"),
+                panics.count("dials")
+            ),
+            Row::LastPanic => match panics.last() {
+                Some(panic) => format!("Last: {}", short_location(&panic.location)),
+                None => "Last: none".to_string(),
+            },
+            Row::Timing(i) => {
+                let (board, label) = TIMED_BOARDS[i];
+                match telemetry.get(board) {
+                    Some(timing) => format!(
+                        "{} {}/s {}us",
+                        label, timing.scans_per_second, timing.worst_loop_us
+                    ),
+                    None => format!("{}: no timing yet", label),
+                }
+            }
+            Row::Contacts => match contacts.suspects().len() {
+                0 => "Contacts: all good".to_string(),
+                suspects => format!("Contacts: {} bad", suspects),
+            },
+            Row::ContactKey(i) => match contacts.suspects().get(i) {
+                Some((key, counts)) => format!(
+                    " {}: {} bad {} chat",
+                    key_name(*key),
+                    counts.impossible,
+                    counts.chatter
+                ),
+                None => String::new(),
+            },
+        }
+    }
+
+    // Add/remove act on a right press only, so scrolling past them with left can't delete anything
+    fn change(self, settings: &mut Settings, step: i32) {
+        match self {
+            Row::Octave => settings.step_octave(step),
+            Row::Semitones => settings.step_semitones(step),
+            Row::VelocityCurve => settings.cycle_velocity_curve(step),
+            Row::ReleaseCurve => settings.cycle_release_curve(step),
+            Row::Calibration => settings.step_calibration(step),
+            Row::KeyMap => settings.step_key_mapping(step),
+            Row::Tuning => settings.cycle_tuning(step),
+            Row::TuningMode => settings.toggle_tuning_mode(step),
+            Row::Arp => settings.arpeggiator_mut().enabled = step > 0,
+            Row::ArpOrder => settings.arpeggiator_mut().step_order(step),
+            Row::ArpOctaves => settings.arpeggiator_mut().step_octaves(step),
+            Row::ArpGate => settings.arpeggiator_mut().step_gate(step),
+            Row::ArpLatch => settings.arpeggiator_mut().latch = step > 0,
+            Row::ArpTempo => settings.arpeggiator_mut().step_bpm(step),
+            Row::DialsButton(i) => settings.buttons_mut()[i].cycle(step),
+            Row::ZoneCurve(i) => settings.cycle_zone_curve(i, step),
+            Row::RemoveZone(i) if step > 0 => settings.remove_zone(i),
+            Row::AddZone if step > 0 => settings.add_zone(),
+            Row::ZoneLow(i) | Row::ZoneHigh(i) | Row::ZoneChannel(i) | Row::ZoneTranspose(i) => {
+                let Some(zone) = settings.zone_mut(i) else {
+                    return;
+                };
+                match self {
+                    Row::ZoneLow(_) => zone.step_low(step),
+                    Row::ZoneHigh(_) => zone.step_high(step),
+                    Row::ZoneChannel(_) => zone.step_channel(step),
+                    _ => zone.step_transpose(step),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Boards' names in the telemetry, and on screen
+const TIMED_BOARDS: [(&str, &str); 2] = [("keyboard", "Kbd"), ("dials", "Dials")];
+
+// Just the file name, the whole path won't fit
+fn short_location(location: &str) -> &str {
+    location.rsplit('/').next().unwrap_or(location)
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+const LINE_HEIGHT: i32 = 10;
+// The first line of the screen is the status line, the menu scrolls underneath it
+const VISIBLE_LINES: usize = 5;
+
 struct UIState {
-    button_u_down: bool,
-    button_d_down: bool,
-    button_r_down: bool,
-    button_l_down: bool,
-    button_c_down: bool,
-    button_a_down: bool,
-    button_b_down: bool,
+    settings: SharedSettings,
+    panics: SharedPanics,
+    contacts: SharedContacts,
+    telemetry: SharedTelemetry,
+    selected_item: usize,
 }
 
 impl UIState {
-    fn new() -> Self {
+    fn new(
+        settings: SharedSettings,
+        panics: SharedPanics,
+        contacts: SharedContacts,
+        telemetry: SharedTelemetry,
+    ) -> Self {
         Self {
-            button_u_down: false,
-            button_d_down: false,
-            button_r_down: false,
-            button_l_down: false,
-            button_c_down: false,
-            button_a_down: false,
-            button_b_down: false,
+            settings,
+            panics,
+            contacts,
+            telemetry,
+            selected_item: 0,
         }
     }
 
     fn process_event(&mut self, event: UIEvent) {
-        match event {
-            UIEvent::Down(b) => match b {
-                Button::DpadUp => self.button_u_down = true,
-                Button::DpadDown => self.button_d_down = true,
-                Button::DpadRight => self.button_r_down = true,
-                Button::DpadLeft => self.button_l_down = true,
-                Button::DpadCenter => self.button_c_down = true,
-                Button::A => self.button_a_down = true,
-                Button::B => self.button_b_down = true,
-                _ => {}
-            },
-            UIEvent::Up(b) => match b {
-                Button::DpadUp => self.button_u_down = false,
-                Button::DpadDown => self.button_d_down = false,
-                Button::DpadRight => self.button_r_down = false,
-                Button::DpadLeft => self.button_l_down = false,
-                Button::DpadCenter => self.button_c_down = false,
-                Button::A => self.button_a_down = false,
-                Button::B => self.button_b_down = false,
-                _ => {}
-            },
+        // Everything happens on press, releases don't do anything yet
+        let UIEvent::Down(button) = event else {
+            return;
+        };
+
+        match button {
+            Button::DpadUp => self.select(self.selected_item.saturating_sub(1)),
+            Button::DpadDown => self.select(self.selected_item + 1),
+            Button::DpadLeft => self.change_selected(-1),
+            Button::DpadRight => self.change_selected(1),
+            // Octave shift is needed mid-song, so it gets its own buttons instead of a trip through the menu
+            Button::A => self.change_settings(|settings| settings.step_octave(-1)),
+            Button::B => self.change_settings(|settings| settings.step_octave(1)),
+            _ => {}
+        }
+    }
+
+    fn select(&mut self, item: usize) {
+        self.selected_item = item.min(self.rows().len() - 1);
+    }
+
+    fn rows(&self) -> Vec<Row> {
+        Row::all(
+            &self.settings.read().unwrap(),
+            &self.contacts.read().unwrap(),
+        )
+    }
+
+    fn change_selected(&mut self, step: i32) {
+        // Rows can go away without the menu, like the arp's when the dials board turns it off
+        self.select(self.selected_item);
+
+        match self.rows()[self.selected_item] {
+            // Clears the counts, after cleaning the contacts say. They aren't settings, so it's a
+            //   right press like removing a zone and there's nothing to save
+            Row::Contacts if step > 0 => self.contacts.write().unwrap().clear(),
+            row => self.change_settings(|settings| row.change(settings, step)),
+        }
+
+        // Removing a zone can leave the cursor past the end
+        self.select(self.selected_item);
+    }
+
+    fn change_settings(&self, change: impl FnOnce(&mut Settings)) {
+        let mut settings = self.settings.write().unwrap();
+        change(&mut settings);
+
+        if let Err(e) = settings.save() {
+            println!("Couldn't save settings: {}", e);
         }
     }
 }
 
 pub fn do_ui<I: IO<D>, D: Display>(
     mut io: I,
+    settings: SharedSettings,
+    panics: SharedPanics,
+    contacts: SharedContacts,
+    telemetry: SharedTelemetry,
     event_channel: Receiver<UIEvent>,
     mut frame_hook: impl FnMut(),
 ) -> ! {
-    let mut state = UIState::new();
+    let mut state = UIState::new(settings, panics, contacts, telemetry);
 
     let mut render = ui_renderer();
 
@@ -94,57 +348,96 @@ pub fn do_ui<I: IO<D>, D: Display>(
 }
 
 fn ui_renderer<'a, D: Display>() -> impl FnMut(&mut D, &UIState) + 'a {
-    let style_outline = PrimitiveStyleBuilder::new()
-        .stroke_color(BinaryColor::On)
-        .stroke_width(1)
-        .build();
-
-    let style_filled = PrimitiveStyleBuilder::new()
-        .fill_color(BinaryColor::On)
-        .build();
+    let text_style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
 
-    let button_u = Triangle::new(Point::new(20, 20), Point::new(30, 2), Point::new(40, 20));
-    let button_l = Triangle::new(Point::new(0, 30), Point::new(18, 21), Point::new(18, 41));
-    let button_r = Triangle::new(Point::new(60, 30), Point::new(42, 21), Point::new(42, 41));
-    let button_d = Triangle::new(Point::new(30, 60), Point::new(40, 42), Point::new(20, 42));
+    move |display, state| {
+        display.clear_buffer();
 
-    let button_c = Rectangle::new(Point::new(20, 22), Size::new(20, 18));
-    let button_a = Circle::new(Point::new(70, 40), 20);
-    let button_b = Circle::new(Point::new(100, 20), 20);
+        let settings = state.settings.read().unwrap();
+        let panics = state.panics.read().unwrap();
+        let contacts = state.contacts.read().unwrap();
+        let telemetry = state.telemetry.read().unwrap();
+        let rows = Row::all(&settings, &contacts);
 
-    move |display, state| {
-        let style = |filled: bool| -> &PrimitiveStyle<BinaryColor> {
-            if filled {
-                &style_filled
-            } else {
-                &style_outline
-            }
+        // Always show where the keyboard is shifted to, whatever row is selected. Unless an arduino
+        //   just panicked, that's more important for a few seconds
+        let status = match panics.recent() {
+            Some(panic) => format!("PANIC {} {}", panic.board, short_location(&panic.location)),
+            None => format!(
+                "Oct {:+}  Semi {:+}",
+                settings.octave(),
+                settings.semitones()
+            ),
         };
+        Text::with_baseline(&status, Point::zero(), text_style, Baseline::Top)
+            .draw(display)
+            .unwrap();
 
-        display.clear_buffer();
+        // Scroll so the selected row is always on screen
+        let first_line = state.selected_item.saturating_sub(VISIBLE_LINES - 1);
 
-        button_u
-            .draw_styled(style(state.button_u_down), display)
-            .unwrap();
-        button_l
-            .draw_styled(style(state.button_l_down), display)
-            .unwrap();
-        button_r
-            .draw_styled(style(state.button_r_down), display)
-            .unwrap();
-        button_d
-            .draw_styled(style(state.button_d_down), display)
-            .unwrap();
-        button_c
-            .draw_styled(style(state.button_c_down), display)
-            .unwrap();
-        button_a
-            .draw_styled(style(state.button_a_down), display)
-            .unwrap();
-        button_b
-            .draw_styled(style(state.button_b_down), display)
+        for (line, (i, row)) in rows
+            .iter()
+            .enumerate()
+            .skip(first_line)
+            .take(VISIBLE_LINES)
+            .enumerate()
+        {
+            let cursor = if i == state.selected_item { '>' } else { ' ' };
+            let text = format!(
+                "{}{}",
+                cursor,
+                row.text(&settings, &panics, &contacts, &telemetry)
+            );
+
+            Text::with_baseline(
+                &text,
+                Point::new(0, (line as i32 + 1) * LINE_HEIGHT),
+                text_style,
+                Baseline::Top,
+            )
+            .draw(display)
             .unwrap();
+        }
+        drop(settings);
+        drop(panics);
+        drop(contacts);
+        drop(telemetry);
 
         display.flush().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn rows_removed_outside_the_menu_move_the_cursor() {
+        // Changing a row saves the settings
+        let dir = std::env::temp_dir().join(format!("ui-state-{}", process::id()));
+        let settings = Settings::load_from(&dir).shared();
+        settings.write().unwrap().arpeggiator_mut().enabled = true;
+        let mut contacts = Contacts::new();
+        contacts.record(10, 1, 0);
+        contacts.record(20, 0, 1);
+        let mut state = UIState::new(
+            settings.clone(),
+            Panics::load_from(dir.join("panics.txt")).shared(),
+            contacts.shared(),
+            Telemetry::new().shared(),
+        );
+
+        state.select(usize::MAX);
+        let last = state.selected_item;
+        // Like the dials' arp button
+        settings.write().unwrap().arpeggiator_mut().enabled = false;
+        state.process_event(UIEvent::Down(Button::DpadLeft));
+
+        assert_eq!(state.selected_item, last - 5);
+        assert_eq!(state.selected_item, state.rows().len() - 1);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,311 @@
+// Velocity curves turn how long a key took to travel between its two contacts into a midi velocity.
+// Curves are read from a library file on the boot partition so players can tune the feel without a rebuild.
+//
+//   [heavy]
+//   shape = power          # linear | power | log | s-curve | fixed | table
+//   amount = 0.5           # exponent, log base, s-curve steepness, or the fixed velocity
+//   min_travel_ms = 1      # travel times at or below this are full velocity
+//   max_travel_ms = 80     # travel times at or above this are the quietest velocity
+//
+//   [mine]
+//   shape = table
+//   file = mine.curve      # 128 velocities, fastest travel first. Relative to the library's directory
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::config::{read_config, Section};
+
+pub const CURVE_LIBRARY_FILE: &str = "velocity-curves.conf";
+
+const TABLE_SIZE: usize = 128;
+
+const DEFAULT_MIN_TRAVEL_TIME: f32 = 1.0;
+const DEFAULT_MAX_TRAVEL_TIME: f32 = 80.0;
+
+pub enum CurveShape {
+    Linear,
+    Power(f32),
+    Logarithmic(f32),
+    SCurve(f32),
+    Fixed(u8),
+    Table(Box<[u8; TABLE_SIZE]>),
+}
+
+// The range of travel times (in milliseconds) a curve is stretched over
+#[derive(Copy, Clone)]
+pub struct TravelWindow {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl TravelWindow {
+    pub fn new(min: f32, max: f32) -> Result<Self> {
+        if !min.is_finite() || !max.is_finite() || min < 0.0 || max <= min {
+            bail!(
+                "travel window must satisfy 0 <= min < max, got {} and {}",
+                min,
+                max
+            );
+        }
+
+        Ok(Self { min, max })
+    }
+
+    // Travel time normalized to [0-1], where 0 is the fastest key press
+    fn normalize(&self, travel_time: f32) -> f32 {
+        (travel_time.clamp(self.min, self.max) - self.min) / (self.max - self.min)
+    }
+}
+
+pub struct VelocityCurve {
+    pub name: String,
+    shape: CurveShape,
+    window: TravelWindow,
+}
+
+impl VelocityCurve {
+    pub fn new(name: impl Into<String>, shape: CurveShape) -> Self {
+        Self {
+            name: name.into(),
+            shape,
+            window: TravelWindow {
+                min: DEFAULT_MIN_TRAVEL_TIME,
+                max: DEFAULT_MAX_TRAVEL_TIME,
+            },
+        }
+    }
+
+    fn from_section(section: &Section, library_dir: &Path) -> Result<Self> {
+        let shape = match section.require("shape")? {
+            "linear" => CurveShape::Linear,
+            "power" => CurveShape::Power(section.parse_finite_or("amount", 2.0)?.clamp(0.0, 10.0)),
+            "log" => {
+                let base = section.parse_finite_or("amount", 10.0)?;
+                if base <= 0.0 {
+                    bail!("[{}] log amount must be above 0", section.name);
+                }
+                CurveShape::Logarithmic(base)
+            }
+            "s-curve" => {
+                let steepness = section.parse_finite_or("amount", 8.0)?;
+                if steepness <= 0.0 {
+                    bail!("[{}] s-curve amount must be above 0", section.name);
+                }
+                CurveShape::SCurve(steepness)
+            }
+            "fixed" => CurveShape::Fixed(section.parse_or("amount", 100u8)?.clamp(1, 127)),
+            "table" => CurveShape::Table(read_table(library_dir.join(section.require("file")?))?),
+            other => bail!("[{}] unknown shape '{}'", section.name, other),
+        };
+
+        let window = TravelWindow::new(
+            section.parse_finite_or("min_travel_ms", DEFAULT_MIN_TRAVEL_TIME)?,
+            section.parse_finite_or("max_travel_ms", DEFAULT_MAX_TRAVEL_TIME)?,
+        )
+        .map_err(|e| anyhow!("[{}] {}", section.name, e))?;
+
+        Ok(Self {
+            name: section.name.clone(),
+            shape,
+            window,
+        })
+    }
+
+    // Velocity for a travel time normalized to [0-1], where 0 is the fastest key press
+    fn velocity(&self, t: f32) -> u8 {
+        let loudness = match &self.shape {
+            CurveShape::Linear => 1.0 - t,
+            CurveShape::Power(pow) => 1.0 - t.powf(*pow),
+            CurveShape::Logarithmic(base) => 1.0 - (1.0 + base * t).ln() / (1.0 + base).ln(),
+            CurveShape::SCurve(steepness) => {
+                // Logistic function, rescaled so it still passes through (0, 0) and (1, 1)
+                let logistic = |x: f32| 1.0 / (1.0 + (-steepness * (x - 0.5)).exp());
+                1.0 - (logistic(t) - logistic(0.0)) / (logistic(1.0) - logistic(0.0))
+            }
+            CurveShape::Fixed(velocity) => return *velocity,
+            CurveShape::Table(table) => {
+                return table[(t * (TABLE_SIZE - 1) as f32).round() as usize];
+            }
+        };
+
+        // Velocity 0 would be a note off
+        (1.0 + loudness.clamp(0.0, 1.0) * 126.0) as u8
+    }
+}
+
+// The firmware reports travel times in tenths of a millisecond
+pub fn travel_time_ms(travel_time: u16) -> f32 {
+    travel_time as f32 / 10.0
+}
+
+// A calibrated key's own window replaces the curve's, so every key covers the whole curve
+pub fn calc_velocity(
+    travel_time: u16,
+    curve: &VelocityCurve,
+    key_window: Option<&TravelWindow>,
+) -> u8 {
+    // Midi expects some number in [0-127]
+    let norm_travel_time = key_window
+        .unwrap_or(&curve.window)
+        .normalize(travel_time_ms(travel_time));
+
+    let velocity = curve.velocity(norm_travel_time);
+    assert!(velocity <= 127);
+    assert!(velocity > 0);
+
+    velocity
+}
+
+// Used when there's no library file, or it can't be read
+pub fn default_curves() -> Vec<VelocityCurve> {
+    vec![
+        VelocityCurve::new("default", CurveShape::Power(2.0)),
+        VelocityCurve::new("linear", CurveShape::Linear),
+        VelocityCurve::new("heavy", CurveShape::Power(0.5)),
+        VelocityCurve::new("log", CurveShape::Logarithmic(10.0)),
+        VelocityCurve::new("s-curve", CurveShape::SCurve(8.0)),
+        VelocityCurve::new("fixed", CurveShape::Fixed(100)),
+    ]
+}
+
+pub fn load_curves(dir: &Path) -> Vec<VelocityCurve> {
+    let library_path = dir.join(CURVE_LIBRARY_FILE);
+    if !library_path.exists() {
+        return default_curves();
+    }
+
+    match read_curve_library(&library_path) {
+        Ok(curves) => curves,
+        Err(e) => {
+            println!("Couldn't load velocity curves, using defaults: {}", e);
+            default_curves()
+        }
+    }
+}
+
+fn read_curve_library(path: &Path) -> Result<Vec<VelocityCurve>> {
+    let library_dir = path.parent().unwrap();
+
+    let curves = read_config(path)?
+        .iter()
+        .filter(|section| !section.name.is_empty())
+        .map(|section| VelocityCurve::from_section(section, library_dir))
+        .collect::<Result<Vec<_>>>()?;
+
+    if curves.is_empty() {
+        bail!("{} doesn't define any curves", path.display());
+    }
+
+    Ok(curves)
+}
+
+fn read_table(path: impl AsRef<Path>) -> Result<Box<[u8; TABLE_SIZE]>> {
+    let path = path.as_ref();
+    let contents =
+        fs::read_to_string(path).map_err(|e| anyhow!("couldn't read {}: {}", path.display(), e))?;
+
+    let values = contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap())
+        .flat_map(|line| line.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|value| !value.is_empty())
+        .map(|value| match value.parse::<u8>() {
+            Ok(v) if (1..=127).contains(&v) => Ok(v),
+            _ => Err(anyhow!(
+                "{}: '{}' isn't a velocity in [1-127]",
+                path.display(),
+                value
+            )),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let table: [u8; TABLE_SIZE] = values.try_into().map_err(|values: Vec<u8>| {
+        anyhow!(
+            "{}: expected {} velocities, found {}",
+            path.display(),
+            TABLE_SIZE,
+            values.len()
+        )
+    })?;
+
+    Ok(Box::new(table))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+
+    fn curve(text: &str) -> Result<VelocityCurve> {
+        let sections = parse_config(text)?;
+        VelocityCurve::from_section(&sections[1], Path::new("/nonexistent"))
+    }
+
+    #[test]
+    fn curves_go_from_loudest_to_quietest() {
+        for curve in default_curves() {
+            let velocities: Vec<u8> = (0..=800).map(|t| calc_velocity(t, &curve, None)).collect();
+
+            assert!(
+                velocities.windows(2).all(|pair| pair[0] >= pair[1]),
+                "{} gets louder",
+                curve.name
+            );
+            if curve.name != "fixed" {
+                assert_eq!(velocities[0], 127, "{}", curve.name);
+                assert_eq!(velocities[800], 1, "{}", curve.name);
+            }
+        }
+    }
+
+    #[test]
+    fn key_windows_replace_the_curves() {
+        let curve = VelocityCurve::new("linear", CurveShape::Linear);
+        let window = TravelWindow::new(10.0, 20.0).unwrap();
+
+        assert_eq!(calc_velocity(100, &curve, Some(&window)), 127);
+        assert_eq!(calc_velocity(150, &curve, Some(&window)), 64);
+        assert_eq!(calc_velocity(200, &curve, Some(&window)), 1);
+    }
+
+    #[test]
+    fn curves_are_read_from_sections() {
+        let power = curve("[soft]\nshape = power\namount = 0.5\nmax_travel_ms = 40\n").unwrap();
+        assert_eq!(power.name, "soft");
+        assert_eq!(power.window.max, 40.0);
+        assert!(matches!(power.shape, CurveShape::Power(amount) if amount == 0.5));
+
+        let fixed = curve("[fixed]\nshape = fixed\namount = 90\n").unwrap();
+        assert_eq!(fixed.velocity(0.3), 90);
+        let fixed = curve("[fixed]\nshape = fixed\namount = 200\n").unwrap();
+        assert_eq!(fixed.velocity(0.3), 127);
+        assert!(curve("[fixed]\nshape = fixed\namount = 300\n").is_err());
+    }
+
+    #[test]
+    fn bad_curves_are_rejected() {
+        for text in [
+            "[c]\n",
+            "[c]\nshape = wobbly\n",
+            "[c]\nshape = log\namount = 0\n",
+            "[c]\nshape = s-curve\namount = -1\n",
+            "[c]\nshape = linear\nmin_travel_ms = 10\nmax_travel_ms = 5\n",
+            "[c]\nshape = linear\nmin_travel_ms = -1\n",
+            "[c]\nshape = table\nfile = missing.curve\n",
+            // Parse as floats, then panic the driver when a key's played
+            "[c]\nshape = power\namount = nan\n",
+            "[c]\nshape = log\namount = inf\n",
+            "[c]\nshape = s-curve\namount = NaN\n",
+            "[c]\nshape = linear\nmin_travel_ms = nan\n",
+            "[c]\nshape = linear\nmax_travel_ms = inf\n",
+        ] {
+            assert!(curve(text).is_err(), "{:?} was accepted", text);
+        }
+
+        assert!(TravelWindow::new(f32::NAN, 10.0).is_err());
+        assert!(TravelWindow::new(0.0, f32::NAN).is_err());
+    }
+}
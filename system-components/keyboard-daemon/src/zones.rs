@@ -0,0 +1,87 @@
+// Zones split the keybed into ranges that each play on their own midi channel.
+// Zones can overlap, a key inside more than one zone plays all of them (layering).
+
+use anyhow::{bail, Result};
+use midly::num::u4;
+
+use crate::calibration::KEYS;
+use crate::config::Section;
+
+const MAX_TRANSPOSE: i8 = 48;
+
+pub struct Zone {
+    // Key indexes, inclusive
+    pub low: u8,
+    pub high: u8,
+
+    pub channel: u4,
+    pub transpose: i8,
+
+    // Name of a curve in the library. None follows the global velocity curve
+    pub curve: Option<String>,
+}
+
+impl Zone {
+    pub fn full(channel: u4) -> Self {
+        Self {
+            low: 0,
+            high: KEYS as u8 - 1,
+            channel,
+            transpose: 0,
+            curve: None,
+        }
+    }
+
+    pub fn contains(&self, key: u8) -> bool {
+        (self.low..=self.high).contains(&key)
+    }
+
+    pub fn from_section(section: &Section) -> Result<Self> {
+        let zone = Self {
+            low: section.parse_or("low", 0u8)?,
+            high: section.parse_or("high", KEYS as u8 - 1)?,
+            // Channels are 1-16 for humans, 0-15 on the wire
+            channel: u4::new(section.parse_or("channel", 1u8)?.clamp(1, 16) - 1),
+            transpose: section
+                .parse_or("transpose", 0i8)?
+                .clamp(-MAX_TRANSPOSE, MAX_TRANSPOSE),
+            curve: section.get("curve").map(str::to_string),
+        };
+
+        if zone.low > zone.high || zone.high as usize >= KEYS {
+            bail!("zone must have low <= high < {}", KEYS);
+        }
+
+        Ok(zone)
+    }
+
+    pub fn to_section(&self) -> Section {
+        let mut section = Section::new("zone");
+        section.set("low", self.low);
+        section.set("high", self.high);
+        section.set("channel", self.channel.as_int() + 1);
+        section.set("transpose", self.transpose);
+        if let Some(curve) = &self.curve {
+            section.set("curve", curve);
+        }
+
+        section
+    }
+
+    pub fn step_low(&mut self, step: i32) {
+        self.low = (self.low as i32 + step).clamp(0, self.high as i32) as u8;
+    }
+
+    pub fn step_high(&mut self, step: i32) {
+        self.high = (self.high as i32 + step).clamp(self.low as i32, KEYS as i32 - 1) as u8;
+    }
+
+    pub fn step_channel(&mut self, step: i32) {
+        self.channel = u4::new((self.channel.as_int() as i32 + step).rem_euclid(16) as u8);
+    }
+
+    pub fn step_transpose(&mut self, step: i32) {
+        self.transpose =
+            (self.transpose as i32 + step).clamp(-MAX_TRANSPOSE as i32, MAX_TRANSPOSE as i32) as i8;
+    }
+}
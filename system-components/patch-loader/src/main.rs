@@ -4,9 +4,9 @@ use std::{env, fs};
 
 use rosc::{encoder, OscMessage, OscPacket, OscType};
 
-const PATCH_DIRECTORY: &'static str = "/usr/share/patches";
+const PATCH_DIRECTORY: &str = "/usr/share/patches";
 
-const CARDINAL_ADDRESS: &'static str = "localhost:2228";
+const CARDINAL_ADDRESS: &str = "localhost:2228";
 
 fn send_message(socket: &UdpSocket, addr: impl Into<String>, args: Vec<OscType>) {
     let msg_buf = encoder::encode(&OscPacket::Message(OscMessage {
@@ -32,7 +32,7 @@ fn recv_message(socket: &UdpSocket, addr: impl Into<String>) -> Vec<OscType> {
                         "Address of received message didn't match"
                     );
 
-                    return msg.args;
+                    msg.args
                 }
                 OscPacket::Bundle(_) => {
                     panic!("Received a bundle, not a message!")
@@ -111,30 +111,37 @@ impl TTY {
         })
     }
 
+    // Another handle to the same device, so one thread can write while another blocks reading
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(TTY {
+            device: self.device.try_clone()?,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+        })
+    }
+
     // Mimics the timeout interface provided by std::TcpStream
     // Although curiously TcpStream's implementation does not require mut
     pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
-        self.read_timeout = if let Some(timeout) = timeout {
-            if timeout.is_zero() {
-                None
-            } else {
-                Some(timeout)
-            }
-        } else {
-            None
-        };
+        self.read_timeout = timeout.filter(|timeout| !timeout.is_zero());
     }
 
     pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
-        self.write_timeout = if let Some(timeout) = timeout {
-            if timeout.is_zero() {
-                None
-            } else {
-                Some(timeout)
-            }
-        } else {
-            None
-        };
+        self.write_timeout = timeout.filter(|timeout| !timeout.is_zero());
+    }
+
+    // DTR is wired to the arduino's reset through a capacitor, dropping it and raising it again resets it
+    pub fn set_dtr(&mut self, on: bool) -> io::Result<()> {
+        let request = if on { libc::TIOCMBIS } else { libc::TIOCMBIC };
+        let bits: libc::c_int = libc::TIOCM_DTR;
+        check(unsafe { libc::ioctl(self.device.as_raw_fd(), request as _, &bits) })?;
+        Ok(())
+    }
+
+    // Throw away anything received but not read yet
+    pub fn discard_input(&mut self) -> io::Result<()> {
+        check(unsafe { libc::tcflush(self.device.as_raw_fd(), libc::TCIFLUSH) })?;
+        Ok(())
     }
 }
 
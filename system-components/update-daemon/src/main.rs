@@ -1,5 +1,5 @@
 use std::fs;
-use std::io::{stderr, stdout, Read};
+use std::io::{stderr, stdout};
 use std::path::PathBuf;
 use std::process::Command;
 
@@ -16,14 +16,14 @@ const SERIAL_BAUD: u32 = 115_200;
 const GADGET_LUN_PATH: &str =
     "/sys/kernel/config/usb_gadget/keystation/functions/mass_storage.usb0/lun.0/file";
 
-const CMDLINE_PATH: &'static str = "/boot/cmdline.txt";
+const CMDLINE_PATH: &str = "/boot/cmdline.txt";
 
 fn parse_cmdline_root(kernel_cmdline: &str) -> Option<&str> {
-    return kernel_cmdline
-        .split(" ")
+    kernel_cmdline
+        .split(' ')
         .find(|option| option.starts_with("root="))
-        .and_then(|root_options| root_options.split_once("="))
-        .and_then(|(_, root)| Some(root));
+        .and_then(|root_options| root_options.split_once('='))
+        .map(|(_, root)| root)
 }
 
 fn current_rootfs_partition() -> Result<PathBuf> {
@@ -49,7 +49,7 @@ fn set_rootfs_partition(new_rootfs: &str) -> Result<()> {
     let root_option =
         parse_cmdline_root(&kernel_cmdline).ok_or(anyhow!("Couldn't get root option"))?;
 
-    let new_cmdline = kernel_cmdline.replace(root_option, &new_rootfs);
+    let new_cmdline = kernel_cmdline.replace(root_option, new_rootfs);
     fs::write(CMDLINE_PATH, new_cmdline)?;
 
     Ok(())
@@ -91,14 +91,11 @@ fn main() {
             }
         };
 
-        match do_cmd(command.to_owned(), |resp| {
+        if let Err(e) = do_cmd(command.to_owned(), |resp| {
             encode::write_str(&mut serial, resp)?;
             Ok(())
         }) {
-            Err(e) => {
-                eprintln!("Error running cmd; {}", e);
-            }
-            Ok(_) => {}
+            eprintln!("Error running cmd; {}", e);
         }
     }
 }